@@ -0,0 +1,309 @@
+use super::protocol::{DnsPacket, DnsRecord, QueryType, ResponseCode};
+use super::zonefile;
+use std::cmp;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+// Is `name` equal to or below `zone`?
+fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{0}", zone))
+}
+
+pub struct Zone {
+    pub origin: String,
+    soa: DnsRecord,
+    records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    pub fn new(records: Vec<DnsRecord>) -> Result<Zone> {
+        let mut soas = records
+            .iter()
+            .filter(|rec| rec.get_querytype() == QueryType::SOA);
+        let soa = match (soas.next(), soas.next()) {
+            (Some(soa), None) => soa.clone(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Zone must contain exactly one SOA record",
+                ))
+            }
+        };
+
+        let origin = soa.get_domain().to_string();
+        if let Some(stray) = records
+            .iter()
+            .find(|rec| !in_zone(rec.get_domain(), &origin))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Record {0} is outside zone {1}", stray.get_domain(), origin),
+            ));
+        }
+
+        Ok(Zone {
+            origin,
+            soa,
+            records,
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Zone> {
+        let path = path.as_ref();
+        Zone::new(zonefile::load(path)?).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{0}: {1}", path.display(), e),
+            )
+        })
+    }
+
+    // The SOA to accompany negative answers, with its TTL capped per RFC 2308 section 3
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa.clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = self.soa {
+            soa.set_ttl(cmp::min(minimum, ttl));
+        }
+
+        soa
+    }
+
+    // Find the closest delegation point strictly below the apex covering qname, if any
+    fn find_delegation(&self, qname: &str) -> Option<Vec<DnsRecord>> {
+        self.records
+            .iter()
+            .filter(|rec| rec.get_querytype() == QueryType::NS)
+            .map(|rec| rec.get_domain())
+            .filter(|cut| *cut != self.origin && in_zone(qname, cut))
+            .max_by_key(|cut| cut.len())
+            .map(|cut| {
+                self.records
+                    .iter()
+                    .filter(|rec| rec.get_querytype() == QueryType::NS && rec.get_domain() == cut)
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    pub fn query(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        // Names at or below a zone cut belong to someone else; refer the client there
+        if let Some(ns_records) = self.find_delegation(qname) {
+            for ns in &ns_records {
+                if let DnsRecord::NS { ref host, .. } = *ns {
+                    packet.resources.extend(
+                        self.records
+                            .iter()
+                            .filter(|rec| {
+                                rec.get_domain() == host
+                                    && (rec.get_querytype() == QueryType::A
+                                        || rec.get_querytype() == QueryType::AAAA)
+                            })
+                            .cloned(),
+                    );
+                }
+            }
+            packet.authorities = ns_records;
+
+            return packet;
+        }
+
+        packet.header.authoritative_answer = true;
+
+        let at_name = self
+            .records
+            .iter()
+            .filter(|rec| rec.get_domain() == qname)
+            .collect::<Vec<&DnsRecord>>();
+
+        packet.answers = at_name
+            .iter()
+            .filter(|rec| rec.get_querytype() == qtype)
+            .map(|rec| (*rec).clone())
+            .collect();
+
+        // A CNAME stands in for every other type at its name
+        if packet.answers.is_empty() {
+            packet.answers = at_name
+                .iter()
+                .filter(|rec| rec.get_querytype() == QueryType::CNAME)
+                .map(|rec| (*rec).clone())
+                .collect();
+        }
+
+        if packet.answers.is_empty() {
+            // Empty non-terminals exist even though they own no records themselves
+            let exists = !at_name.is_empty()
+                || self
+                    .records
+                    .iter()
+                    .any(|rec| rec.get_domain().ends_with(&format!(".{0}", qname)));
+
+            packet.header.rescode = if exists {
+                ResponseCode::NOERROR
+            } else {
+                ResponseCode::NXDOMAIN
+            };
+            packet.authorities.push(self.negative_soa());
+        }
+
+        packet
+    }
+}
+
+// Store of the zones this server is authoritative for
+pub struct Authority {
+    zones: Vec<Zone>,
+}
+
+impl Authority {
+    pub fn new() -> Authority {
+        Authority { zones: Vec::new() }
+    }
+
+    pub fn add_zone(&mut self, zone: Zone) -> Result<()> {
+        if self.zones.iter().any(|z| z.origin == zone.origin) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Zone {0} is already loaded", zone.origin),
+            ));
+        }
+
+        println!("Loaded zone {0}", zone.origin);
+        self.zones.push(zone);
+
+        Ok(())
+    }
+
+    // Answer a question from the most specific local zone containing it
+    pub fn query(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let qname = qname.to_lowercase();
+
+        self.zones
+            .iter()
+            .filter(|zone| in_zone(&qname, &zone.origin))
+            .max_by_key(|zone| zone.origin.len())
+            .map(|zone| zone.query(&qname, qtype))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "$ORIGIN example.com.
+$TTL 3600
+@ SOA ns1 hostmaster 1 7200 900 1209600 300
+@ NS ns1
+ns1 A 192.0.2.53
+www A 192.0.2.1
+alias CNAME www
+a.b.c A 192.0.2.2
+sub NS ns.sub
+ns.sub A 192.0.2.54
+";
+
+    fn authority() -> Authority {
+        let mut authority = Authority::new();
+        authority
+            .add_zone(Zone::new(zonefile::parse(ZONE).unwrap()).unwrap())
+            .unwrap();
+        authority
+    }
+
+    fn negative_soa(packet: &DnsPacket) -> u32 {
+        match packet.authorities[..] {
+            [DnsRecord::SOA {
+                ref domain, ttl, ..
+            }] if domain == "example.com" => ttl,
+            _ => panic!("Expected the zone's SOA: {:?}", packet.authorities),
+        }
+    }
+
+    #[test]
+    fn answers_authoritatively() {
+        let packet = authority().query("WWW.example.com", QueryType::A).unwrap();
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResponseCode::NOERROR);
+        assert_eq!(
+            packet.answers,
+            vec![DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 3600,
+            }]
+        );
+    }
+
+    #[test]
+    fn answers_with_cname_for_other_types() {
+        let packet = authority()
+            .query("alias.example.com", QueryType::A)
+            .unwrap();
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].get_querytype(), QueryType::CNAME);
+    }
+
+    #[test]
+    fn missing_name_is_nxdomain_with_soa() {
+        let packet = authority().query("nope.example.com", QueryType::A).unwrap();
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(packet.answers.is_empty());
+        // Capped at the SOA minimum for negative caching
+        assert_eq!(negative_soa(&packet), 300);
+    }
+
+    #[test]
+    fn missing_type_is_nodata_with_soa() {
+        let authority = authority();
+
+        for qname in &["www.example.com", "b.c.example.com"] {
+            let packet = authority.query(qname, QueryType::AAAA).unwrap();
+
+            assert!(packet.header.authoritative_answer);
+            assert_eq!(packet.header.rescode, ResponseCode::NOERROR);
+            assert!(packet.answers.is_empty());
+            assert_eq!(negative_soa(&packet), 300);
+        }
+    }
+
+    #[test]
+    fn refers_delegated_names_with_glue() {
+        let packet = authority()
+            .query("www.sub.example.com", QueryType::A)
+            .unwrap();
+
+        assert!(!packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResponseCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::NS {
+                domain: "sub.example.com".to_string(),
+                host: "ns.sub.example.com".to_string(),
+                ttl: 3600,
+            }]
+        );
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::A {
+                domain: "ns.sub.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 54),
+                ttl: 3600,
+            }]
+        );
+    }
+
+    #[test]
+    fn ignores_names_outside_its_zones() {
+        assert!(authority().query("example.org", QueryType::A).is_none());
+        assert!(authority().query("notexample.com", QueryType::A).is_none());
+    }
+}
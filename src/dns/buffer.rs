@@ -1,9 +1,72 @@
-use std::io::{Error, ErrorKind, Result};
+use super::error::{DnsError, Result};
+use std::collections::HashMap;
 
 // Maximum size of DNS packet
-const MAX_UDP_SIZE: usize = 512;
+pub const MAX_UDP_SIZE: usize = 512;
+// Largest UDP payload we will negotiate with EDNS(0)
+pub const MAX_EDNS_SIZE: usize = 4096;
 // Maximum size of label
 const MAX_LABEL_LEN: usize = 63;
+// Maximum size of a name on the wire, including length bytes and the terminating null
+const MAX_NAME_LEN: usize = 255;
+// Compression pointers have 14 bits for the offset they point at
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+// A name of maximum length has at most 127 labels, so never needs more jumps than this
+const MAX_JUMPS: usize = 127;
+
+// Split a name into its labels, checking it can be written: labels must be non-empty and
+// short enough for their length byte, and the whole name must fit in 255 bytes uncompressed
+fn split_labels(qname: &str) -> Result<Vec<&str>> {
+    // The root domain is just the terminating null byte
+    if qname.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let labels = qname.split('.').collect::<Vec<&str>>();
+    for label in &labels {
+        if label.is_empty() {
+            return Err(DnsError::BadLabel(format!("Empty label in {0}", qname)));
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(DnsError::BadLabel(format!(
+                "Label exceeds maximum length: {0}",
+                MAX_LABEL_LEN
+            )));
+        }
+    }
+
+    // A length byte for each label, and the terminating null
+    let name_len = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    if name_len > MAX_NAME_LEN {
+        return Err(DnsError::NameTooLong);
+    }
+
+    Ok(labels)
+}
+
+// Offsets of the names already written to a message, so later occurrences of a name, or of
+// any of its suffixes, can be replaced with a pointer (RFC 1035 section 4.1.4)
+pub struct CompressionTable {
+    offsets: HashMap<String, usize>,
+    enabled: bool,
+}
+
+impl CompressionTable {
+    pub fn new() -> CompressionTable {
+        CompressionTable {
+            offsets: HashMap::new(),
+            enabled: true,
+        }
+    }
+
+    // A table which never compresses, for writing records in DNSSEC canonical form
+    pub fn disabled() -> CompressionTable {
+        CompressionTable {
+            offsets: HashMap::new(),
+            enabled: false,
+        }
+    }
+}
 
 pub trait ByteBuffer {
     const MAX_SIZE: usize;
@@ -86,6 +149,14 @@ pub trait ByteBuffer {
 
         // track whether we've encountered a jump
         let mut jumped = false;
+        let mut jumps = 0;
+
+        // Pointers must point strictly before wherever we last started reading labels from,
+        // which rules out both forward references and loops
+        let mut pointer_limit = pos;
+
+        // Length of the name on the wire, without compression
+        let mut name_len = 0;
 
         // Delimiter between labels in name. For first iteration, keep empty.
         // Next iterations will use '.'
@@ -94,42 +165,63 @@ pub trait ByteBuffer {
             // Beginning of label, so grab length byte
             let label_len = self.get(pos)?;
 
-            // If two most significant bits are set, we need to jump
-            if (label_len & 0xC0) == 0xC0 {
-                // Move the head past the length byte and jump byte
-                if !jumped {
-                    self.seek(pos + 2)?;
-                }
+            match label_len & 0xC0 {
+                // If two most significant bits are set, we need to jump
+                0xC0 => {
+                    // Read another byte, calculate offset and jump
+                    let jump_byte = self.get(pos + 1)? as u16;
+                    let offset = ((((label_len as u16) ^ 0xC0) << 8) | jump_byte) as usize;
 
-                // Read another byte, calculate offset and jump
-                let jump_byte = self.get(pos + 1)? as u16;
-                let offset = (((label_len as u16) ^ 0xC0) << 8) | jump_byte;
-                pos = offset as usize;
+                    jumps += 1;
+                    if offset >= pointer_limit || jumps > MAX_JUMPS {
+                        return Err(DnsError::PointerLoop);
+                    }
 
-                // We jumped
-                jumped = true;
-            }
-            // No-jump scenario, where a single label is read
-            else {
-                // Move one byte past length byte
-                pos += 1;
-
-                // Domain names are terminated by an empty byte
-                if label_len == 0 {
-                    break;
+                    // Move the head past the length byte and jump byte
+                    if !jumped {
+                        self.seek(pos + 2)?;
+                    }
+
+                    pos = offset;
+                    pointer_limit = offset;
+
+                    // We jumped
+                    jumped = true;
                 }
+                // No-jump scenario, where a single label is read
+                0x00 => {
+                    // Move one byte past length byte
+                    pos += 1;
+
+                    name_len += label_len as usize + 1;
+                    if name_len > MAX_NAME_LEN {
+                        return Err(DnsError::NameTooLong);
+                    }
 
-                // Apppend delimiter to output buffer
-                qname.push_str(delim);
+                    // Domain names are terminated by an empty byte
+                    if label_len == 0 {
+                        break;
+                    }
 
-                // Extract ASCII bytes for the label and append to output buffer
-                let str_buffer = self.get_range(pos, label_len as usize)?;
-                qname.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                    // Apppend delimiter to output buffer
+                    qname.push_str(delim);
 
-                delim = ".";
+                    // Extract ASCII bytes for the label and append to output buffer
+                    let str_buffer = self.get_range(pos, label_len as usize)?;
+                    qname.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
 
-                // Move forward in packet buffer by length of label
-                pos += label_len as usize;
+                    delim = ".";
+
+                    // Move forward in packet buffer by length of label
+                    pos += label_len as usize;
+                }
+                // 0x40 and 0x80 are extended and reserved label types (RFC 6891 section 5)
+                _ => {
+                    return Err(DnsError::BadLabel(format!(
+                        "Unsupported label type {0:#04x}",
+                        label_len & 0xC0
+                    )))
+                }
             }
         }
 
@@ -142,20 +234,38 @@ pub trait ByteBuffer {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        let labels = qname.split('.').collect::<Vec<&str>>();
-
-        for label in labels {
-            // Check label length
-            let len = label.len();
-            if len > MAX_LABEL_LEN {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Label exceeds maximum length: {0}", MAX_LABEL_LEN),
-                ));
+        for label in split_labels(qname)? {
+            // Write the length of the label and then the label
+            self.write(label.len() as u8)?;
+            for label_byte in label.as_bytes() {
+                self.write(*label_byte)?;
             }
+        }
 
-            // Write the length of the label and then the label
-            self.write(len as u8)?;
+        // Write null byte to terminate qname
+        self.write(0)?;
+
+        Ok(())
+    }
+
+    // Write a name, pointing back at an earlier copy of its longest suffix already in the
+    // message. Offsets are taken from the head, so the message must start at offset 0.
+    fn write_compressed_qname(&mut self, qname: &str, table: &mut CompressionTable) -> Result<()> {
+        let labels = split_labels(qname)?;
+
+        for (i, label) in labels.iter().enumerate() {
+            // Names compare case-insensitively
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(&offset) = table.offsets.get(&suffix) {
+                return self.write_u16(0xC000 | offset as u16);
+            }
+
+            // Later names can only point at suffixes within reach of a pointer
+            if table.enabled && self.head() <= MAX_POINTER_OFFSET {
+                table.offsets.insert(suffix, self.head());
+            }
+
+            self.write(label.len() as u8)?;
             for label_byte in label.as_bytes() {
                 self.write(*label_byte)?;
             }
@@ -169,27 +279,36 @@ pub trait ByteBuffer {
 }
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; MAX_UDP_SIZE], // buffer data
-    pub head: usize,         // byte-offset in packet
+    pub buf: Vec<u8>, // buffer data
+    pub head: usize,  // byte-offset in packet
 }
 
 impl BytePacketBuffer {
     // Fresh buffer
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(MAX_UDP_SIZE)
+    }
+
+    // Fresh buffer for a UDP payload size negotiated with EDNS(0)
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; MAX_UDP_SIZE],
+            buf: vec![0; size],
             head: 0,
         }
     }
 }
 
 impl ByteBuffer for BytePacketBuffer {
-    const MAX_SIZE: usize = MAX_UDP_SIZE;
+    const MAX_SIZE: usize = MAX_EDNS_SIZE;
 
     fn head(&self) -> usize {
         self.head
     }
 
+    fn max_size(&self) -> usize {
+        self.buf.len()
+    }
+
     fn step(&mut self, steps: usize) -> Result<()> {
         self.head += steps;
 
@@ -204,7 +323,7 @@ impl ByteBuffer for BytePacketBuffer {
 
     fn read(&mut self) -> Result<u8> {
         if self.head >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::Truncated);
         }
         let data = self.buf[self.head];
         self.step(1)?;
@@ -214,15 +333,15 @@ impl ByteBuffer for BytePacketBuffer {
 
     fn get(&self, offset: usize) -> Result<u8> {
         if offset >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::Truncated);
         }
 
         Ok(self.buf[offset])
     }
 
     fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if start + len > self.max_size() {
+            return Err(DnsError::Truncated);
         }
 
         Ok(&self.buf[start..start + len])
@@ -230,15 +349,19 @@ impl ByteBuffer for BytePacketBuffer {
 
     fn write(&mut self, val: u8) -> Result<()> {
         if self.head() >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(DnsError::BufferFull);
         }
-        self.buf[self.head()] = val;
+        let head = self.head();
+        self.buf[head] = val;
         self.step(1)?;
 
         Ok(())
     }
 
     fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.max_size() {
+            return Err(DnsError::BufferFull);
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -260,7 +383,7 @@ impl ExtendingBuffer {
 }
 
 impl ByteBuffer for ExtendingBuffer {
-    const MAX_SIZE: usize = usize::max_value();
+    const MAX_SIZE: usize = usize::MAX;
 
     fn head(&self) -> usize {
         self.head
@@ -279,7 +402,7 @@ impl ByteBuffer for ExtendingBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        let data = self.buf[self.head()];
+        let data = self.get(self.head())?;
         self.step(1)?;
 
         Ok(data)
@@ -293,8 +416,8 @@ impl ByteBuffer for ExtendingBuffer {
     }
 
     fn get(&self, offset: usize) -> Result<u8> {
-        if self.head() >= self.buf.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted read beyond buffer"));
+        if offset >= self.buf.len() {
+            return Err(DnsError::Truncated);
         }
 
         let data = self.buf[offset];
@@ -304,7 +427,7 @@ impl ByteBuffer for ExtendingBuffer {
 
     fn get_range(&self, offset: usize, len: usize) -> Result<&[u8]> {
         if offset + len > self.buf.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted read beyond buffer"));
+            return Err(DnsError::Truncated);
         }
 
         let data = &self.buf[offset..offset + len];
@@ -314,7 +437,7 @@ impl ByteBuffer for ExtendingBuffer {
 
     fn set(&mut self, offset: usize, data: u8) -> Result<()> {
         if offset >= self.buf.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted write beyond buffer"));
+            return Err(DnsError::BufferFull);
         }
 
         self.buf[offset] = data;
@@ -329,8 +452,8 @@ pub struct VariableBuffer {
 }
 
 impl VariableBuffer {
-    pub fn new(capacity: usize) -> Self {
-        let buf = vec![0; capacity];
+    // Wrap a complete message, such as one read off a TCP stream
+    pub fn from_bytes(buf: Vec<u8>) -> Self {
         Self {
             buf,
             head: 0
@@ -339,7 +462,7 @@ impl VariableBuffer {
 }
 
 impl ByteBuffer for VariableBuffer {
-    const MAX_SIZE: usize = usize::max_value();
+    const MAX_SIZE: usize = usize::MAX;
 
     fn head(&self) -> usize {
         self.head
@@ -363,14 +486,14 @@ impl ByteBuffer for VariableBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        let data = self.buf[self.head()];
+        let data = self.get(self.head())?;
         self.step(1)?;
 
         Ok(data)
     }
 
     fn write(&mut self, data: u8) -> Result<()> {
-        self.buf[self.head] = data;
+        self.set(self.head, data)?;
         self.step(1)?;
 
         Ok(())
@@ -378,7 +501,7 @@ impl ByteBuffer for VariableBuffer {
 
     fn get(&self, offset: usize) -> Result<u8> {
         if offset >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted read beyond buffer"));
+            return Err(DnsError::Truncated);
         }
 
         let data = self.buf[offset];
@@ -388,7 +511,7 @@ impl ByteBuffer for VariableBuffer {
 
     fn get_range(&self, offset: usize, len: usize) -> Result<&[u8]> {
         if offset + len > self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted read beyond buffer"));
+            return Err(DnsError::Truncated);
         }
 
         let data = &self.buf[offset..offset + len];
@@ -398,11 +521,168 @@ impl ByteBuffer for VariableBuffer {
 
     fn set(&mut self, offset: usize, data: u8) -> Result<()> {
         if offset >= self.max_size() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Attempted write beyond buffer"));
+            return Err(DnsError::BufferFull);
         }
 
         self.buf[offset] = data;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_names(buf: &[u8], count: usize) -> Vec<String> {
+        let mut buffer = VariableBuffer::from_bytes(buf.to_vec());
+        (0..count)
+            .map(|_| {
+                let mut name = String::new();
+                buffer.read_qname(&mut name).unwrap();
+                name
+            })
+            .collect()
+    }
+
+    fn read_name(buf: &[u8], offset: usize) -> Result<String> {
+        let mut buffer = VariableBuffer::from_bytes(buf.to_vec());
+        buffer.seek(offset)?;
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+        Ok(name)
+    }
+
+    // A name of the given label lengths, in a's
+    fn long_name(lens: &[usize]) -> String {
+        lens.iter().map(|&len| "a".repeat(len)).collect::<Vec<String>>().join(".")
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        // Pointing at itself
+        assert!(matches!(read_name(&[0xC0, 0], 0), Err(DnsError::PointerLoop)));
+        // Two pointers at each other
+        assert!(matches!(read_name(&[0xC0, 2, 0xC0, 0], 2), Err(DnsError::PointerLoop)));
+    }
+
+    #[test]
+    fn rejects_forward_pointers() {
+        let buf = [0xC0, 2, 3, b'c', b'o', b'm', 0];
+        assert!(matches!(read_name(&buf, 0), Err(DnsError::PointerLoop)));
+        assert_eq!(read_name(&buf, 2).unwrap(), "com");
+    }
+
+    #[test]
+    fn rejects_names_over_255_bytes() {
+        let mut buffer = ExtendingBuffer::new();
+        let longest = long_name(&[63, 63, 63, 61]);
+        buffer.write_qname(&longest).unwrap();
+        assert_eq!(buffer.head(), MAX_NAME_LEN);
+        assert_eq!(read_name(&buffer.buf, 0).unwrap(), longest);
+
+        // One more byte in the last label, with its terminating null moved along
+        let mut buf = buffer.buf.clone();
+        buf[3 * 64] = 62;
+        buf.insert(MAX_NAME_LEN - 1, b'a');
+        assert!(matches!(read_name(&buf, 0), Err(DnsError::NameTooLong)));
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        for &label_type in &[0x40, 0x80] {
+            let buf = [label_type | 3, b'c', b'o', b'm', 0];
+            assert!(matches!(read_name(&buf, 0), Err(DnsError::BadLabel(_))));
+        }
+    }
+
+    #[test]
+    fn writers_reject_names_which_cannot_be_read() {
+        let too_long = long_name(&[63, 63, 63, 62]);
+        for name in &["www..example.com", "example.com.", ".", &too_long] {
+            let mut buffer = ExtendingBuffer::new();
+            assert!(buffer.write_qname(name).is_err(), "{0}", name);
+            let mut names = CompressionTable::new();
+            assert!(buffer.write_compressed_qname(name, &mut names).is_err(), "{0}", name);
+            assert_eq!(buffer.head(), 0);
+        }
+    }
+
+    #[test]
+    fn compression_reuses_suffixes() {
+        let mut buffer = ExtendingBuffer::new();
+        let mut names = CompressionTable::new();
+        buffer.write_compressed_qname("www.example.com", &mut names).unwrap();
+        buffer.write_compressed_qname("mail.example.com", &mut names).unwrap();
+        buffer.write_compressed_qname("example.com", &mut names).unwrap();
+        buffer.write_compressed_qname("www.example.com", &mut names).unwrap();
+
+        // example.com starts after the first label of the first name
+        assert_eq!(&buffer.buf[17..], &[
+            4, b'm', b'a', b'i', b'l', 0xC0, 4,
+            0xC0, 4,
+            0xC0, 0,
+        ]);
+        assert_eq!(read_names(&buffer.buf, 4), vec![
+            "www.example.com",
+            "mail.example.com",
+            "example.com",
+            "www.example.com",
+        ]);
+    }
+
+    #[test]
+    fn compression_ignores_case() {
+        let mut buffer = ExtendingBuffer::new();
+        let mut names = CompressionTable::new();
+        buffer.write_compressed_qname("WWW.Example.COM", &mut names).unwrap();
+        buffer.write_compressed_qname("ftp.EXAMPLE.com", &mut names).unwrap();
+
+        assert_eq!(&buffer.buf[17..], &[3, b'f', b't', b'p', 0xC0, 4]);
+        assert_eq!(read_names(&buffer.buf, 2), vec!["www.example.com", "ftp.example.com"]);
+    }
+
+    #[test]
+    fn compression_skips_names_out_of_pointer_reach() {
+        let mut buffer = ExtendingBuffer::new();
+        let mut names = CompressionTable::new();
+        buffer.write_compressed_qname("near.example.com", &mut names).unwrap();
+        while buffer.head() <= MAX_POINTER_OFFSET {
+            buffer.write(0).unwrap();
+        }
+
+        // Written past where a pointer can reach, so later copies are spelled out in full
+        let far = buffer.head();
+        buffer.write_compressed_qname("far.test", &mut names).unwrap();
+        let again = buffer.head();
+        buffer.write_compressed_qname("far.test", &mut names).unwrap();
+        assert_eq!(&buffer.buf[far..again], &buffer.buf[again..]);
+
+        // Names within reach are still pointed at from beyond it
+        let near = buffer.head();
+        buffer.write_compressed_qname("near.example.com", &mut names).unwrap();
+        assert_eq!(&buffer.buf[near..], &[0xC0, 0]);
+
+        let mut buffer = VariableBuffer::from_bytes(buffer.buf);
+        for &(offset, expected) in &[
+            (far, "far.test"),
+            (again, "far.test"),
+            (near, "near.example.com"),
+        ] {
+            let mut name = String::new();
+            buffer.seek(offset).unwrap();
+            buffer.read_qname(&mut name).unwrap();
+            assert_eq!(name, expected);
+        }
+    }
+
+    #[test]
+    fn disabled_compression_writes_names_in_full() {
+        let mut buffer = ExtendingBuffer::new();
+        let mut names = CompressionTable::disabled();
+        buffer.write_compressed_qname("example.com", &mut names).unwrap();
+        buffer.write_compressed_qname("example.com", &mut names).unwrap();
+
+        assert_eq!(&buffer.buf[..13], &buffer.buf[13..]);
+    }
+}
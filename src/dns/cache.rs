@@ -0,0 +1,461 @@
+use super::protocol::{DnsPacket, DnsRecord, QueryType, ResponseCode};
+use std::borrow::Borrow;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+struct CacheEntry {
+    record: DnsRecord,
+    expires: Instant,
+}
+
+impl CacheEntry {
+    fn new(record: DnsRecord, now: Instant) -> CacheEntry {
+        let expires = now + Duration::from_secs(record.get_ttl() as u64);
+        CacheEntry { record, expires }
+    }
+
+    // Copy of the record with its TTL counted down to the remaining lifetime
+    fn remaining(&self, now: Instant) -> Option<DnsRecord> {
+        if self.expires <= now {
+            return None;
+        }
+
+        let mut record = self.record.clone();
+        record.set_ttl((self.expires - now).as_secs() as u32);
+
+        Some(record)
+    }
+}
+
+// What we know about a single (name, type) question. Each answer keeps whether it was
+// authenticated (the AD bit), so a cached copy is marked just like the original.
+enum CachedAnswer {
+    // The records answering the question, and any NSEC or NSEC3 records proving an answer
+    // expanded from a wildcard
+    Records(Vec<CacheEntry>, Vec<CacheEntry>, bool),
+    // The name exists, but has no records of this type (NODATA); holds the SOA and any
+    // DNSSEC records proving it
+    NoData(Vec<CacheEntry>, bool),
+}
+
+// Entries cached together are only usable until the first of them expires
+fn expires<'a, I>(entries: I) -> Instant
+where
+    I: IntoIterator<Item = &'a CacheEntry>,
+{
+    entries
+        .into_iter()
+        .map(|entry| entry.expires)
+        .min()
+        .unwrap_or_else(Instant::now)
+}
+
+// The records of entries cached together, or None once any of them has expired
+fn remaining(entries: &[CacheEntry], now: Instant) -> Option<Vec<DnsRecord>> {
+    entries
+        .iter()
+        .map(|entry| entry.remaining(now))
+        .collect::<Option<Vec<DnsRecord>>>()
+}
+
+// A map of cached values, also indexed by when each expires so the ones expiring soonest can be
+// found without a scan. The index is keyed by a sequence number as well, as many values expire
+// at the same instant.
+pub struct ExpiringMap<K, V> {
+    values: HashMap<K, (V, (Instant, u64))>,
+    expiry: BTreeMap<(Instant, u64), K>,
+    sequence: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> ExpiringMap<K, V> {
+    pub fn new() -> ExpiringMap<K, V> {
+        ExpiringMap {
+            values: HashMap::new(),
+            expiry: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.values.get(key).map(|(value, _)| value)
+    }
+
+    // A value, unless it has expired
+    pub fn get_live<Q>(&self, key: &Q, now: Instant) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.values.get(key) {
+            Some((value, (expires, _))) if *expires > now => Some(value),
+            _ => None,
+        }
+    }
+
+    fn remove<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if let Some((_, slot)) = self.values.remove(key) {
+            self.expiry.remove(&slot);
+        }
+    }
+
+    // Insert a value, first dropping any which have expired, and then whichever expire soonest
+    // until there's room for it
+    pub fn insert(&mut self, key: K, value: V, expires: Instant, max_entries: usize) {
+        self.remove(&key);
+
+        let now = Instant::now();
+        while let Some(first) = self.expiry.first_entry() {
+            if first.key().0 > now && self.values.len() < max_entries {
+                break;
+            }
+            self.values.remove(&first.remove());
+        }
+
+        let slot = (expires, self.sequence);
+        self.sequence += 1;
+        self.expiry.insert(slot, key.clone());
+        self.values.insert(key, (value, slot));
+    }
+}
+
+// Thread-safe cache of answers, keyed by question name and type
+pub struct RecordCache {
+    answers: RwLock<ExpiringMap<(String, QueryType), CachedAnswer>>,
+    // Names which do not exist at all (NXDOMAIN), regardless of type; holds the SOA and any
+    // DNSSEC records proving it, and whether the answer was authenticated
+    nxdomains: RwLock<ExpiringMap<String, (Vec<CacheEntry>, bool)>>,
+    // Bound on each of the maps above; 0 disables caching
+    max_entries: usize,
+}
+
+impl RecordCache {
+    pub fn new(max_entries: usize) -> RecordCache {
+        RecordCache {
+            answers: RwLock::new(ExpiringMap::new()),
+            nxdomains: RwLock::new(ExpiringMap::new()),
+            max_entries,
+        }
+    }
+
+    fn insert_answer(&self, key: (String, QueryType), answer: CachedAnswer) {
+        let answer_expires = match answer {
+            CachedAnswer::Records(ref entries, ref proofs, _) => {
+                expires(entries.iter().chain(proofs))
+            }
+            CachedAnswer::NoData(ref entries, _) => expires(entries),
+        };
+
+        self.answers
+            .write()
+            .expect("Failed to acquire cache lock")
+            .insert(key, answer, answer_expires, self.max_entries);
+    }
+
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let qname = qname.to_lowercase();
+        let now = Instant::now();
+
+        if let Some(packet) = self.lookup_nxdomain(&qname, now) {
+            return Some(packet);
+        }
+
+        let key = (qname, qtype);
+        let packet = {
+            let answers = self.answers.read().expect("Failed to acquire cache lock");
+            match answers.get(&key)? {
+                // An answer is only usable if every record in it is still alive
+                CachedAnswer::Records(entries, proofs, authed_data) => remaining(entries, now)
+                    .zip(remaining(proofs, now))
+                    .map(|(records, proofs)| {
+                        let mut packet = DnsPacket::new();
+                        packet.header.rescode = ResponseCode::NOERROR;
+                        packet.header.authed_data = *authed_data;
+                        packet.answers = records;
+                        packet.authorities = proofs;
+                        packet
+                    }),
+                CachedAnswer::NoData(entries, authed_data) => {
+                    remaining(entries, now).map(|records| {
+                        let mut packet = DnsPacket::new();
+                        packet.header.rescode = ResponseCode::NOERROR;
+                        packet.header.authed_data = *authed_data;
+                        packet.authorities = records;
+                        packet
+                    })
+                }
+            }
+        };
+
+        if packet.is_none() {
+            // Evict the stale answer so the next lookup goes to the network
+            self.answers
+                .write()
+                .expect("Failed to acquire cache lock")
+                .remove(&key);
+        }
+
+        packet
+    }
+
+    fn lookup_nxdomain(&self, qname: &str, now: Instant) -> Option<DnsPacket> {
+        let (records, authed_data) = {
+            let nxdomains = self.nxdomains.read().expect("Failed to acquire cache lock");
+            let (entries, authed_data) = nxdomains.get(qname)?;
+            (remaining(entries, now), *authed_data)
+        };
+
+        match records {
+            Some(records) => {
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = ResponseCode::NXDOMAIN;
+                packet.header.authed_data = authed_data;
+                packet.authorities = records;
+
+                Some(packet)
+            }
+            None => {
+                self.nxdomains
+                    .write()
+                    .expect("Failed to acquire cache lock")
+                    .remove(qname);
+
+                None
+            }
+        }
+    }
+
+    // Cache the result of resolving a question, whether positive or negative
+    pub fn store(&self, qname: &str, qtype: QueryType, packet: &DnsPacket) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let qname = qname.to_lowercase();
+
+        match packet.header.rescode {
+            ResponseCode::NOERROR if !packet.answers.is_empty() => {
+                self.store_records(qname, qtype, packet)
+            }
+            ResponseCode::NOERROR => {
+                if let Some(entries) = negative_entries(packet) {
+                    let answer = CachedAnswer::NoData(entries, packet.header.authed_data);
+                    self.insert_answer((qname, qtype), answer);
+                }
+            }
+            // An NXDOMAIN alongside answers denies the end of a CNAME chain, not the name asked for
+            ResponseCode::NXDOMAIN if packet.answers.is_empty() => {
+                if let Some(entries) = negative_entries(packet) {
+                    let nxdomain_expires = expires(&entries);
+                    self.nxdomains
+                        .write()
+                        .expect("Failed to acquire cache lock")
+                        .insert(
+                            qname,
+                            (entries, packet.header.authed_data),
+                            nxdomain_expires,
+                            self.max_entries,
+                        );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn store_records(&self, qname: String, qtype: QueryType, packet: &DnsPacket) {
+        let proofs = packet
+            .authorities
+            .iter()
+            .filter(|rec| is_denial(rec))
+            .collect::<Vec<&DnsRecord>>();
+
+        // Records with no lifetime must not be cached at all
+        if packet
+            .answers
+            .iter()
+            .chain(proofs.iter().copied())
+            .any(|rec| rec.get_ttl() == 0)
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        let cached = packet
+            .answers
+            .iter()
+            .map(|rec| CacheEntry::new(rec.clone(), now))
+            .collect::<Vec<CacheEntry>>();
+        let proofs = proofs
+            .into_iter()
+            .map(|rec| CacheEntry::new(rec.clone(), now))
+            .collect::<Vec<CacheEntry>>();
+
+        let answer = CachedAnswer::Records(cached, proofs, packet.header.authed_data);
+        self.insert_answer((qname, qtype), answer);
+    }
+}
+
+// NSEC and NSEC3 records, and their signatures
+fn is_denial(rec: &DnsRecord) -> bool {
+    match *rec {
+        DnsRecord::RRSIG { type_covered, .. } => {
+            matches!(type_covered, QueryType::NSEC | QueryType::NSEC3)
+        }
+        _ => matches!(rec.get_querytype(), QueryType::NSEC | QueryType::NSEC3),
+    }
+}
+
+// Negative answers live for the lesser of the SOA's TTL and its MINIMUM field (RFC 2308 section 5).
+// Any NSEC, NSEC3 and RRSIG records proving the answer are kept alongside the SOA, for no longer.
+fn negative_entries(packet: &DnsPacket) -> Option<Vec<CacheEntry>> {
+    let negative_ttl = match *packet.get_soa()? {
+        DnsRecord::SOA { minimum, ttl, .. } => cmp::min(minimum, ttl),
+        _ => return None,
+    };
+    if negative_ttl == 0 {
+        return None;
+    }
+
+    let now = Instant::now();
+    let entries = packet
+        .authorities
+        .iter()
+        .filter(|rec| {
+            matches!(
+                rec.get_querytype(),
+                QueryType::SOA | QueryType::NSEC | QueryType::NSEC3 | QueryType::RRSIG
+            )
+        })
+        .map(|rec| {
+            let mut rec = rec.clone();
+            rec.set_ttl(cmp::min(rec.get_ttl(), negative_ttl));
+            CacheEntry::new(rec, now)
+        })
+        .collect();
+
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn soa() -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        }
+    }
+
+    fn response(rescode: ResponseCode, authed_data: bool) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        packet.header.authed_data = authed_data;
+        packet
+    }
+
+    #[test]
+    fn cache_hit_keeps_authenticated_data() {
+        for &authed_data in &[true, false] {
+            let cache = RecordCache::new(16);
+
+            let mut answer = response(ResponseCode::NOERROR, authed_data);
+            answer.answers.push(DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "192.0.2.1".parse().unwrap(),
+                ttl: 300,
+            });
+            cache.store("www.example.com", QueryType::A, &answer);
+
+            let mut nodata = response(ResponseCode::NOERROR, authed_data);
+            nodata.authorities.push(soa());
+            cache.store("www.example.com", QueryType::AAAA, &nodata);
+
+            let mut nxdomain = response(ResponseCode::NXDOMAIN, authed_data);
+            nxdomain.authorities.push(soa());
+            cache.store("nope.example.com", QueryType::A, &nxdomain);
+
+            for &(qname, qtype, rescode) in &[
+                ("www.example.com", QueryType::A, ResponseCode::NOERROR),
+                ("www.example.com", QueryType::AAAA, ResponseCode::NOERROR),
+                ("nope.example.com", QueryType::A, ResponseCode::NXDOMAIN),
+            ] {
+                let cached = cache.lookup(qname, qtype).unwrap();
+                assert_eq!(cached.header.rescode, rescode);
+                assert_eq!(cached.header.authed_data, authed_data);
+            }
+        }
+    }
+
+    fn a_answer(domain: &str, ttl: u32) -> DnsPacket {
+        let mut packet = response(ResponseCode::NOERROR, false);
+        packet.answers.push(DnsRecord::A {
+            domain: domain.to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl,
+        });
+        packet
+    }
+
+    #[test]
+    fn full_cache_evicts_soonest_to_expire() {
+        let cache = RecordCache::new(2);
+        cache.store(
+            "long.example.com",
+            QueryType::A,
+            &a_answer("long.example.com", 3600),
+        );
+        cache.store(
+            "short.example.com",
+            QueryType::A,
+            &a_answer("short.example.com", 60),
+        );
+        cache.store(
+            "new.example.com",
+            QueryType::A,
+            &a_answer("new.example.com", 300),
+        );
+
+        assert!(cache.lookup("short.example.com", QueryType::A).is_none());
+        assert!(cache.lookup("long.example.com", QueryType::A).is_some());
+        assert!(cache.lookup("new.example.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn replacing_an_answer_does_not_evict_others() {
+        let cache = RecordCache::new(2);
+        cache.store(
+            "one.example.com",
+            QueryType::A,
+            &a_answer("one.example.com", 60),
+        );
+        for ttl in 100..200 {
+            cache.store(
+                "two.example.com",
+                QueryType::A,
+                &a_answer("two.example.com", ttl),
+            );
+        }
+
+        assert!(cache.lookup("one.example.com", QueryType::A).is_some());
+        let cached = cache.lookup("two.example.com", QueryType::A).unwrap();
+        assert!(cached.answers[0].get_ttl() > 190);
+        assert_eq!(cache.answers.read().unwrap().expiry.len(), 2);
+    }
+}
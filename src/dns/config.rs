@@ -0,0 +1,393 @@
+use super::buffer::{MAX_EDNS_SIZE, MAX_UDP_SIZE};
+use super::upstream::Strategy;
+use serde::Deserialize;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+// Avoids IP fragmentation on common paths; see the DNS Flag Day 2020 recommendation
+const DEFAULT_EDNS_SIZE: u16 = 1232;
+
+fn check_edns_size(section: &str, size: u16) -> Result<()> {
+    if (size as usize) < MAX_UDP_SIZE || (size as usize) > MAX_EDNS_SIZE {
+        return Err(invalid(format!(
+            "{0}: edns_udp_size must be between {1} and {2}",
+            section, MAX_UDP_SIZE, MAX_EDNS_SIZE
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Address and port the UDP and TCP servers listen on
+    pub listen_address: IpAddr,
+    pub port: u16,
+    pub udp_threads: usize,
+    pub tcp_threads: usize,
+    // How long an idle TCP client connection is kept open, in milliseconds
+    pub tcp_timeout_ms: u64,
+    // Maximum number of simultaneous TCP client connections
+    pub max_tcp_connections: usize,
+    // Largest UDP response we'll send to EDNS(0) clients
+    pub edns_udp_size: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 2053,
+            udp_threads: 5,
+            tcp_threads: 5,
+            tcp_timeout_ms: 10_000,
+            max_tcp_connections: 100,
+            edns_udp_size: DEFAULT_EDNS_SIZE,
+        }
+    }
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+// An upstream server, given either as just its address or along with a weight
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamConfig {
+    Address(String),
+    Weighted {
+        address: String,
+        #[serde(default = "default_weight")]
+        weight: usize,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    // Either "recursive" or "forward"
+    pub mode: String,
+    // Downstream server used in forwarding mode: host[:port] to query with plain DNS,
+    // tls://host[:port] for DNS over TLS, or an https:// URL for DNS over HTTPS. IPv6
+    // addresses need brackets to be given a port.
+    pub upstream: Option<String>,
+    // Further downstream servers, each optionally weighted
+    pub upstreams: Vec<UpstreamConfig>,
+    // How the server each query goes to is picked: "strict" order, "round-robin", "random"
+    // or "lowest-latency". Weights apply to round-robin and random.
+    pub upstream_strategy: String,
+    // How often servers which stopped answering are checked on, in milliseconds
+    pub health_check_interval_ms: u64,
+    // Fixed local port to send upstream queries from. Leave unset to use a pool of
+    // randomized ports instead, which is much harder to spoof.
+    pub client_port: Option<u16>,
+    // Number of randomized source ports in the pool
+    pub source_ports: usize,
+    // How long to wait for each upstream response, in milliseconds
+    pub timeout_ms: u64,
+    // How many times a timed out query is resent before giving up. With several upstreams,
+    // each is sent the query once per round instead.
+    pub retries: u32,
+    // Delay before the first retry, in milliseconds; doubled for each later retry
+    pub backoff_ms: u64,
+    // UDP payload size advertised to upstream servers with EDNS(0)
+    pub edns_udp_size: u16,
+    // Validate answers with DNSSEC in recursive mode. Off unless asked for, as answers from
+    // zones with broken signatures then fail rather than resolve.
+    pub dnssec_validation: bool,
+    // DS or DNSKEY records in master file format to trust instead of the root zone's keys
+    pub trust_anchor_file: Option<PathBuf>,
+    // Name a DNS over TLS or HTTPS upstream's certificate must be valid for, also sent as the
+    // SNI. Defaults to the upstream's host.
+    pub tls_name: Option<String>,
+    // PEM file of CA certificates to check a DNS over TLS or HTTPS upstream against, in place
+    // of the Mozilla root CAs
+    pub tls_ca_file: Option<PathBuf>,
+    // Base64 SHA-256 hashes of the public keys a DNS over TLS or HTTPS upstream may present.
+    // When set, these are checked instead of the CA file. A pin on the server's own key skips
+    // the name check; a pin on an intermediate CA's key needs a chain to it valid for the name.
+    pub tls_spki_pins: Vec<String>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> ResolverConfig {
+        ResolverConfig {
+            mode: "recursive".to_string(),
+            upstream: None,
+            upstreams: Vec::new(),
+            upstream_strategy: "strict".to_string(),
+            health_check_interval_ms: 5000,
+            client_port: None,
+            source_ports: 16,
+            timeout_ms: 2000,
+            retries: 2,
+            backoff_ms: 100,
+            edns_udp_size: DEFAULT_EDNS_SIZE,
+            dnssec_validation: false,
+            trust_anchor_file: None,
+            tls_name: None,
+            tls_ca_file: None,
+            tls_spki_pins: Vec::new(),
+        }
+    }
+}
+
+impl ResolverConfig {
+    // Every upstream server with its weight, the single upstream first
+    pub fn upstream_list(&self) -> Vec<(&str, usize)> {
+        let upstreams = self.upstreams.iter().map(|upstream| match upstream {
+            UpstreamConfig::Address(address) => (address.as_str(), 1),
+            UpstreamConfig::Weighted { address, weight } => (address.as_str(), *weight),
+        });
+
+        self.upstream
+            .iter()
+            .map(|address| (address.as_str(), 1))
+            .chain(upstreams)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // Maximum number of cached answers; 0 disables caching
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            max_entries: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // DNS over TLS is served once a certificate and key are given
+    pub port: u16,
+    pub threads: usize,
+    // PEM files holding the certificate chain and its private key. Changes to them are picked
+    // up by new connections.
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig {
+            port: 853,
+            threads: 5,
+            certificate: None,
+            private_key: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.certificate.is_some()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpsConfig {
+    // DNS over HTTPS, served with the certificate from the tls section over HTTP/2, or
+    // HTTP/1.1 for clients which don't speak it
+    pub enabled: bool,
+    pub port: u16,
+    pub threads: usize,
+    // Path queries are accepted on
+    pub path: String,
+}
+
+impl Default for HttpsConfig {
+    fn default() -> HttpsConfig {
+        HttpsConfig {
+            enabled: false,
+            port: 443,
+            threads: 5,
+            path: "/dns-query".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub resolver: ResolverConfig,
+    pub cache: CacheConfig,
+    pub tls: TlsConfig,
+    pub https: HttpsConfig,
+    // RFC 1035 master files to serve authoritatively
+    pub zones: Vec<PathBuf>,
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to read {0}: {1}", path.display(), e),
+            )
+        })?;
+
+        toml::from_str(&contents)
+            .map_err(|e| invalid(format!("Failed to parse {0}: {1}", path.display(), e)))
+    }
+
+    // Check the settings are coherent before anything is started with them
+    pub fn validate(&self) -> Result<()> {
+        if self.server.udp_threads == 0 || self.server.tcp_threads == 0 {
+            return Err(invalid(
+                "server: thread counts must be at least 1".to_string(),
+            ));
+        }
+
+        if self.server.tcp_timeout_ms == 0 {
+            return Err(invalid(
+                "server: tcp_timeout_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.server.max_tcp_connections == 0 {
+            return Err(invalid(
+                "server: max_tcp_connections must be at least 1".to_string(),
+            ));
+        }
+
+        check_edns_size("server", self.server.edns_udp_size)?;
+        check_edns_size("resolver", self.resolver.edns_udp_size)?;
+
+        if self.resolver.client_port.is_none() && self.resolver.source_ports == 0 {
+            return Err(invalid(
+                "resolver: source_ports must be at least 1".to_string(),
+            ));
+        }
+
+        if self.resolver.timeout_ms == 0 {
+            return Err(invalid(
+                "resolver: timeout_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        match self.resolver.mode.as_str() {
+            "recursive" => {}
+            "forward" => {
+                if self.resolver.upstream_list().is_empty() {
+                    return Err(invalid(
+                        "resolver: forward mode requires an upstream".to_string(),
+                    ));
+                }
+            }
+            other => {
+                return Err(invalid(format!(
+                    "resolver: unknown mode {0:?}, expected \"recursive\" or \"forward\"",
+                    other
+                )))
+            }
+        }
+
+        if self
+            .resolver
+            .upstream_list()
+            .iter()
+            .any(|&(_, weight)| weight == 0)
+        {
+            return Err(invalid(
+                "resolver: upstream weights must be at least 1".to_string(),
+            ));
+        }
+
+        if Strategy::from_name(&self.resolver.upstream_strategy).is_none() {
+            return Err(invalid(format!(
+                "resolver: unknown upstream_strategy {0:?}, expected one of strict, \
+                 round-robin, random or lowest-latency",
+                self.resolver.upstream_strategy
+            )));
+        }
+
+        if self.resolver.health_check_interval_ms == 0 {
+            return Err(invalid(
+                "resolver: health_check_interval_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(ref path) = self.resolver.trust_anchor_file {
+            if !path.is_file() {
+                return Err(invalid(format!(
+                    "resolver: trust_anchor_file {0} is not a readable file",
+                    path.display()
+                )));
+            }
+        }
+
+        if let Some(ref path) = self.resolver.tls_ca_file {
+            if !path.is_file() {
+                return Err(invalid(format!(
+                    "resolver: tls_ca_file {0} is not a readable file",
+                    path.display()
+                )));
+            }
+        }
+
+        if self.tls.certificate.is_some() != self.tls.private_key.is_some() {
+            return Err(invalid(
+                "tls: certificate and private_key must be given together".to_string(),
+            ));
+        }
+
+        if self.tls.threads == 0 {
+            return Err(invalid("tls: threads must be at least 1".to_string()));
+        }
+
+        if self.https.enabled && self.tls.certificate.is_none() {
+            return Err(invalid(
+                "https: serving DNS over HTTPS needs tls.certificate and tls.private_key"
+                    .to_string(),
+            ));
+        }
+
+        if self.https.threads == 0 {
+            return Err(invalid("https: threads must be at least 1".to_string()));
+        }
+
+        if !self.https.path.starts_with('/') {
+            return Err(invalid("https: path must start with /".to_string()));
+        }
+
+        let tls_files = self
+            .tls
+            .certificate
+            .iter()
+            .chain(self.tls.private_key.iter());
+        if let Some(path) = tls_files.into_iter().find(|path| !path.is_file()) {
+            return Err(invalid(format!(
+                "tls: {0} is not a readable file",
+                path.display()
+            )));
+        }
+
+        if let Some(zone) = self.zones.iter().find(|zone| !zone.is_file()) {
+            return Err(invalid(format!(
+                "zones: {0} is not a readable file",
+                zone.display()
+            )));
+        }
+
+        Ok(())
+    }
+}
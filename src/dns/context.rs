@@ -1,36 +1,109 @@
+use super::authority::{Authority, Zone};
+use super::cache::RecordCache;
+use super::config::Config;
+use super::dnssec::{KeyCache, ROOT_TRUST_ANCHORS};
 use super::network::NetworkClient;
+use super::protocol::{DnsRecord, QueryType};
 use super::resolver::{DnsResolver, ForwardResolver, RecursiveResolver, ResolverMode};
+use super::tls::CertificateStore;
+use super::upstream::UpstreamPool;
+use super::zonefile;
 use std::boxed::Box;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub struct ServerContext {
-    pub client: NetworkClient,
+    pub client: Arc<NetworkClient>,
+    pub cache: RecordCache,
+    pub authority: Authority,
+    pub listen_address: IpAddr,
     pub dns_port: u16,
+    pub tcp_timeout: Duration,
+    pub max_tcp_connections: usize,
+    // UDP payload size we advertise to clients with EDNS(0)
+    pub edns_udp_size: u16,
+    // DNS over TLS port, and the certificate served on it if enabled
+    pub tls_port: u16,
+    pub certificates: Option<CertificateStore>,
+    // DNS over HTTPS port, and the path queries are accepted on
+    pub https_port: u16,
+    pub doh_path: String,
     resolver_mode: ResolverMode,
     pub allow_recursion: bool,
+    // Whether the recursive resolver validates answers with DNSSEC, starting from these
+    // DS and DNSKEY records, and the keys it has validated so far
+    pub dnssec_validation: bool,
+    pub trust_anchors: Vec<DnsRecord>,
+    pub dnssec_keys: KeyCache,
 }
 
 impl ServerContext {
-    pub fn new() -> ServerContext {
-        ServerContext {
-            client: NetworkClient::new(34521),
-            dns_port: 2053,
-            resolver_mode: ResolverMode::Forwarding {
-                host: "0.0.0.0".to_string(),
-                port: 53,
-            },
-            allow_recursion: true,
+    pub fn new(config: &Config) -> Result<ServerContext> {
+        let client = Arc::new(NetworkClient::new(&config.resolver)?);
+        let resolver_mode = ResolverMode::from_config(&config.resolver)?;
+        if let ResolverMode::Forwarding(ref upstreams) = resolver_mode {
+            UpstreamPool::start_health_checks(
+                upstreams.clone(),
+                client.clone(),
+                Duration::from_millis(config.resolver.health_check_interval_ms),
+            )?;
         }
-    }
 
-    pub fn set_resolver_mode(&mut self, mode: ResolverMode) {
-        self.resolver_mode = mode;
+        // Load local authoritative zones
+        let mut authority = Authority::new();
+        for path in &config.zones {
+            authority.add_zone(Zone::from_file(path)?)?;
+        }
+
+        // DNSSEC trust anchors, the root zone's keys unless others are configured
+        let trust_anchors = match config.resolver.trust_anchor_file {
+            Some(ref path) => zonefile::load(path)?,
+            None => zonefile::parse(ROOT_TRUST_ANCHORS)?,
+        };
+        if trust_anchors
+            .iter()
+            .any(|rec| !matches!(rec.get_querytype(), QueryType::DS | QueryType::DNSKEY))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Trust anchors must be DS or DNSKEY records",
+            ));
+        }
+
+        let certificates = match (&config.tls.certificate, &config.tls.private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Some(CertificateStore::load(certificate, private_key)?)
+            }
+            _ => None,
+        };
+
+        Ok(ServerContext {
+            client,
+            cache: RecordCache::new(config.cache.max_entries),
+            authority,
+            listen_address: config.server.listen_address,
+            dns_port: config.server.port,
+            tcp_timeout: Duration::from_millis(config.server.tcp_timeout_ms),
+            max_tcp_connections: config.server.max_tcp_connections,
+            edns_udp_size: config.server.edns_udp_size,
+            tls_port: config.tls.port,
+            certificates,
+            https_port: config.https.port,
+            doh_path: config.https.path.clone(),
+            resolver_mode,
+            allow_recursion: true,
+            dnssec_validation: config.resolver.dnssec_validation,
+            trust_anchors,
+            dnssec_keys: KeyCache::new(config.cache.max_entries),
+        })
     }
 
     pub fn get_resolver(&self, context_ptr: Arc<ServerContext>) -> Box<dyn DnsResolver> {
         match self.resolver_mode {
-            ResolverMode::Forwarding { ref host, port } => {
-                Box::new(ForwardResolver::new((host.clone(), port), context_ptr))
+            ResolverMode::Forwarding(ref upstreams) => {
+                Box::new(ForwardResolver::new(upstreams.clone(), context_ptr))
             }
             ResolverMode::Recursive => Box::new(RecursiveResolver::new(context_ptr)),
         }
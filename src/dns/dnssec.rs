@@ -0,0 +1,1933 @@
+use super::buffer::{ByteBuffer, CompressionTable, ExtendingBuffer};
+use super::cache::ExpiringMap;
+use super::error::{DnsError, Result};
+use super::protocol::{fqdn, to_base32hex, DnsPacket, DnsRecord, QueryType, ResponseCode};
+use ring::{digest, signature};
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The root zone's key signing keys, as DS records (https://data.iana.org/root-anchors/)
+pub const ROOT_TRUST_ANCHORS: &str = "\
+. 0 IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. 0 IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+// DNSKEY flags (RFC 4034 section 2.1.1, RFC 5011 section 7)
+const FLAG_ZONE_KEY: u16 = 0x0100;
+const FLAG_REVOKED: u16 = 0x0080;
+// The only protocol value a DNSKEY may have (RFC 4034 section 2.1.2)
+const DNSKEY_PROTOCOL: u8 = 3;
+
+// NSEC3 spans with this flag may hide unsigned delegations (RFC 5155 section 3.1.2.1)
+const NSEC3_OPT_OUT: u8 = 0x01;
+const NSEC3_SHA1: u8 = 1;
+// Proofs needing more hash iterations are treated as insecure (RFC 9276 section 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+// Signing algorithms we can verify: RSA/SHA-256, ECDSA P-256/SHA-256, ECDSA P-384/SHA-384 and
+// Ed25519 (RFC 8624 section 3.1)
+fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 8 | 13 | 14 | 15)
+}
+
+// DS digest types: SHA-1, SHA-256 and SHA-384
+fn ds_digest(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+fn bogus(msg: String) -> DnsError {
+    DnsError::Bogus(msg)
+}
+
+fn labels(name: &str) -> Vec<&str> {
+    if name.is_empty() {
+        Vec::new()
+    } else {
+        name.split('.').collect()
+    }
+}
+
+// Whether `name` is `zone` or any name below it
+fn is_subdomain(name: &str, zone: &str) -> bool {
+    let (name, zone) = (labels(name), labels(zone));
+    name.len() >= zone.len()
+        && name[name.len() - zone.len()..]
+            .iter()
+            .zip(zone.iter())
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+// The ancestor of `name` made of its last `count` labels
+fn ancestor(name: &str, count: usize) -> String {
+    let labels = labels(name);
+    labels[labels.len() - count..].join(".")
+}
+
+fn wildcard_of(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{0}", name)
+    }
+}
+
+// Names compare label by label from the root, as lower case bytes (RFC 4034 section 6.1)
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a = labels(a).into_iter().rev().map(|l| l.to_ascii_lowercase());
+    let b = labels(b).into_iter().rev().map(|l| l.to_ascii_lowercase());
+    a.cmp(b)
+}
+
+// Whether `name` falls strictly between the ends of an NSEC or NSEC3 span. The last span of a
+// zone wraps around to the first name.
+fn in_span<T: Ord>(owner: T, next: T, name: T) -> bool {
+    if owner < next {
+        owner < name && name < next
+    } else {
+        owner < name || name < next
+    }
+}
+
+// Uncompressed, lower case wire form of a name
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels(name) {
+        wire.push(label.len() as u8);
+        wire.extend(label.to_ascii_lowercase().bytes());
+    }
+    wire.push(0);
+
+    wire
+}
+
+fn dnskey_rdata(key: &DnsRecord) -> Vec<u8> {
+    let mut rdata = Vec::new();
+    if let DnsRecord::DNSKEY {
+        flags,
+        protocol,
+        algorithm,
+        ref public_key,
+        ..
+    } = *key
+    {
+        rdata.extend(&flags.to_be_bytes());
+        rdata.push(protocol);
+        rdata.push(algorithm);
+        rdata.extend(public_key);
+    }
+
+    rdata
+}
+
+// RFC 4034 Appendix B
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut acc: u32 = 0;
+    for (idx, &byte) in rdata.iter().enumerate() {
+        acc += if idx % 2 == 0 {
+            (byte as u32) << 8
+        } else {
+            byte as u32
+        };
+    }
+    acc += (acc >> 16) & 0xFFFF;
+
+    (acc & 0xFFFF) as u16
+}
+
+// Zone keys which may sign records: not revoked, and of an algorithm we support
+fn is_usable_key(key: &DnsRecord) -> bool {
+    match *key {
+        DnsRecord::DNSKEY {
+            flags,
+            protocol,
+            algorithm,
+            ..
+        } => {
+            flags & FLAG_ZONE_KEY != 0
+                && flags & FLAG_REVOKED == 0
+                && protocol == DNSKEY_PROTOCOL
+                && is_supported_algorithm(algorithm)
+        }
+        _ => false,
+    }
+}
+
+fn same_key(a: &DnsRecord, b: &DnsRecord) -> bool {
+    match (a, b) {
+        (
+            DnsRecord::DNSKEY {
+                algorithm: a_algorithm,
+                public_key: a_key,
+                ..
+            },
+            DnsRecord::DNSKEY {
+                algorithm: b_algorithm,
+                public_key: b_key,
+                ..
+            },
+        ) => a_algorithm == b_algorithm && a_key == b_key,
+        _ => false,
+    }
+}
+
+// Whether a DS record is the digest of a zone's DNSKEY (RFC 4034 section 5.1.4)
+fn ds_matches(zone: &str, ds: &DnsRecord, key: &DnsRecord) -> bool {
+    match (ds, key) {
+        (
+            DnsRecord::DS {
+                key_tag: tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            },
+            DnsRecord::DNSKEY {
+                algorithm: key_algorithm,
+                ..
+            },
+        ) => {
+            let rdata = dnskey_rdata(key);
+            let algo = match ds_digest(*digest_type) {
+                Some(algo) => algo,
+                None => return false,
+            };
+            if algorithm != key_algorithm || *tag != key_tag(&rdata) {
+                return false;
+            }
+
+            let mut data = wire_name(zone);
+            data.extend(&rdata);
+            digest::digest(algo, &data).as_ref() == &digest[..]
+        }
+        _ => false,
+    }
+}
+
+// RSA public keys are the exponent length, exponent and modulus (RFC 3110 section 2)
+fn rsa_components(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = match *key.first()? {
+        0 => {
+            let len = u16::from_be_bytes([*key.get(1)?, *key.get(2)?]) as usize;
+            (len, &key[3..])
+        }
+        len => (len as usize, &key[1..]),
+    };
+    if rest.len() <= len {
+        return None;
+    }
+
+    let strip = |bytes: &[u8]| -> usize { bytes.iter().take_while(|&&b| b == 0).count() };
+    let (exponent, modulus) = rest.split_at(len);
+    Some((&exponent[strip(exponent)..], &modulus[strip(modulus)..]))
+}
+
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        8 => match rsa_components(public_key) {
+            Some((e, n)) => signature::RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    sig,
+                )
+                .is_ok(),
+            None => false,
+        },
+        // ECDSA keys are the bare curve point, without the uncompressed point prefix (RFC 6605)
+        13 | 14 => {
+            let algo = if algorithm == 13 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            let mut point = vec![0x04];
+            point.extend(public_key);
+            signature::UnparsedPublicKey::new(algo, point)
+                .verify(data, sig)
+                .is_ok()
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+// Signature times compare with serial number arithmetic, so they survive wrapping in 2106
+// (RFC 4034 section 3.1.5)
+fn in_validity_period(now: u32, inception: u32, expiration: u32) -> bool {
+    now.wrapping_sub(inception) as i32 >= 0 && expiration.wrapping_sub(now) as i32 >= 0
+}
+
+// The record's RDATA in canonical form: uncompressed, with the names in the types listed by
+// RFC 4034 section 6.2 lower cased (less NSEC, per RFC 6840 section 5.1)
+fn canonical_rdata(rec: &DnsRecord) -> Result<Vec<u8>> {
+    let mut rec = rec.clone();
+    match rec {
+        DnsRecord::NS { ref mut host, .. }
+        | DnsRecord::CNAME { ref mut host, .. }
+        | DnsRecord::PTR { ref mut host, .. }
+        | DnsRecord::MX { ref mut host, .. }
+        | DnsRecord::SRV { ref mut host, .. } => *host = host.to_lowercase(),
+        DnsRecord::SOA {
+            ref mut m_name,
+            ref mut r_name,
+            ..
+        } => {
+            *m_name = m_name.to_lowercase();
+            *r_name = r_name.to_lowercase();
+        }
+        DnsRecord::RRSIG {
+            ref mut signer_name,
+            ..
+        } => *signer_name = signer_name.to_lowercase(),
+        _ => {}
+    }
+
+    let mut buffer = ExtendingBuffer::new();
+    rec.write(&mut buffer, &mut CompressionTable::disabled())?;
+
+    // Skip the owner name, type, class, TTL and RDATA length
+    let start = wire_name(rec.get_domain()).len() + 10;
+    Ok(buffer.buf[start..buffer.head()].to_vec())
+}
+
+// The data an RRSIG signs: its own RDATA less the signature, then each record of the RRset in
+// canonical form and order (RFC 4034 section 3.1.8.1)
+fn signed_data(sig: &DnsRecord, owner: &str, records: &[&DnsRecord]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let (type_covered, original_ttl) = match *sig {
+        DnsRecord::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            ref signer_name,
+            ..
+        } => {
+            data.extend(&type_covered.to_num().to_be_bytes());
+            data.push(algorithm);
+            data.push(labels);
+            data.extend(&original_ttl.to_be_bytes());
+            data.extend(&expiration.to_be_bytes());
+            data.extend(&inception.to_be_bytes());
+            data.extend(&key_tag.to_be_bytes());
+            data.extend(wire_name(signer_name));
+            (type_covered, original_ttl)
+        }
+        _ => return Ok(data),
+    };
+
+    let mut rdatas = records
+        .iter()
+        .map(|rec| canonical_rdata(rec))
+        .collect::<Result<Vec<Vec<u8>>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    let owner = wire_name(owner);
+    for rdata in rdatas {
+        data.extend(&owner);
+        data.extend(&type_covered.to_num().to_be_bytes());
+        data.extend(&1u16.to_be_bytes());
+        data.extend(&original_ttl.to_be_bytes());
+        data.extend(&(rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+    }
+
+    Ok(data)
+}
+
+// Hash a name as NSEC3 does: SHA-1 over the name and salt, repeated (RFC 5155 section 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> String {
+    let mut input = wire_name(name);
+    input.extend(salt);
+    let mut hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &input);
+    for _ in 0..iterations {
+        let mut input = hash.as_ref().to_vec();
+        input.extend(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &input);
+    }
+
+    to_base32hex(hash.as_ref())
+}
+
+// Records sharing an owner and type, along with the signatures covering them
+struct RrSet<'a> {
+    owner: String,
+    rtype: QueryType,
+    records: Vec<&'a DnsRecord>,
+    sigs: Vec<&'a DnsRecord>,
+}
+
+fn rrsets(records: &[DnsRecord]) -> Vec<RrSet<'_>> {
+    let mut sets: Vec<RrSet> = Vec::new();
+    for rec in records {
+        let (owner, rtype) = match *rec {
+            DnsRecord::RRSIG { .. } | DnsRecord::OPT { .. } => continue,
+            _ => (rec.get_domain().to_lowercase(), rec.get_querytype()),
+        };
+        match sets
+            .iter_mut()
+            .find(|set| set.owner == owner && set.rtype == rtype)
+        {
+            Some(set) => set.records.push(rec),
+            None => sets.push(RrSet {
+                owner,
+                rtype,
+                records: vec![rec],
+                sigs: Vec::new(),
+            }),
+        }
+    }
+
+    for rec in records {
+        if let DnsRecord::RRSIG { type_covered, .. } = *rec {
+            let owner = rec.get_domain().to_lowercase();
+            if let Some(set) = sets
+                .iter_mut()
+                .find(|set| set.owner == owner && set.rtype == type_covered)
+            {
+                set.sigs.push(rec);
+            }
+        }
+    }
+
+    sets
+}
+
+// How long what we learned from some records holds: the least of their TTLs, with negative
+// answers only lasting as long as their SOA allows (RFC 2308 section 5)
+fn min_ttl<'a, I>(records: I) -> u32
+where
+    I: IntoIterator<Item = &'a DnsRecord>,
+{
+    records
+        .into_iter()
+        .map(|rec| match *rec {
+            DnsRecord::SOA { ttl, minimum, .. } => cmp::min(ttl, minimum),
+            _ => rec.get_ttl(),
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+// What the DS lookup for a name says about it
+#[derive(Clone)]
+enum Cut {
+    // A signed zone starts here, with these keys
+    Secure(Vec<DnsRecord>),
+    // An unsigned zone starts here, so nothing below can be validated
+    Insecure,
+    // The name is inside its parent's zone
+    Within,
+}
+
+// NSEC and NSEC3 records from a response whose signatures have been checked, for proving that
+// names or types don't exist
+struct Denial<'a> {
+    zone: String,
+    nsecs: Vec<&'a DnsRecord>,
+    nsec3s: Vec<&'a DnsRecord>,
+}
+
+impl<'a> Denial<'a> {
+    fn nsec_matching(&self, name: &str) -> Option<&'a [QueryType]> {
+        self.nsecs.iter().find_map(|rec| match **rec {
+            DnsRecord::NSEC {
+                ref domain,
+                ref types,
+                ..
+            } if domain.eq_ignore_ascii_case(name) => Some(&types[..]),
+            _ => None,
+        })
+    }
+
+    // The owner and next name of the NSEC whose span covers the name
+    fn nsec_covering(&self, name: &str) -> Option<(&'a str, &'a str)> {
+        self.nsecs.iter().find_map(|rec| match **rec {
+            DnsRecord::NSEC {
+                ref domain,
+                ref next_domain,
+                ..
+            } => {
+                let (owner, next) = (domain.as_str(), next_domain.as_str());
+                let cmp = |a: &str, b: &str| canonical_cmp(a, b) == Ordering::Less;
+                let covers = if cmp(owner, next) {
+                    cmp(owner, name) && cmp(name, next)
+                } else {
+                    cmp(owner, name) || cmp(name, next)
+                };
+                if covers {
+                    Some((owner, next))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+    }
+
+    // The closest encloser of a name covered by an NSEC is the longest ancestor it shares with
+    // either end of the span
+    fn nsec_closest_encloser(name: &str, owner: &str, next: &str) -> String {
+        let shared = |other: &str| {
+            (0..=labels(name).len())
+                .rev()
+                .find(|&count| is_subdomain(other, &ancestor(name, count)))
+                .unwrap_or(0)
+        };
+
+        ancestor(name, cmp::max(shared(owner), shared(next)))
+    }
+
+    // The salt and iterations to hash names with, or None if the proof can't be checked
+    fn nsec3_params(&self) -> Option<(&'a [u8], u16)> {
+        self.nsec3s.iter().find_map(|rec| match **rec {
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                iterations,
+                ref salt,
+                ..
+            } if hash_algorithm == NSEC3_SHA1 && iterations <= MAX_NSEC3_ITERATIONS => {
+                Some((&salt[..], iterations))
+            }
+            _ => None,
+        })
+    }
+
+    // The hash of an NSEC3 record's owner is its first label
+    fn nsec3_owner_hash(&self, domain: &str) -> Option<String> {
+        let domain_labels = labels(domain);
+        if domain_labels.len() == labels(&self.zone).len() + 1 && is_subdomain(domain, &self.zone) {
+            Some(domain_labels[0].to_ascii_uppercase())
+        } else {
+            None
+        }
+    }
+
+    // The flags and types of the NSEC3 matching a hash
+    fn nsec3_matching(&self, hash: &str) -> Option<(u8, &'a [QueryType])> {
+        self.nsec3s.iter().find_map(|rec| match **rec {
+            DnsRecord::NSEC3 {
+                ref domain,
+                flags,
+                ref types,
+                ..
+            } if self.nsec3_owner_hash(domain).as_deref() == Some(hash) => {
+                Some((flags, &types[..]))
+            }
+            _ => None,
+        })
+    }
+
+    // The flags of the NSEC3 whose span covers a hash
+    fn nsec3_covering(&self, hash: &str) -> Option<u8> {
+        self.nsec3s.iter().find_map(|rec| match **rec {
+            DnsRecord::NSEC3 {
+                ref domain,
+                flags,
+                ref next_hashed_owner,
+                ..
+            } => {
+                let owner = self.nsec3_owner_hash(domain)?;
+                let next = to_base32hex(next_hashed_owner);
+                if in_span(owner.as_str(), next.as_str(), hash) {
+                    Some(flags)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+    }
+
+    // Find the closest encloser of a name which doesn't exist, and the flags of the NSEC3
+    // covering the next closer name (RFC 5155 section 8.3)
+    fn nsec3_closest_encloser<H: Fn(&str) -> String>(
+        &self,
+        name: &str,
+        hash: H,
+    ) -> Result<(String, u8)> {
+        for count in (labels(&self.zone).len()..labels(name).len()).rev() {
+            let encloser = ancestor(name, count);
+            if self.nsec3_matching(&hash(&encloser)).is_none() {
+                continue;
+            }
+
+            let next_closer = ancestor(name, count + 1);
+            return match self.nsec3_covering(&hash(&next_closer)) {
+                Some(flags) => Ok((encloser, flags)),
+                None => Err(bogus(format!("No NSEC3 covers {0}", fqdn(&next_closer)))),
+            };
+        }
+
+        Err(bogus(format!(
+            "No closest encloser proof for {0}",
+            fqdn(name)
+        )))
+    }
+
+    fn no_records(&self) -> DnsError {
+        bogus(format!(
+            "No NSEC or NSEC3 records from {0}",
+            fqdn(&self.zone)
+        ))
+    }
+
+    // Prove a name doesn't exist, and that no wildcard could have stood in for it. Returns false
+    // if the proof only shows the name is in an insecure part of the zone.
+    fn prove_nxdomain(&self, name: &str) -> Result<bool> {
+        let no_wildcard = || bogus(format!("No proof of no wildcard for {0}", fqdn(name)));
+
+        if !self.nsecs.is_empty() {
+            let (owner, next) = self
+                .nsec_covering(name)
+                .ok_or_else(|| bogus(format!("No NSEC covers {0}", fqdn(name))))?;
+            let wildcard = wildcard_of(&Denial::nsec_closest_encloser(name, owner, next));
+            if self.nsec_matching(&wildcard).is_some() || self.nsec_covering(&wildcard).is_none() {
+                return Err(no_wildcard());
+            }
+
+            return Ok(true);
+        }
+
+        if self.nsec3s.is_empty() {
+            return Err(self.no_records());
+        }
+        let (salt, iterations) = match self.nsec3_params() {
+            Some(params) => params,
+            None => return Ok(false),
+        };
+        let hash = |name: &str| nsec3_hash(name, salt, iterations);
+
+        let (encloser, flags) = self.nsec3_closest_encloser(name, hash)?;
+        // An opted out span may hide an unsigned delegation, which would have the name
+        if flags & NSEC3_OPT_OUT != 0 {
+            return Ok(false);
+        }
+        if self
+            .nsec3_covering(&hash(&wildcard_of(&encloser)))
+            .is_none()
+        {
+            return Err(no_wildcard());
+        }
+
+        Ok(true)
+    }
+
+    // Prove a name has no records of a type (RFC 4035 section 5.4, RFC 5155 section 8.5-8.7)
+    fn prove_nodata(&self, name: &str, qtype: QueryType) -> Result<bool> {
+        let lacks =
+            |types: &[QueryType]| !types.contains(&qtype) && !types.contains(&QueryType::CNAME);
+        let no_proof = || {
+            bogus(format!(
+                "No proof {0} has no {1} records",
+                fqdn(name),
+                qtype
+            ))
+        };
+
+        if !self.nsecs.is_empty() {
+            if let Some(types) = self.nsec_matching(name) {
+                return if lacks(types) {
+                    Ok(true)
+                } else {
+                    Err(no_proof())
+                };
+            }
+
+            if let Some((owner, next)) = self.nsec_covering(name) {
+                // An empty non-terminal, which only exists as the parent of other names
+                if is_subdomain(next, name) {
+                    return Ok(true);
+                }
+
+                // A wildcard matched the name, but has no records of the type either
+                let wildcard = wildcard_of(&Denial::nsec_closest_encloser(name, owner, next));
+                if self.nsec_matching(&wildcard).is_some_and(lacks) {
+                    return Ok(true);
+                }
+            }
+
+            return Err(no_proof());
+        }
+
+        if self.nsec3s.is_empty() {
+            return Err(self.no_records());
+        }
+        let (salt, iterations) = match self.nsec3_params() {
+            Some(params) => params,
+            None => return Ok(false),
+        };
+        let hash = |name: &str| nsec3_hash(name, salt, iterations);
+
+        if let Some((_, types)) = self.nsec3_matching(&hash(name)) {
+            return if lacks(types) {
+                Ok(true)
+            } else {
+                Err(no_proof())
+            };
+        }
+
+        let (encloser, flags) = self.nsec3_closest_encloser(name, hash)?;
+        // A DS missing from an opted out span belongs to an unsigned delegation
+        if qtype == QueryType::DS && flags & NSEC3_OPT_OUT != 0 {
+            return Ok(false);
+        }
+        match self.nsec3_matching(&hash(&wildcard_of(&encloser))) {
+            Some((_, types)) if lacks(types) => Ok(true),
+            _ => Err(no_proof()),
+        }
+    }
+
+    // Work out from the proof that a name has no DS records whether it's an unsigned delegation
+    fn prove_no_ds(&self, name: &str) -> Result<Cut> {
+        let cut = |types: &[QueryType]| {
+            if types.contains(&QueryType::DS) {
+                Err(bogus(format!("DS records for {0} were denied", fqdn(name))))
+            } else if types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA) {
+                Ok(Cut::Insecure)
+            } else {
+                Ok(Cut::Within)
+            }
+        };
+        let no_proof = || bogus(format!("No proof {0} has no DS records", fqdn(name)));
+
+        if !self.nsecs.is_empty() {
+            if let Some(types) = self.nsec_matching(name) {
+                return cut(types);
+            }
+            match self.nsec_covering(name) {
+                Some((_, next)) if is_subdomain(next, name) => return Ok(Cut::Within),
+                _ => return Err(no_proof()),
+            }
+        }
+
+        if self.nsec3s.is_empty() {
+            return Err(self.no_records());
+        }
+        let (salt, iterations) = match self.nsec3_params() {
+            Some(params) => params,
+            None => return Ok(Cut::Insecure),
+        };
+        let hash = |name: &str| nsec3_hash(name, salt, iterations);
+
+        if let Some((_, types)) = self.nsec3_matching(&hash(name)) {
+            return cut(types);
+        }
+        match self.nsec3_closest_encloser(name, hash)? {
+            (_, flags) if flags & NSEC3_OPT_OUT != 0 => Ok(Cut::Insecure),
+            _ => Err(no_proof()),
+        }
+    }
+
+    // Prove a name answered from a wildcard doesn't exist itself (RFC 4035 section 5.3.4,
+    // RFC 5155 section 8.8)
+    fn prove_wildcard(&self, name: &str, encloser: &str) -> Result<bool> {
+        let no_proof = || {
+            bogus(format!(
+                "No proof {0} doesn't exist beside its wildcard",
+                fqdn(name)
+            ))
+        };
+
+        if !self.nsecs.is_empty() {
+            return match self.nsec_covering(name) {
+                Some(_) => Ok(true),
+                None => Err(no_proof()),
+            };
+        }
+
+        if self.nsec3s.is_empty() {
+            return Err(self.no_records());
+        }
+        let (salt, iterations) = match self.nsec3_params() {
+            Some(params) => params,
+            None => return Ok(false),
+        };
+
+        let next_closer = ancestor(name, labels(encloser).len() + 1);
+        match self.nsec3_covering(&nsec3_hash(&next_closer, salt, iterations)) {
+            Some(_) => Ok(true),
+            None => Err(no_proof()),
+        }
+    }
+}
+
+// Zone cuts and validated keys, kept between queries until the records proving them expire,
+// so each query needn't walk the chain of trust and check its signatures all over again
+pub struct KeyCache {
+    cuts: RwLock<ExpiringMap<String, Cut>>,
+    max_entries: usize,
+}
+
+impl KeyCache {
+    pub fn new(max_entries: usize) -> KeyCache {
+        KeyCache {
+            cuts: RwLock::new(ExpiringMap::new()),
+            max_entries,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Cut> {
+        self.cuts
+            .read()
+            .expect("Failed to acquire key cache lock")
+            .get_live(name, Instant::now())
+            .cloned()
+    }
+
+    fn insert(&self, name: &str, cut: Cut, ttl: u32) {
+        if ttl == 0 || self.max_entries == 0 {
+            return;
+        }
+
+        let expires = Instant::now() + Duration::from_secs(ttl as u64);
+        self.cuts
+            .write()
+            .expect("Failed to acquire key cache lock")
+            .insert(name.to_string(), cut, expires, self.max_entries);
+    }
+}
+
+// Validates responses with DNSSEC, building the chain of trust down from the trust anchors with
+// records looked up through `fetch`
+pub struct Validator<'a, F> {
+    anchors: &'a [DnsRecord],
+    key_cache: &'a KeyCache,
+    fetch: F,
+    now: u32,
+    // What we've learned about each name looked up for DS records so far
+    cuts: HashMap<String, Cut>,
+}
+
+impl<'a, F> Validator<'a, F>
+where
+    F: Fn(&str, QueryType) -> Result<DnsPacket>,
+{
+    pub fn new(anchors: &'a [DnsRecord], key_cache: &'a KeyCache, fetch: F) -> Validator<'a, F> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as u32)
+            .unwrap_or(0);
+
+        Validator {
+            anchors,
+            key_cache,
+            fetch,
+            now,
+            cuts: HashMap::new(),
+        }
+    }
+
+    // Validate a response to a question. Returns true if it is secure, false if it comes from
+    // an unsigned zone, and a Bogus error if it should be signed but the signatures don't check out.
+    pub fn validate(&mut self, qname: &str, qtype: QueryType, packet: &DnsPacket) -> Result<bool> {
+        // Failures such as SERVFAIL have nothing to validate
+        if packet.header.rescode != ResponseCode::NOERROR
+            && packet.header.rescode != ResponseCode::NXDOMAIN
+        {
+            return Ok(false);
+        }
+
+        let mut secure = true;
+
+        // Each RRset may come from a different zone, as with the links of a CNAME chain
+        for rrset in rrsets(&packet.answers) {
+            let (zone, keys) = match self.zone_keys(&rrset.owner)? {
+                Some(zone_keys) => zone_keys,
+                None => {
+                    secure = false;
+                    continue;
+                }
+            };
+
+            // An answer expanded from a wildcard also needs proof the name itself doesn't exist
+            if let Some(encloser) = self.verify_rrset(&rrset, &zone, &keys)? {
+                let denial = self.verified_denial(&packet.authorities, &zone, &keys)?;
+                secure &= denial.prove_wildcard(&rrset.owner, &encloser)?;
+            }
+        }
+
+        // The AD bit vouches for the authority section too (RFC 4035 section 3.2.3), so NS records
+        // sent along with an answer must be signed by their zone. Ones which aren't are passed on,
+        // but the answer isn't marked secure.
+        for rrset in rrsets(&packet.authorities) {
+            if rrset.rtype != QueryType::NS {
+                continue;
+            }
+            secure &= match self.zone_keys(&rrset.owner)? {
+                Some((zone, keys)) => {
+                    zone == rrset.owner && self.verify_rrset(&rrset, &zone, &keys).is_ok()
+                }
+                None => false,
+            };
+        }
+
+        // Follow any CNAMEs to the name which should hold the answer
+        let mut name = qname.to_lowercase();
+        if qtype != QueryType::CNAME {
+            for _ in 0..packet.answers.len() {
+                let host = packet.answers.iter().find_map(|rec| match *rec {
+                    DnsRecord::CNAME {
+                        ref domain,
+                        ref host,
+                        ..
+                    } if domain.eq_ignore_ascii_case(&name) => Some(host.to_lowercase()),
+                    _ => None,
+                });
+                match host {
+                    Some(host) => name = host,
+                    None => break,
+                }
+            }
+        }
+
+        // Anything short of an answer needs a signed proof of non-existence
+        let answered = packet.answers.iter().any(|rec| {
+            rec.get_domain().eq_ignore_ascii_case(&name)
+                && (rec.get_querytype() == qtype || qtype == QueryType::UNKNOWN(255))
+        });
+        if !answered {
+            secure &= match self.zone_keys(&name)? {
+                Some((zone, keys)) => {
+                    let denial = self.verified_denial(&packet.authorities, &zone, &keys)?;
+                    if packet.header.rescode == ResponseCode::NXDOMAIN {
+                        denial.prove_nxdomain(&name)?
+                    } else {
+                        denial.prove_nodata(&name, qtype)?
+                    }
+                }
+                None => false,
+            };
+        }
+
+        Ok(secure)
+    }
+
+    // Find the zone a name belongs to and its validated keys, following DS records down from the
+    // closest trust anchor. None means the name is in an unsigned zone.
+    fn zone_keys(&mut self, name: &str) -> Result<Option<(String, Vec<DnsRecord>)>> {
+        let name = name.to_lowercase();
+        let anchor = self
+            .anchors
+            .iter()
+            .map(|rec| rec.get_domain().to_lowercase())
+            .filter(|zone| is_subdomain(&name, zone))
+            .max_by_key(|zone| labels(zone).len());
+        let mut zone = match anchor {
+            Some(zone) => zone,
+            None => return Ok(None),
+        };
+
+        let mut keys = match self.anchor_keys(&zone)? {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+
+        for count in labels(&zone).len() + 1..=labels(&name).len() {
+            let child = ancestor(&name, count);
+            if !self.cuts.contains_key(&child) {
+                let cut = match self.key_cache.get(&child) {
+                    Some(cut) => cut,
+                    None => match self.find_cut(&child, &zone, &keys)? {
+                        Some((cut, ttl)) => {
+                            self.key_cache.insert(&child, cut.clone(), ttl);
+                            cut
+                        }
+                        // The name doesn't exist, so neither does anything below it
+                        None => break,
+                    },
+                };
+                self.cuts.insert(child.clone(), cut);
+            }
+
+            match self.cuts[&child] {
+                Cut::Secure(ref child_keys) => {
+                    zone = child;
+                    keys = child_keys.clone();
+                }
+                Cut::Insecure => return Ok(None),
+                Cut::Within => {}
+            }
+        }
+
+        Ok(Some((zone, keys)))
+    }
+
+    // Keys for a zone with a trust anchor, whether DS records or the keys themselves
+    fn anchor_keys(&mut self, zone: &str) -> Result<Option<Vec<DnsRecord>>> {
+        if let Some(cut) = self.cuts.get(zone) {
+            return Ok(match *cut {
+                Cut::Secure(ref keys) => Some(keys.clone()),
+                _ => None,
+            });
+        }
+        if let Some(cut) = self.key_cache.get(zone) {
+            self.cuts.insert(zone.to_string(), cut.clone());
+            return Ok(match cut {
+                Cut::Secure(keys) => Some(keys),
+                _ => None,
+            });
+        }
+
+        let ds = self
+            .anchors
+            .iter()
+            .filter(|rec| {
+                rec.get_querytype() == QueryType::DS && rec.get_domain().eq_ignore_ascii_case(zone)
+            })
+            .cloned()
+            .collect::<Vec<DnsRecord>>();
+        let trusted = self
+            .anchors
+            .iter()
+            .filter(|rec| {
+                rec.get_querytype() == QueryType::DNSKEY
+                    && rec.get_domain().eq_ignore_ascii_case(zone)
+            })
+            .cloned()
+            .collect::<Vec<DnsRecord>>();
+
+        let keys = self.prove_keys(zone, &ds, &trusted)?;
+        let cut = match keys {
+            Some(ref keys) => {
+                self.key_cache
+                    .insert(zone, Cut::Secure(keys.clone()), min_ttl(keys));
+                Cut::Secure(keys.clone())
+            }
+            None => Cut::Insecure,
+        };
+        self.cuts.insert(zone.to_string(), cut);
+
+        Ok(keys)
+    }
+
+    // Look up the DS records for a name within a signed zone, to find whether it starts a
+    // zone of its own, and for how long that holds. None means the name doesn't exist.
+    fn find_cut(
+        &mut self,
+        name: &str,
+        zone: &str,
+        keys: &[DnsRecord],
+    ) -> Result<Option<(Cut, u32)>> {
+        let response = (self.fetch)(name, QueryType::DS)?;
+
+        for rrset in rrsets(&response.answers) {
+            if rrset.owner != name {
+                continue;
+            }
+            match rrset.rtype {
+                QueryType::DS => {
+                    self.verify_rrset(&rrset, zone, keys)?;
+                    let ds = rrset
+                        .records
+                        .into_iter()
+                        .cloned()
+                        .collect::<Vec<DnsRecord>>();
+
+                    return Ok(Some(match self.prove_keys(name, &ds, &[])? {
+                        Some(keys) => {
+                            let ttl = cmp::min(min_ttl(&ds), min_ttl(&keys));
+                            (Cut::Secure(keys), ttl)
+                        }
+                        None => (Cut::Insecure, min_ttl(&ds)),
+                    }));
+                }
+                // An alias can't also be a zone cut
+                QueryType::CNAME => {
+                    self.verify_rrset(&rrset, zone, keys)?;
+                    return Ok(Some((Cut::Within, min_ttl(rrset.records))));
+                }
+                _ => {}
+            }
+        }
+
+        match response.header.rescode {
+            ResponseCode::NXDOMAIN => Ok(None),
+            ResponseCode::NOERROR => {
+                let denial = self.verified_denial(&response.authorities, zone, keys)?;
+                let cut = denial.prove_no_ds(name)?;
+                Ok(Some((cut, min_ttl(&response.authorities))))
+            }
+            rescode => Err(bogus(format!(
+                "Looking up DS records for {0} failed with {1:?}",
+                fqdn(name),
+                rescode
+            ))),
+        }
+    }
+
+    // Fetch a zone's keys and check them against its DS records or trusted keys. None means
+    // none of those use algorithms we support, so the zone is treated as unsigned
+    // (RFC 4035 section 5.2).
+    fn prove_keys(
+        &self,
+        zone: &str,
+        ds: &[DnsRecord],
+        trusted: &[DnsRecord],
+    ) -> Result<Option<Vec<DnsRecord>>> {
+        let ds = ds
+            .iter()
+            .filter(|rec| match **rec {
+                DnsRecord::DS {
+                    algorithm,
+                    digest_type,
+                    ..
+                } => is_supported_algorithm(algorithm) && ds_digest(digest_type).is_some(),
+                _ => false,
+            })
+            .collect::<Vec<&DnsRecord>>();
+        let trusted = trusted
+            .iter()
+            .filter(|key| is_usable_key(key))
+            .collect::<Vec<&DnsRecord>>();
+        if ds.is_empty() && trusted.is_empty() {
+            return Ok(None);
+        }
+
+        let response = (self.fetch)(zone, QueryType::DNSKEY)?;
+        let dnskeys = rrsets(&response.answers)
+            .into_iter()
+            .find(|set| set.rtype == QueryType::DNSKEY && set.owner == zone)
+            .ok_or_else(|| bogus(format!("No DNSKEY records for {0}", fqdn(zone))))?;
+
+        // The key set must be signed by a key the parent zone or a trust anchor vouches for
+        let entry_keys = dnskeys
+            .records
+            .iter()
+            .filter(|key| {
+                is_usable_key(key)
+                    && (ds.iter().any(|rec| ds_matches(zone, rec, key))
+                        || trusted.iter().any(|rec| same_key(rec, key)))
+            })
+            .map(|&key| key.clone())
+            .collect::<Vec<DnsRecord>>();
+        if entry_keys.is_empty() {
+            return Err(bogus(format!(
+                "No DNSKEY for {0} matches its DS records",
+                fqdn(zone)
+            )));
+        }
+        self.verify_rrset(&dnskeys, zone, &entry_keys)?;
+
+        Ok(Some(
+            dnskeys
+                .records
+                .into_iter()
+                .filter(|key| is_usable_key(key))
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    // Check an RRset is signed by one of a zone's keys. If it was expanded from a wildcard,
+    // returns the wildcard's parent name, the closest encloser.
+    fn verify_rrset(
+        &self,
+        rrset: &RrSet,
+        zone: &str,
+        keys: &[DnsRecord],
+    ) -> Result<Option<String>> {
+        // A literal wildcard owner's asterisk isn't counted (RFC 4034 section 3.1.3)
+        let owner_labels = labels(&rrset.owner);
+        let count = if owner_labels.first() == Some(&"*") {
+            owner_labels.len() - 1
+        } else {
+            owner_labels.len()
+        };
+
+        for sig in &rrset.sigs {
+            if let DnsRecord::RRSIG {
+                algorithm,
+                labels: sig_labels,
+                expiration,
+                inception,
+                key_tag: tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } = **sig
+            {
+                if !signer_name.eq_ignore_ascii_case(zone)
+                    || !in_validity_period(self.now, inception, expiration)
+                    || sig_labels as usize > count
+                {
+                    continue;
+                }
+
+                // Fewer labels than the owner means the records were expanded from a wildcard
+                let (signed_owner, encloser) = if (sig_labels as usize) < count {
+                    let encloser = ancestor(&rrset.owner, sig_labels as usize);
+                    (wildcard_of(&encloser), Some(encloser))
+                } else {
+                    (rrset.owner.clone(), None)
+                };
+                let data = signed_data(sig, &signed_owner, &rrset.records)?;
+
+                let verified = keys.iter().any(|key| match *key {
+                    DnsRecord::DNSKEY {
+                        algorithm: key_algorithm,
+                        ref public_key,
+                        ..
+                    } => {
+                        key_algorithm == algorithm
+                            && key_tag(&dnskey_rdata(key)) == tag
+                            && verify_signature(algorithm, public_key, &data, signature)
+                    }
+                    _ => false,
+                });
+                if verified {
+                    return Ok(encloser);
+                }
+            }
+        }
+
+        Err(bogus(format!(
+            "No valid signature from {0} for {1} {2}",
+            fqdn(zone),
+            fqdn(&rrset.owner),
+            rrset.rtype
+        )))
+    }
+
+    // Check the signatures on the SOA, NSEC and NSEC3 records a zone sent with a response
+    fn verified_denial<'b>(
+        &self,
+        records: &'b [DnsRecord],
+        zone: &str,
+        keys: &[DnsRecord],
+    ) -> Result<Denial<'b>> {
+        let mut denial = Denial {
+            zone: zone.to_string(),
+            nsecs: Vec::new(),
+            nsec3s: Vec::new(),
+        };
+
+        for rrset in rrsets(records) {
+            if !is_subdomain(&rrset.owner, zone) {
+                continue;
+            }
+            match rrset.rtype {
+                QueryType::SOA => {}
+                QueryType::NSEC => denial.nsecs.extend(&rrset.records),
+                QueryType::NSEC3 => denial.nsec3s.extend(&rrset.records),
+                _ => continue,
+            }
+            self.verify_rrset(&rrset, zone, keys)?;
+        }
+
+        Ok(denial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+
+    // The clock the validator runs at, so signature validity doesn't depend on the date
+    const NOW: u32 = 1_700_000_000;
+    const VALID: (u32, u32) = (NOW - 3600, NOW + 3600);
+    const EXPIRED: (u32, u32) = (NOW - 7200, NOW - 3600);
+    const NOT_YET_VALID: (u32, u32) = (NOW + 3600, NOW + 7200);
+
+    enum Key {
+        Ed25519(Ed25519KeyPair),
+        Ecdsa(EcdsaKeyPair, u8),
+        Rsa(RsaKeyPair),
+    }
+
+    impl Key {
+        fn ed25519(seed: u8) -> Key {
+            Key::Ed25519(Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap())
+        }
+
+        fn ecdsa(algorithm: u8, pkcs8: &[u8]) -> Key {
+            let signing = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                _ => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+            };
+            let key = EcdsaKeyPair::from_pkcs8(signing, pkcs8, &SystemRandom::new()).unwrap();
+            Key::Ecdsa(key, algorithm)
+        }
+
+        fn rsa(der: &[u8]) -> Key {
+            Key::Rsa(RsaKeyPair::from_der(der).unwrap())
+        }
+
+        fn algorithm(&self) -> u8 {
+            match *self {
+                Key::Ed25519(_) => 15,
+                Key::Ecdsa(_, algorithm) => algorithm,
+                Key::Rsa(_) => 8,
+            }
+        }
+
+        // The public key as DNSKEY RDATA holds it (RFC 3110, RFC 6605, RFC 8080)
+        fn public_key(&self) -> Vec<u8> {
+            match *self {
+                Key::Ed25519(ref key) => key.public_key().as_ref().to_vec(),
+                // Without the uncompressed point prefix
+                Key::Ecdsa(ref key, _) => key.public_key().as_ref()[1..].to_vec(),
+                Key::Rsa(ref key) => {
+                    let components: signature::RsaPublicKeyComponents<Vec<u8>> =
+                        key.public().into();
+                    let mut public_key = vec![components.e.len() as u8];
+                    public_key.extend(&components.e);
+                    public_key.extend(&components.n);
+                    public_key
+                }
+            }
+        }
+
+        fn sign(&self, data: &[u8]) -> Vec<u8> {
+            let rng = SystemRandom::new();
+            match *self {
+                Key::Ed25519(ref key) => key.sign(data).as_ref().to_vec(),
+                Key::Ecdsa(ref key, _) => key.sign(&rng, data).unwrap().as_ref().to_vec(),
+                Key::Rsa(ref key) => {
+                    let mut sig = vec![0; key.public().modulus_len()];
+                    key.sign(&signature::RSA_PKCS1_SHA256, &rng, data, &mut sig)
+                        .unwrap();
+                    sig
+                }
+            }
+        }
+
+        fn dnskey(&self, zone: &str) -> DnsRecord {
+            DnsRecord::DNSKEY {
+                domain: zone.to_string(),
+                flags: FLAG_ZONE_KEY | 0x0001,
+                protocol: DNSKEY_PROTOCOL,
+                algorithm: self.algorithm(),
+                public_key: self.public_key(),
+                ttl: 3600,
+            }
+        }
+
+        fn ds(&self, zone: &str) -> DnsRecord {
+            let rdata = dnskey_rdata(&self.dnskey(zone));
+            let mut data = wire_name(zone);
+            data.extend(&rdata);
+
+            DnsRecord::DS {
+                domain: zone.to_string(),
+                key_tag: key_tag(&rdata),
+                algorithm: self.algorithm(),
+                digest_type: 2,
+                digest: digest::digest(&digest::SHA256, &data).as_ref().to_vec(),
+                ttl: 3600,
+            }
+        }
+    }
+
+    #[derive(PartialEq)]
+    enum Denial {
+        Nsec,
+        Nsec3,
+        // NSEC3 leaving out unsigned delegations (RFC 5155 section 6)
+        Nsec3OptOut,
+    }
+
+    // A signed zone, which answers queries like an authoritative server would
+    struct Zone {
+        name: String,
+        key: Key,
+        records: Vec<DnsRecord>,
+        denial: Denial,
+        // Inception and expiration of its signatures
+        validity: (u32, u32),
+    }
+
+    impl Zone {
+        fn new(name: &str, key: Key, records: Vec<DnsRecord>, denial: Denial) -> Zone {
+            Zone {
+                name: name.to_string(),
+                key,
+                records,
+                denial,
+                validity: VALID,
+            }
+        }
+
+        // Sign an RRset, which may have been expanded from a wildcard at `signed_owner`
+        fn sign(&self, records: &[&DnsRecord], signed_owner: &str, owner: &str) -> DnsRecord {
+            let mut count = labels(signed_owner).len();
+            if signed_owner.starts_with("*.") {
+                count -= 1;
+            }
+            let mut sig = DnsRecord::RRSIG {
+                domain: owner.to_string(),
+                type_covered: records[0].get_querytype(),
+                algorithm: self.key.algorithm(),
+                labels: count as u8,
+                original_ttl: records[0].get_ttl(),
+                expiration: self.validity.1,
+                inception: self.validity.0,
+                key_tag: key_tag(&dnskey_rdata(&self.key.dnskey(&self.name))),
+                signer_name: self.name.clone(),
+                signature: Vec::new(),
+                ttl: records[0].get_ttl(),
+            };
+
+            let data = signed_data(&sig, signed_owner, records).unwrap();
+            if let DnsRecord::RRSIG {
+                ref mut signature, ..
+            } = sig
+            {
+                *signature = self.key.sign(&data);
+            }
+            sig
+        }
+
+        fn all(&self) -> Vec<DnsRecord> {
+            let mut records = self.records.clone();
+            records.push(self.key.dnskey(&self.name));
+            records
+        }
+
+        fn types_at(&self, name: &str) -> Vec<QueryType> {
+            let mut types = self
+                .all()
+                .iter()
+                .filter(|rec| rec.get_domain() == name)
+                .map(|rec| rec.get_querytype())
+                .collect::<Vec<QueryType>>();
+            types.push(QueryType::RRSIG);
+            types.push(match self.denial {
+                Denial::Nsec => QueryType::NSEC,
+                _ => QueryType::NSEC3,
+            });
+            types.sort();
+            types.dedup();
+            types
+        }
+
+        // Names with records in the denial chain, which opt-out leaves unsigned delegations out of
+        fn owners(&self) -> Vec<String> {
+            let mut owners = self
+                .all()
+                .iter()
+                .map(|rec| rec.get_domain().to_string())
+                .filter(|owner| {
+                    let types = self.types_at(owner);
+                    self.denial != Denial::Nsec3OptOut
+                        || !types.contains(&QueryType::NS)
+                        || types.contains(&QueryType::DS)
+                        || *owner == self.name
+                })
+                .collect::<Vec<String>>();
+            owners.sort_by(|a, b| canonical_cmp(a, b));
+            owners.dedup();
+            owners
+        }
+
+        fn chain(&self) -> Vec<DnsRecord> {
+            let owners = self.owners();
+            let mut chain = Vec::new();
+
+            if self.denial == Denial::Nsec {
+                for (i, owner) in owners.iter().enumerate() {
+                    let nsec = DnsRecord::NSEC {
+                        domain: owner.clone(),
+                        next_domain: owners[(i + 1) % owners.len()].clone(),
+                        types: self.types_at(owner),
+                        ttl: 300,
+                    };
+                    chain.push(self.sign(&[&nsec], owner, owner));
+                    chain.push(nsec);
+                }
+                return chain;
+            }
+
+            let mut hashes = owners
+                .iter()
+                .map(|owner| (nsec3_hash(owner, &[0xab], 2), owner))
+                .collect::<Vec<(String, &String)>>();
+            hashes.sort();
+            for (i, &(ref hash, owner)) in hashes.iter().enumerate() {
+                let domain = format!("{0}.{1}", hash.to_lowercase(), self.name);
+                let nsec3 = DnsRecord::NSEC3 {
+                    domain: domain.clone(),
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: match self.denial {
+                        Denial::Nsec3OptOut => NSEC3_OPT_OUT,
+                        _ => 0,
+                    },
+                    iterations: 2,
+                    salt: vec![0xab],
+                    next_hashed_owner: from_base32hex(&hashes[(i + 1) % hashes.len()].0),
+                    types: self.types_at(owner),
+                    ttl: 300,
+                };
+                chain.push(self.sign(&[&nsec3], &domain, &domain));
+                chain.push(nsec3);
+            }
+            chain
+        }
+
+        fn respond(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+            let mut packet = DnsPacket::new();
+            let all = self.all();
+
+            let matching = all
+                .iter()
+                .filter(|rec| rec.get_domain() == qname && rec.get_querytype() == qtype)
+                .collect::<Vec<&DnsRecord>>();
+            let alias = all
+                .iter()
+                .filter(|rec| rec.get_domain() == qname && rec.get_querytype() == QueryType::CNAME)
+                .collect::<Vec<&DnsRecord>>();
+            let matching = if matching.is_empty() { alias } else { matching };
+            if !matching.is_empty() {
+                packet.answers.push(self.sign(&matching, qname, qname));
+                packet.answers.extend(matching.into_iter().cloned());
+                return packet;
+            }
+
+            let exists = all.iter().any(|rec| is_subdomain(rec.get_domain(), qname));
+            let wildcard = wildcard_of(&ancestor(qname, labels(qname).len() - 1));
+            let expanded = all
+                .iter()
+                .filter(|rec| rec.get_domain() == wildcard && rec.get_querytype() == qtype)
+                .collect::<Vec<&DnsRecord>>();
+            if !exists && !expanded.is_empty() {
+                packet.answers.push(self.sign(&expanded, &wildcard, qname));
+                for rec in expanded {
+                    if let DnsRecord::A { addr, ttl, .. } = *rec {
+                        packet.answers.push(a(qname, addr, ttl));
+                    }
+                }
+            } else if !exists {
+                packet.header.rescode = ResponseCode::NXDOMAIN;
+            }
+            packet.authorities = self.chain();
+            packet
+        }
+    }
+
+    fn from_base32hex(text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let (mut bits, mut value) = (0, 0u32);
+        for c in text.chars() {
+            value = (value << 5) | c.to_digit(32).unwrap();
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((value >> bits) as u8);
+                value &= (1 << bits) - 1;
+            }
+        }
+        bytes
+    }
+
+    fn a(domain: &str, addr: std::net::Ipv4Addr, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr,
+            ttl,
+        }
+    }
+
+    fn host(domain: &str) -> DnsRecord {
+        a(domain, "192.0.2.1".parse().unwrap(), 60)
+    }
+
+    fn ns(domain: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: "ns.example".to_string(),
+            ttl: 60,
+        }
+    }
+
+    struct Fixture {
+        zones: Vec<Zone>,
+        anchors: Vec<DnsRecord>,
+    }
+
+    // The root and a tree of zones under test, with a mix of algorithms and denial methods:
+    // sub.test uses NSEC3, optout.test NSEC3 with opt-out, and insecure.test and
+    // unsigned.optout.test are signed but have no DS records, so they're insecure.
+    fn fixture(validity: (u32, u32)) -> Fixture {
+        let root = Key::ed25519(1);
+        let test = Key::ecdsa(13, include_bytes!("../../testdata/dnssec/p256.pk8"));
+        let sub = Key::ecdsa(14, include_bytes!("../../testdata/dnssec/p384.pk8"));
+        let rsa = Key::rsa(include_bytes!("../../testdata/dnssec/rsa.der"));
+        let optout = Key::ed25519(2);
+        let anchors = vec![root.ds("")];
+
+        let test_records = vec![
+            host("www.test"),
+            host("*.wild.test"),
+            host("a.b.test"),
+            ns("sub.test"),
+            sub.ds("sub.test"),
+            ns("rsa.test"),
+            rsa.ds("rsa.test"),
+            ns("optout.test"),
+            optout.ds("optout.test"),
+            ns("insecure.test"),
+        ];
+        let sub_records = vec![
+            host("www.sub.test"),
+            DnsRecord::CNAME {
+                domain: "alias.sub.test".to_string(),
+                host: "www.test".to_string(),
+                ttl: 60,
+            },
+        ];
+        let optout_records = vec![host("www.optout.test"), ns("unsigned.optout.test")];
+
+        let mut test = Zone::new("test", test, test_records, Denial::Nsec);
+        test.validity = validity;
+        let zones = vec![
+            Zone::new(
+                "",
+                root,
+                vec![ns("test"), test.key.ds("test")],
+                Denial::Nsec,
+            ),
+            test,
+            Zone::new("sub.test", sub, sub_records, Denial::Nsec3),
+            Zone::new("rsa.test", rsa, vec![host("www.rsa.test")], Denial::Nsec),
+            Zone::new("optout.test", optout, optout_records, Denial::Nsec3OptOut),
+            Zone::new(
+                "unsigned.optout.test",
+                Key::ed25519(3),
+                vec![host("www.unsigned.optout.test")],
+                Denial::Nsec,
+            ),
+            Zone::new(
+                "insecure.test",
+                Key::ed25519(4),
+                vec![host("www.insecure.test")],
+                Denial::Nsec,
+            ),
+        ];
+
+        Fixture { zones, anchors }
+    }
+
+    impl Fixture {
+        // Ask the closest zone, or its parent for DS records at its apex
+        fn fetch(&self, name: &str, qtype: QueryType) -> Result<DnsPacket> {
+            let zone = self
+                .zones
+                .iter()
+                .filter(|zone| {
+                    is_subdomain(name, &zone.name) && !(qtype == QueryType::DS && zone.name == name)
+                })
+                .max_by_key(|zone| labels(&zone.name).len())
+                .unwrap();
+            Ok(zone.respond(name, qtype))
+        }
+
+        // A response as a resolver would put it together, following CNAMEs
+        fn query(&self, name: &str, qtype: QueryType) -> DnsPacket {
+            let mut packet = self.fetch(name, qtype).unwrap();
+            let alias = packet.answers.iter().find_map(|rec| match *rec {
+                DnsRecord::CNAME { ref host, .. } => Some(host.clone()),
+                _ => None,
+            });
+            if let Some(host) = alias {
+                let target = self.fetch(&host, qtype).unwrap();
+                packet.answers.extend(target.answers);
+            }
+            packet
+        }
+
+        fn validate(&self, name: &str, qtype: QueryType, packet: &DnsPacket) -> Result<bool> {
+            self.validate_with(&self.anchors, name, qtype, packet)
+        }
+
+        fn validate_with(
+            &self,
+            anchors: &[DnsRecord],
+            name: &str,
+            qtype: QueryType,
+            packet: &DnsPacket,
+        ) -> Result<bool> {
+            let key_cache = KeyCache::new(0);
+            let mut validator =
+                Validator::new(anchors, &key_cache, |name: &str, qtype: QueryType| {
+                    self.fetch(name, qtype)
+                });
+            validator.now = NOW;
+            validator.validate(name, qtype, packet)
+        }
+
+        fn check(&self, name: &str, qtype: QueryType) -> Result<bool> {
+            self.validate(name, qtype, &self.query(name, qtype))
+        }
+    }
+
+    fn is_bogus(result: Result<bool>) -> bool {
+        matches!(result, Err(DnsError::Bogus(_)))
+    }
+
+    #[test]
+    fn signed_answers_are_secure() {
+        let fixture = fixture(VALID);
+
+        assert!(fixture.check("www.test", QueryType::A).unwrap());
+        assert!(fixture.check("www.sub.test", QueryType::A).unwrap());
+        assert!(fixture.check("www.rsa.test", QueryType::A).unwrap());
+        assert!(fixture.check("www.optout.test", QueryType::A).unwrap());
+        assert!(fixture.check("test", QueryType::DNSKEY).unwrap());
+        // A CNAME in one zone to a name in another
+        assert!(fixture.check("alias.sub.test", QueryType::A).unwrap());
+    }
+
+    #[test]
+    fn delegation_without_ds_is_insecure() {
+        let fixture = fixture(VALID);
+
+        assert!(!fixture.check("www.insecure.test", QueryType::A).unwrap());
+    }
+
+    #[test]
+    fn nsec_proves_nxdomain() {
+        let fixture = fixture(VALID);
+
+        let packet = fixture.query("nope.test", QueryType::A);
+        assert_eq!(packet.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(fixture
+            .validate("nope.test", QueryType::A, &packet)
+            .unwrap());
+    }
+
+    #[test]
+    fn nsec_proves_nodata() {
+        let fixture = fixture(VALID);
+
+        assert!(fixture.check("www.test", QueryType::AAAA).unwrap());
+        // An empty non-terminal, which only exists as the parent of a.b.test
+        assert!(fixture.check("b.test", QueryType::A).unwrap());
+    }
+
+    #[test]
+    fn nsec3_proves_nxdomain() {
+        let fixture = fixture(VALID);
+
+        let packet = fixture.query("nope.sub.test", QueryType::A);
+        assert_eq!(packet.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(fixture
+            .validate("nope.sub.test", QueryType::A, &packet)
+            .unwrap());
+    }
+
+    #[test]
+    fn nsec3_proves_nodata() {
+        let fixture = fixture(VALID);
+
+        assert!(fixture.check("www.sub.test", QueryType::MX).unwrap());
+    }
+
+    #[test]
+    fn nsec3_opt_out_is_insecure() {
+        let fixture = fixture(VALID);
+
+        // The delegation has no NSEC3 of its own, only an opted out span covering it
+        assert!(!fixture
+            .check("www.unsigned.optout.test", QueryType::A)
+            .unwrap());
+        // An opted out span could hide an unsigned delegation holding the name
+        let packet = fixture.query("nope.optout.test", QueryType::A);
+        assert_eq!(packet.header.rescode, ResponseCode::NXDOMAIN);
+        assert!(!fixture
+            .validate("nope.optout.test", QueryType::A, &packet)
+            .unwrap());
+        // Names in the chain are still proven as usual
+        assert!(fixture.check("www.optout.test", QueryType::MX).unwrap());
+    }
+
+    #[test]
+    fn wildcard_answer_is_secure() {
+        let fixture = fixture(VALID);
+
+        let packet = fixture.query("x.wild.test", QueryType::A);
+        assert!(packet
+            .answers
+            .iter()
+            .any(|rec| rec.get_querytype() == QueryType::A && rec.get_domain() == "x.wild.test"));
+        assert!(fixture
+            .validate("x.wild.test", QueryType::A, &packet)
+            .unwrap());
+    }
+
+    #[test]
+    fn wildcard_answer_without_proof_is_bogus() {
+        let fixture = fixture(VALID);
+
+        let mut packet = fixture.query("x.wild.test", QueryType::A);
+        packet.authorities.clear();
+        assert!(is_bogus(fixture.validate(
+            "x.wild.test",
+            QueryType::A,
+            &packet
+        )));
+    }
+
+    #[test]
+    fn tampered_answer_is_bogus() {
+        let fixture = fixture(VALID);
+
+        let mut packet = fixture.query("www.test", QueryType::A);
+        for rec in packet.answers.iter_mut() {
+            if let DnsRecord::A { ref mut addr, .. } = *rec {
+                *addr = "198.51.100.1".parse().unwrap();
+            }
+        }
+        assert!(is_bogus(fixture.validate(
+            "www.test",
+            QueryType::A,
+            &packet
+        )));
+    }
+
+    #[test]
+    fn stripped_signatures_are_bogus() {
+        let fixture = fixture(VALID);
+
+        let mut packet = fixture.query("www.sub.test", QueryType::A);
+        packet
+            .answers
+            .retain(|rec| rec.get_querytype() != QueryType::RRSIG);
+        assert!(is_bogus(fixture.validate(
+            "www.sub.test",
+            QueryType::A,
+            &packet
+        )));
+    }
+
+    #[test]
+    fn denial_without_proof_is_bogus() {
+        let fixture = fixture(VALID);
+
+        let mut packet = fixture.query("nope.test", QueryType::A);
+        packet.authorities.clear();
+        assert!(is_bogus(fixture.validate(
+            "nope.test",
+            QueryType::A,
+            &packet
+        )));
+
+        let mut packet = fixture.query("www.sub.test", QueryType::MX);
+        packet.authorities.clear();
+        assert!(is_bogus(fixture.validate(
+            "www.sub.test",
+            QueryType::MX,
+            &packet
+        )));
+    }
+
+    #[test]
+    fn nxdomain_for_existing_name_is_bogus() {
+        let fixture = fixture(VALID);
+
+        for &(name, qtype) in &[
+            ("www.test", QueryType::AAAA),
+            ("www.sub.test", QueryType::MX),
+        ] {
+            let mut packet = fixture.query(name, qtype);
+            packet.header.rescode = ResponseCode::NXDOMAIN;
+            assert!(is_bogus(fixture.validate(name, qtype, &packet)));
+        }
+    }
+
+    #[test]
+    fn untrusted_keys_are_bogus() {
+        let fixture = fixture(VALID);
+
+        let anchors = vec![Key::ed25519(9).ds("")];
+        let packet = fixture.query("www.test", QueryType::A);
+        assert!(is_bogus(fixture.validate_with(
+            &anchors,
+            "www.test",
+            QueryType::A,
+            &packet
+        )));
+    }
+
+    #[test]
+    fn expired_signature_is_bogus() {
+        let fixture = fixture(EXPIRED);
+
+        assert!(is_bogus(fixture.check("www.test", QueryType::A)));
+    }
+
+    #[test]
+    fn not_yet_valid_signature_is_bogus() {
+        let fixture = fixture(NOT_YET_VALID);
+
+        assert!(is_bogus(fixture.check("www.test", QueryType::A)));
+    }
+
+    #[test]
+    fn validated_keys_are_cached_between_queries() {
+        let fixture = fixture(VALID);
+        let key_cache = KeyCache::new(16);
+        let fetches = std::cell::Cell::new(0);
+        let fetch = |name: &str, qtype: QueryType| {
+            fetches.set(fetches.get() + 1);
+            fixture.fetch(name, qtype)
+        };
+
+        let validate = || {
+            let mut validator = Validator::new(&fixture.anchors, &key_cache, &fetch);
+            validator.now = NOW;
+            let packet = fixture.query("www.sub.test", QueryType::A);
+            validator.validate("www.sub.test", QueryType::A, &packet)
+        };
+
+        assert!(validate().unwrap());
+        let first_fetches = fetches.get();
+        assert!(first_fetches > 0);
+
+        // The chain of trust down to sub.test is already known
+        assert!(validate().unwrap());
+        assert_eq!(fetches.get(), first_fetches);
+    }
+
+    #[test]
+    fn authority_ns_records_must_be_signed() {
+        let fixture = fixture(VALID);
+        let sub = fixture
+            .zones
+            .iter()
+            .find(|zone| zone.name == "sub.test")
+            .unwrap();
+        let apex_ns = ns("sub.test");
+
+        let mut packet = fixture.query("www.sub.test", QueryType::A);
+        packet
+            .authorities
+            .push(sub.sign(&[&apex_ns], "sub.test", "sub.test"));
+        packet.authorities.push(apex_ns);
+        assert!(fixture
+            .validate("www.sub.test", QueryType::A, &packet)
+            .unwrap());
+
+        // Without a signature the answer still stands, but isn't marked secure
+        packet
+            .authorities
+            .retain(|rec| rec.get_querytype() != QueryType::RRSIG);
+        assert!(!fixture
+            .validate("www.sub.test", QueryType::A, &packet)
+            .unwrap());
+    }
+}
@@ -0,0 +1,93 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+// Everything which can go wrong reading, writing or resolving a DNS message
+#[derive(Debug)]
+pub enum DnsError {
+    // A message ended part way through something we were reading
+    Truncated,
+    // A message didn't fit in the buffer it was being written to
+    BufferFull,
+    // A label which is too long, or of a reserved type
+    BadLabel(String),
+    // A name longer than 255 bytes on the wire
+    NameTooLong,
+    // A compression pointer which loops, or points forwards
+    PointerLoop,
+    // A record whose contents don't match its type or length
+    BadRecord(String),
+    // A response which doesn't answer the query it was sent for
+    UnexpectedResponse(String),
+    // An upstream server didn't answer in time
+    Timeout(String),
+    // An upstream server refused to answer
+    UpstreamRefused(String),
+    // A DNS over HTTPS upstream failed at the HTTP level, or sent something other than a DNS
+    // message
+    UpstreamHttp(String),
+    // An answer failed DNSSEC validation
+    Bogus(String),
+    Io(io::Error),
+}
+
+impl DnsError {
+    // Was the message itself malformed, as opposed to us failing to handle it?
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            DnsError::Truncated
+                | DnsError::BadLabel(_)
+                | DnsError::NameTooLong
+                | DnsError::PointerLoop
+                | DnsError::BadRecord(_)
+        )
+    }
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsError::Truncated => write!(f, "Message is truncated"),
+            DnsError::BufferFull => write!(f, "Message does not fit in buffer"),
+            DnsError::BadLabel(msg) => write!(f, "Bad label: {0}", msg),
+            DnsError::NameTooLong => write!(f, "Name exceeds maximum length"),
+            DnsError::PointerLoop => write!(f, "Compression pointer loops or points forwards"),
+            DnsError::BadRecord(msg) => write!(f, "Bad record: {0}", msg),
+            DnsError::UnexpectedResponse(msg) => write!(f, "Unexpected response: {0}", msg),
+            DnsError::Timeout(msg) => write!(f, "Timed out: {0}", msg),
+            DnsError::UpstreamRefused(msg) => write!(f, "Refused: {0}", msg),
+            DnsError::UpstreamHttp(msg) => write!(f, "HTTP error: {0}", msg),
+            DnsError::Bogus(msg) => write!(f, "DNSSEC validation failed: {0}", msg),
+            DnsError::Io(e) => write!(f, "{0}", e),
+        }
+    }
+}
+
+impl error::Error for DnsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DnsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> DnsError {
+        DnsError::Io(e)
+    }
+}
+
+// Lets DNS errors surface through I/O code, such as while loading zones at startup
+impl From<DnsError> for io::Error {
+    fn from(e: DnsError) -> io::Error {
+        match e {
+            DnsError::Io(e) => e,
+            DnsError::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, DnsError>;
@@ -0,0 +1,307 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::time::{Duration, Instant};
+
+// DNS messages over TCP are prefixed with their length as two bytes (RFC 1035 section 4.2.2)
+const LENGTH_PREFIX: usize = 2;
+const READ_CHUNK: usize = 4096;
+
+// Read timeouts surface as either kind, depending on the platform
+pub fn is_timeout(e: &Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+// Reads length-prefixed DNS messages from a stream. Bytes are accumulated across calls, so a
+// message split over several segments, or interrupted by a read timeout, is picked up where it
+// left off, and bytes of pipelined messages read ahead are kept for the next call.
+pub struct FrameReader<R: Read> {
+    inner: R,
+    pending: Vec<u8>,
+    // How long a message may take to arrive once its first byte has, and when that was
+    message_timeout: Option<Duration>,
+    started: Option<Instant>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            pending: Vec::new(),
+            message_timeout: None,
+            started: None,
+        }
+    }
+
+    // Give up on a message which hasn't fully arrived this long after it started, so a client
+    // can't hold its connection open by trickling a message in a byte at a time
+    pub fn set_message_timeout(&mut self, timeout: Duration) {
+        self.message_timeout = Some(timeout);
+    }
+
+    // Read the next message. Returns Ok(None) if the stream ends cleanly between messages;
+    // ending part way through one is an error. Zero-length messages are returned as such.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return Ok(Some(frame));
+            }
+            if let (Some(timeout), Some(started)) = (self.message_timeout, self.started) {
+                if started.elapsed() >= timeout {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        "Message not completed in time",
+                    ));
+                }
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            match self.inner.read(&mut chunk) {
+                Ok(0) if self.pending.is_empty() => return Ok(None),
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Stream closed part way through a message",
+                    ))
+                }
+                Ok(bytes_read) => {
+                    if self.pending.is_empty() {
+                        self.started = Some(Instant::now());
+                    }
+                    self.pending.extend_from_slice(&chunk[..bytes_read]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Has part of a message arrived without the rest of it?
+    pub fn has_partial_frame(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        if self.pending.len() < LENGTH_PREFIX {
+            return None;
+        }
+
+        let len = ((self.pending[0] as usize) << 8) | (self.pending[1] as usize);
+        if self.pending.len() < LENGTH_PREFIX + len {
+            return None;
+        }
+
+        let frame = self.pending[LENGTH_PREFIX..LENGTH_PREFIX + len].to_vec();
+        self.pending.drain(..LENGTH_PREFIX + len);
+        // The next message started arriving no earlier than now
+        self.started = if self.pending.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+
+        Some(frame)
+    }
+}
+
+// Write a message with its length prefix. Both go out in a single buffer, so concurrent writers
+// holding a lock on the stream can't interleave, and short writes are retried until done.
+pub fn write_frame<W: Write>(writer: &mut W, message: &[u8]) -> Result<()> {
+    if message.len() > u16::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Message of {0} bytes is too long for TCP", message.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX + message.len());
+    frame.push((message.len() >> 8) as u8);
+    frame.push((message.len() & 0xFF) as u8);
+    frame.extend_from_slice(message);
+
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its data a few bytes per read, failing with a timeout before each read
+    // listed in `timeouts`
+    struct MockStream {
+        data: Vec<u8>,
+        position: usize,
+        chunk: usize,
+        reads: usize,
+        timeouts: Vec<usize>,
+    }
+
+    impl MockStream {
+        fn new(data: &[u8], chunk: usize) -> MockStream {
+            MockStream {
+                data: data.to_vec(),
+                position: 0,
+                chunk,
+                reads: 0,
+                timeouts: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.reads += 1;
+            if self.timeouts.contains(&self.reads) {
+                return Err(Error::new(ErrorKind::WouldBlock, "timed out"));
+            }
+
+            let end = (self.position + self.chunk)
+                .min(self.data.len())
+                .min(self.position + buf.len());
+            let bytes_read = end - self.position;
+            buf[..bytes_read].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+
+            Ok(bytes_read)
+        }
+    }
+
+    // Accepts at most a few bytes per write
+    struct ShortWriter {
+        written: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let bytes_written = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..bytes_written]);
+            Ok(bytes_written)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for message in messages {
+            write_frame(&mut data, message).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn reads_frame_one_byte_at_a_time() {
+        let mut reader = FrameReader::new(MockStream::new(&frames(&[b"hello", b"world"]), 1));
+
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"hello");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"world");
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_pipelined_frames_from_one_read() {
+        let data = frames(&[b"one", b"two", b"three"]);
+        let mut reader = FrameReader::new(MockStream::new(&data, data.len()));
+
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"one");
+        assert!(reader.has_partial_frame());
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"two");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"three");
+        assert!(!reader.has_partial_frame());
+        assert_eq!(reader.read_frame().unwrap(), None);
+        assert_eq!(reader.inner.reads, 2);
+    }
+
+    #[test]
+    fn reads_zero_length_frame() {
+        let mut reader = FrameReader::new(MockStream::new(&frames(&[b"", b"next"]), 1));
+
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"next");
+    }
+
+    #[test]
+    fn clean_eof_between_frames() {
+        let mut reader = FrameReader::new(MockStream::new(&[], 1));
+        assert_eq!(reader.read_frame().unwrap(), None);
+
+        let mut reader = FrameReader::new(MockStream::new(&frames(&[b"last"]), 1));
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"last");
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn eof_mid_frame_is_an_error() {
+        // Part way through the length prefix
+        let mut reader = FrameReader::new(MockStream::new(&[0], 1));
+        let e = reader.read_frame().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        // Part way through the message
+        let data = frames(&[b"truncated"]);
+        let mut reader = FrameReader::new(MockStream::new(&data[..6], 1));
+        let e = reader.read_frame().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn resumes_frame_after_timeout() {
+        let mut stream = MockStream::new(&frames(&[b"slow"]), 1);
+        // After the first byte of the prefix, and again part way through the message
+        stream.timeouts = vec![2, 5];
+        let mut reader = FrameReader::new(stream);
+
+        assert!(is_timeout(&reader.read_frame().unwrap_err()));
+        assert!(reader.has_partial_frame());
+        assert!(is_timeout(&reader.read_frame().unwrap_err()));
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"slow");
+    }
+
+    #[test]
+    fn message_timeout_gives_up_on_partial_frame() {
+        let mut reader = FrameReader::new(MockStream::new(&frames(&[b"slow"]), 1));
+        reader.set_message_timeout(Duration::from_millis(0));
+
+        // Bytes keep arriving, but not the whole message before its deadline
+        let e = reader.read_frame().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(reader.has_partial_frame());
+        assert_eq!(reader.inner.reads, 1);
+    }
+
+    #[test]
+    fn message_timeout_starts_with_first_byte() {
+        let mut stream = MockStream::new(&frames(&[b"first", b"second"]), 64);
+        stream.timeouts = vec![1];
+        let mut reader = FrameReader::new(stream);
+        reader.set_message_timeout(Duration::from_secs(60));
+
+        // Waiting between messages doesn't count against the next one
+        assert!(is_timeout(&reader.read_frame().unwrap_err()));
+        assert!(!reader.has_partial_frame());
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"first");
+        assert_eq!(reader.read_frame().unwrap().unwrap(), b"second");
+    }
+
+    #[test]
+    fn write_frame_retries_short_writes() {
+        let message = (0..=255).collect::<Vec<u8>>();
+        let mut writer = ShortWriter {
+            written: Vec::new(),
+            chunk: 3,
+        };
+        write_frame(&mut writer, &message).unwrap();
+
+        assert_eq!(&writer.written[..2], &[0x01, 0x00]);
+        assert_eq!(&writer.written[2..], &message[..]);
+    }
+
+    #[test]
+    fn write_frame_rejects_oversized_message() {
+        let message = vec![0; u16::MAX as usize + 1];
+        let e = write_frame(&mut Vec::new(), &message).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
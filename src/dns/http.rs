@@ -0,0 +1,198 @@
+use h2::RecvStream;
+use http::Request;
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+// Bounds on what we'll read from a client: DNS messages are never larger than 64 KiB, and
+// DNS over HTTPS requests need only a handful of short headers
+const MAX_LINE: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY: usize = 65_535;
+
+fn bad_request(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// An HTTP/1.1 or HTTP/2 request, as much of one as DNS over HTTPS needs
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // Header names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    // Value of a query string parameter, left percent-encoded
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.target.split_at(self.target.find('?')? + 1);
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    // HTTP/1.1 connections persist unless either side says otherwise; HTTP/1.0 ones don't
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    let bytes_read = reader.by_ref().take(MAX_LINE).read_line(&mut line)?;
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(bad_request("Line too long or truncated"));
+    }
+
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+// Read the next request off a connection. Returns Ok(None) if the client closed it cleanly
+// between requests.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    // Stray empty lines before a request are allowed (RFC 9112 section 2.2)
+    let request_line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+
+    let parts = request_line.split(' ').collect::<Vec<&str>>();
+    let (method, target, version) = match parts[..] {
+        [method, target, version] if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target.to_string(), version.to_string())
+        }
+        _ => return Err(bad_request("Malformed request line")),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("Headers cut short"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(bad_request("Too many headers"));
+        }
+        let colon = line
+            .find(':')
+            .ok_or_else(|| bad_request("Malformed header"))?;
+        headers.push((
+            line[..colon].trim().to_string(),
+            line[colon + 1..].trim().to_string(),
+        ));
+    }
+
+    let mut request = HttpRequest {
+        method,
+        target,
+        version,
+        headers,
+        body: Vec::new(),
+    };
+
+    // DNS messages have a known length up front, so chunked bodies aren't supported
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(bad_request("Chunked requests are not supported"));
+    }
+    if let Some(length) = request.header("Content-Length") {
+        let length = length
+            .parse::<usize>()
+            .map_err(|_| bad_request("Malformed Content-Length"))?;
+        if length > MAX_BODY {
+            return Err(bad_request("Request body too large"));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        request.body = body;
+    }
+
+    Ok(Some(request))
+}
+
+// Read the body of a request on an HTTP/2 stream (RFC 9113), giving it the same form as an
+// HTTP/1.1 one
+pub async fn read_h2_request(request: Request<RecvStream>) -> Result<HttpRequest> {
+    let (parts, mut body) = request.into_parts();
+    let mut request = HttpRequest {
+        method: parts.method.to_string(),
+        target: parts
+            .uri
+            .path_and_query()
+            .map(|target| target.to_string())
+            .unwrap_or_default(),
+        version: "HTTP/2".to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: Vec::new(),
+    };
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Error::new(ErrorKind::ConnectionAborted, e))?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        request.body.extend_from_slice(&chunk);
+        if request.body.len() > MAX_BODY {
+            return Err(bad_request("Request body too large"));
+        }
+    }
+
+    Ok(request)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+// Write a complete response in one go, so it goes out in as few TLS records as possible
+pub fn write_response<W: Write>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {0} {1}\r\n", status, reason(status));
+    for (name, value) in headers {
+        response.push_str(&format!("{0}: {1}\r\n", name, value));
+    }
+    response.push_str(&format!("Content-Length: {0}\r\n\r\n", body.len()));
+
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    writer.write_all(&response)?;
+    writer.flush()
+}
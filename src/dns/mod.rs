@@ -1,6 +1,16 @@
+pub mod authority;
 mod buffer;
+mod cache;
+pub mod config;
 pub mod context;
+mod dnssec;
+mod error;
+mod framing;
+mod http;
 mod network;
 mod protocol;
 pub mod resolver;
 pub mod server;
+mod tls;
+mod upstream;
+mod zonefile;
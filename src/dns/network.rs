@@ -1,20 +1,669 @@
-use super::buffer::{ByteBuffer, BytePacketBuffer, ExtendingBuffer, VariableBuffer};
-use super::protocol::{DnsPacket, DnsQuestion, QueryType};
-use std::io::{Error, ErrorKind, Read, Result, Write};
-use std::net::{TcpStream, UdpSocket};
-use std::sync::atomic::{AtomicU16, Ordering};
+use super::buffer::{ByteBuffer, BytePacketBuffer, VariableBuffer, MAX_EDNS_SIZE};
+use super::config::ResolverConfig;
+use super::error::{DnsError, Result};
+use super::framing::{is_timeout, write_frame, FrameReader};
+use super::protocol::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResponseCode};
+use super::tls::{self, TlsReader, TlsWriter};
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Request, StatusCode};
+use rand::{random, thread_rng, Rng};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
 
-pub struct NetworkClient {
+// How long a connection to a TLS upstream is kept open with no queries on it
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Does the response answer the question we asked, with the ID we asked it under?
+fn is_response_to(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(r, q)| r.qtype == q.qtype && r.name.eq_ignore_ascii_case(&q.name))
+}
+
+// A query awaiting its response on one of the client's sockets or connections
+struct PendingQuery {
+    server: SocketAddr,
+    query: DnsPacket,
+    sender: mpsc::Sender<DnsPacket>,
+}
+
+type PendingQueries = Arc<Mutex<HashMap<u16, PendingQuery>>>;
+
+// Pass a response on to the query it answers, if it answers an outstanding one from the server
+// it came from
+fn deliver_response(pending: &PendingQueries, response: DnsPacket, source: SocketAddr) -> bool {
+    let mut pending = pending
+        .lock()
+        .expect("Failed to acquire pending query lock");
+    let id = response.header.id;
+    let matched = match pending.get(&id) {
+        Some(query) => query.server == source && is_response_to(&query.query, &response),
+        None => false,
+    };
+
+    if matched {
+        if let Some(query) = pending.remove(&id) {
+            // The querying thread may have given up already; that's fine
+            let _ = query.sender.send(response);
+        }
+    }
+    matched
+}
+
+// Register a query so its response can be routed back to the sender, re-rolling the ID if it's
+// already in use. Returns the query in wire format.
+fn register_query(
+    pending: &PendingQueries,
+    mut packet: DnsPacket,
+    server: SocketAddr,
+    sender: mpsc::Sender<DnsPacket>,
+) -> Result<(u16, BytePacketBuffer)> {
+    let mut req_buffer = BytePacketBuffer::new();
+    let mut pending = pending
+        .lock()
+        .expect("Failed to acquire pending query lock");
+    while pending.contains_key(&packet.header.id) {
+        packet.header.id = random::<u16>();
+    }
+    packet.write(&mut req_buffer)?;
+
+    let id = packet.header.id;
+    pending.insert(
+        id,
+        PendingQuery {
+            server,
+            query: packet,
+            sender,
+        },
+    );
+
+    Ok((id, req_buffer))
+}
+
+fn forget_query(pending: &PendingQueries, id: u16) {
+    pending
+        .lock()
+        .expect("Failed to acquire pending query lock")
+        .remove(&id);
+}
+
+// Hand each datagram arriving on the socket to the query it answers. Anything which doesn't
+// match an outstanding query from the server it was sent to is dropped and counted.
+fn dispatch_responses(socket: UdpSocket, pending: PendingQueries, mismatched: Arc<AtomicUsize>) {
+    loop {
+        let mut res_buffer = BytePacketBuffer::with_size(MAX_EDNS_SIZE);
+        let source = match socket.recv_from(&mut res_buffer.buf) {
+            Ok((len, source)) => {
+                res_buffer.buf.truncate(len);
+                source
+            }
+            Err(e) => {
+                println!("Failed to receive upstream response: {:?}", e);
+                continue;
+            }
+        };
+
+        let accepted = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) => deliver_response(&pending, response, source),
+            Err(_) => false,
+        };
+
+        if !accepted {
+            let count = mismatched.fetch_add(1, Ordering::Relaxed) + 1;
+            println!(
+                "Dropped unmatched response from {0} ({1} dropped so far)",
+                source, count
+            );
+        }
+    }
+}
+
+// Bind a socket to a random unprivileged port, so the port can't be guessed by a spoofer
+fn bind_random_port(ip: IpAddr) -> io::Result<UdpSocket> {
+    for _ in 0..16 {
+        let port = thread_rng().gen_range(1024, 65535);
+        match UdpSocket::bind((ip, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Let the OS choose an ephemeral port if we keep colliding with other sockets
+    UdpSocket::bind((ip, 0))
+}
+
+// A socket upstream queries are sent from, along with the queries awaiting a response on it
+struct UdpChannel {
     socket: UdpSocket,
-    pid_seq: AtomicU16,
+    pending: PendingQueries,
+}
+
+impl UdpChannel {
+    fn new(socket: UdpSocket, mismatched: Arc<AtomicUsize>) -> Result<UdpChannel> {
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+
+        let dispatch_socket = socket.try_clone()?;
+        let dispatch_pending = pending.clone();
+        thread::Builder::new()
+            .name(format!(
+                "DNS - upstream response dispatcher {0}",
+                socket.local_addr()?
+            ))
+            .spawn(move || dispatch_responses(dispatch_socket, dispatch_pending, mismatched))?;
+
+        Ok(UdpChannel { socket, pending })
+    }
+}
+
+fn resolve_server(server: (&str, u16)) -> Result<SocketAddr> {
+    server.to_socket_addrs()?.next().ok_or_else(|| {
+        DnsError::Io(Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("Could not resolve {0}", server.0),
+        ))
+    })
+}
+
+// An open connection to a TLS upstream. Queries are written to it as they come and one thread
+// reads responses off it, handing each to the query it answers, so many queries can be in
+// flight at once (RFC 7766 section 6.2.1.1).
+struct TlsConnection {
+    addr: SocketAddr,
+    writer: Mutex<TlsWriter>,
+    pending: PendingQueries,
+    closed: AtomicBool,
+    // Why the connection failed, if it did rather than being closed
+    failure: Mutex<Option<String>>,
+}
+
+impl TlsConnection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Once the connection's gone, queries waiting on it are failed by dropping their senders
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pending
+            .lock()
+            .expect("Failed to acquire pending query lock")
+            .clear();
+    }
+}
+
+// Read responses off a connection until the server closes it, it fails, or it sits idle with
+// nothing left to answer
+fn read_tls_responses(reader: TlsReader, connection: Arc<TlsConnection>) {
+    let mut frames = FrameReader::new(reader);
+    loop {
+        let frame = match frames.read_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(ref e) if is_timeout(e) => {
+                let idle = connection
+                    .pending
+                    .lock()
+                    .expect("Failed to acquire pending query lock")
+                    .is_empty();
+                if idle && !frames.has_partial_frame() {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => {
+                println!("TLS connection to {0} failed: {1}", connection.addr, e);
+                *connection
+                    .failure
+                    .lock()
+                    .expect("Failed to acquire TLS upstream lock") = Some(e.to_string());
+                break;
+            }
+        };
+
+        let mut res_buffer = VariableBuffer::from_bytes(frame);
+        let accepted = match DnsPacket::from_buffer(&mut res_buffer) {
+            Ok(response) => deliver_response(&connection.pending, response, connection.addr),
+            Err(_) => false,
+        };
+        if !accepted {
+            println!("Dropped unmatched response from {0}", connection.addr);
+        }
+    }
+
+    connection.close();
+}
+
+// A DNS over TLS server (RFC 7858) queries are forwarded to. One connection is kept open to it
+// and shared by all queries, and reopened when the server closes it.
+pub struct TlsUpstream {
+    host: String,
+    port: u16,
+    // Name the server's certificate must be valid for, which is also sent as the SNI
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    connection: Mutex<Option<Arc<TlsConnection>>>,
+}
+
+impl TlsUpstream {
+    pub fn new(
+        host: &str,
+        port: u16,
+        name: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<TlsUpstream> {
+        let server_name = ServerName::try_from(name.to_string()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid TLS authentication name: {0}", name),
+            )
+        })?;
+
+        Ok(TlsUpstream {
+            host: host.to_string(),
+            port,
+            server_name,
+            config,
+            connection: Mutex::new(None),
+        })
+    }
+
+    // The open connection, or a new one if there isn't one. Queries arriving while it's being
+    // set up wait for it rather than opening their own.
+    fn connect(&self, timeout: Duration) -> Result<Arc<TlsConnection>> {
+        let mut current = self
+            .connection
+            .lock()
+            .expect("Failed to acquire TLS upstream lock");
+        if let Some(ref connection) = *current {
+            if !connection.is_closed() {
+                return Ok(connection.clone());
+            }
+        }
+
+        let addr = resolve_server((&self.host, self.port))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(TLS_IDLE_TIMEOUT))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let session = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let (reader, writer) = tls::split(session, stream)?;
+
+        let connection = Arc::new(TlsConnection {
+            addr,
+            writer: Mutex::new(writer),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            closed: AtomicBool::new(false),
+            failure: Mutex::new(None),
+        });
+        let reader_connection = connection.clone();
+        thread::Builder::new()
+            .name(format!("DNS - TLS upstream reader {0}", addr))
+            .spawn(move || read_tls_responses(reader, reader_connection))?;
+
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn exchange(&self, packet: DnsPacket, timeout: Duration) -> Result<DnsPacket> {
+        // A connection the server has closed while idle only shows up as such when it's next
+        // used, so a query which loses its connection is tried once more on a fresh one
+        let mut failure = None;
+        for _ in 0..2 {
+            let connection = self.connect(timeout)?;
+
+            let (sender, receiver) = mpsc::channel();
+            let (id, req_buffer) =
+                register_query(&connection.pending, packet.clone(), connection.addr, sender)?;
+            // The reader may have closed the connection before we registered
+            if connection.is_closed() {
+                continue;
+            }
+
+            let sent = {
+                let mut writer = connection
+                    .writer
+                    .lock()
+                    .expect("Failed to acquire TLS upstream lock");
+                write_frame(&mut *writer, req_buffer.get_range(0, req_buffer.head())?)
+            };
+            if let Err(e) = sent {
+                println!("Failed to send query to {0}: {1}", connection.addr, e);
+                failure = Some(e.to_string());
+                connection.close();
+                continue;
+            }
+
+            match receiver.recv_timeout(timeout) {
+                Ok(response) => return Ok(response),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    forget_query(&connection.pending, id);
+                    return Err(DnsError::Timeout(format!(
+                        "No response from {0}",
+                        connection.addr
+                    )));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    failure = connection
+                        .failure
+                        .lock()
+                        .expect("Failed to acquire TLS upstream lock")
+                        .clone();
+                    continue;
+                }
+            }
+        }
+
+        let reason = failure.map(|e| format!(": {0}", e)).unwrap_or_default();
+        Err(DnsError::Io(Error::new(
+            ErrorKind::ConnectionAborted,
+            format!(
+                "Connection to {0}:{1} closed before a response{2}",
+                self.host, self.port, reason
+            ),
+        )))
+    }
+}
+
+impl fmt::Display for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tls://{0}:{1}", self.host, self.port)
+    }
+}
+
+// Media type of DNS messages in HTTP bodies (RFC 8484 section 6)
+const DNS_MESSAGE: &str = "application/dns-message";
+
+fn http_error<E: fmt::Display>(upstream: &HttpsUpstream, e: E) -> DnsError {
+    DnsError::UpstreamHttp(format!("{0}: {1}", upstream, e))
+}
+
+// An open HTTP/2 connection to a DNS over HTTPS upstream, and whether it's since closed
+struct HttpsConnection {
+    sender: SendRequest<Bytes>,
+    closed: Arc<AtomicBool>,
+}
+
+// A DNS over HTTPS server (RFC 8484) queries are forwarded to. Queries are POSTed as separate
+// streams on one HTTP/2 connection, which is reopened when the server closes it. The
+// connection is driven by a runtime of its own, so the rest of the server needn't know.
+pub struct HttpsUpstream {
+    url: String,
+    host: String,
+    port: u16,
+    // Name the server's certificate must be valid for, which is also sent as the SNI
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    runtime: Runtime,
+    connection: Mutex<Option<HttpsConnection>>,
+}
+
+impl HttpsUpstream {
+    pub fn new(
+        url: &str,
+        host: &str,
+        port: u16,
+        name: &str,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<HttpsUpstream> {
+        let server_name = ServerName::try_from(name.to_string()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid TLS authentication name: {0}", name),
+            )
+        })?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("DNS - HTTPS upstream")
+            .enable_all()
+            .build()?;
+
+        Ok(HttpsUpstream {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            server_name,
+            connector: TlsConnector::from(config),
+            runtime,
+            connection: Mutex::new(None),
+        })
+    }
+
+    // A handle to send requests on the open connection, or a new one if there isn't one
+    fn connect(&self, timeout: Duration) -> Result<SendRequest<Bytes>> {
+        let mut current = self
+            .connection
+            .lock()
+            .expect("Failed to acquire HTTPS upstream lock");
+        if let Some(ref connection) = *current {
+            if !connection.closed.load(Ordering::SeqCst) {
+                return Ok(connection.sender.clone());
+            }
+        }
+
+        let addr = resolve_server((&self.host, self.port))?;
+        let handshake = async {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let stream = self
+                .connector
+                .connect(self.server_name.clone(), stream)
+                .await?;
+            // HTTP/2 over TLS has to be agreed with ALPN (RFC 9113 section 3.2)
+            if stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+                return Err(http_error(self, "server does not support HTTP/2"));
+            }
+            h2::client::handshake(stream)
+                .await
+                .map_err(|e| http_error(self, e))
+        };
+        let (sender, driver) = self
+            .runtime
+            .block_on(async { tokio::time::timeout(timeout, handshake).await })
+            .map_err(|_| DnsError::Timeout(format!("Connecting to {0}", self)))??;
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let driver_closed = closed.clone();
+        let url = self.url.clone();
+        self.runtime.spawn(async move {
+            if let Err(e) = driver.await {
+                println!("HTTPS connection to {0} failed: {1}", url, e);
+            }
+            driver_closed.store(true, Ordering::SeqCst);
+        });
+
+        *current = Some(HttpsConnection {
+            sender: sender.clone(),
+            closed,
+        });
+        Ok(sender)
+    }
+
+    // POST a query on the connection and return the body of the response. Returns Ok(None) if
+    // the connection closed before the request could be sent.
+    async fn post(&self, sender: SendRequest<Bytes>, query: Bytes) -> Result<Option<Vec<u8>>> {
+        let mut sender = match sender.ready().await {
+            Ok(sender) => sender,
+            Err(_) => return Ok(None),
+        };
+
+        let request = Request::post(self.url.as_str())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .header(CONTENT_LENGTH, query.len())
+            .body(())
+            .map_err(|e| http_error(self, e))?;
+        let (response, mut stream) = sender
+            .send_request(request, false)
+            .map_err(|e| http_error(self, e))?;
+        stream
+            .send_data(query, true)
+            .map_err(|e| http_error(self, e))?;
+
+        let response = response.await.map_err(|e| http_error(self, e))?;
+        if response.status() != StatusCode::OK {
+            return Err(http_error(self, response.status()));
+        }
+        // Only the media type matters; parameters such as a charset may follow it
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or("")
+            .trim();
+        if !media_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+            return Err(http_error(self, "response is not a DNS message"));
+        }
+
+        let mut body = response.into_body();
+        let mut message = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| http_error(self, e))?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            message.extend_from_slice(&chunk);
+            if message.len() > MAX_EDNS_SIZE {
+                return Err(http_error(self, "response is too large"));
+            }
+        }
+
+        Ok(Some(message))
+    }
+
+    fn exchange(&self, mut packet: DnsPacket, timeout: Duration) -> Result<DnsPacket> {
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let query = Bytes::copy_from_slice(req_buffer.get_range(0, req_buffer.head())?);
+
+        // As with TLS, a connection the server closed while idle is only found out about when
+        // it's next used, so the query is tried once more on a fresh one
+        for _ in 0..2 {
+            let sender = self.connect(timeout)?;
+            let message = self
+                .runtime
+                .block_on(async {
+                    tokio::time::timeout(timeout, self.post(sender, query.clone())).await
+                })
+                .map_err(|_| DnsError::Timeout(format!("No response from {0}", self)))??;
+            let message = match message {
+                Some(message) => message,
+                None => continue,
+            };
+
+            let mut res_buffer = VariableBuffer::from_bytes(message);
+            let response = DnsPacket::from_buffer(&mut res_buffer)?;
+            if !is_response_to(&packet, &response) {
+                return Err(DnsError::UnexpectedResponse(format!(
+                    "Response from {0} does not match query",
+                    self
+                )));
+            }
+            return Ok(response);
+        }
+
+        Err(DnsError::Io(Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("Connection to {0} closed before a response", self),
+        )))
+    }
+}
+
+impl fmt::Display for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{0}", self.url)
+    }
+}
+
+// Bind the sockets UDP queries to one address family are sent from
+fn bind_channels(
+    config: &ResolverConfig,
+    ip: IpAddr,
+    mismatched: Arc<AtomicUsize>,
+) -> Result<Vec<UdpChannel>> {
+    match config.client_port {
+        // A fixed source port is easy to spoof, so only use it when explicitly asked to
+        Some(port) => Ok(vec![UdpChannel::new(
+            UdpSocket::bind((ip, port))?,
+            mismatched,
+        )?]),
+        None => (0..config.source_ports)
+            .map(|_| UdpChannel::new(bind_random_port(ip)?, mismatched.clone()))
+            .collect::<Result<Vec<UdpChannel>>>(),
+    }
+}
+
+pub struct NetworkClient {
+    // Pools of sockets on randomized ports, for IPv4 and IPv6 servers; each UDP query goes out
+    // on a random one
+    channels: Vec<UdpChannel>,
+    channels_v6: Vec<UdpChannel>,
+    // How long to wait for each response from an upstream server
+    timeout: Duration,
+    // How many times a timed out UDP query is resent
+    retries: u32,
+    // Delay before the first retry; doubled for each retry after it
+    backoff: Duration,
+    // UDP payload size advertised to upstream servers with EDNS(0)
+    edns_udp_size: u16,
 }
 
 impl NetworkClient {
-    pub fn new(port: u16) -> NetworkClient {
-        NetworkClient {
-            pid_seq: AtomicU16::new(0),
-            socket: UdpSocket::bind(("0.0.0.0", port)).unwrap(),
+    pub fn new(config: &ResolverConfig) -> Result<NetworkClient> {
+        let mismatched = Arc::new(AtomicUsize::new(0));
+        let channels = bind_channels(config, Ipv4Addr::UNSPECIFIED.into(), mismatched.clone())?;
+        // Hosts without IPv6 can still reach IPv4 servers
+        let channels_v6 = bind_channels(config, Ipv6Addr::UNSPECIFIED.into(), mismatched)
+            .unwrap_or_else(|e| {
+                println!("Upstream queries over IPv6 are unavailable: {0}", e);
+                Vec::new()
+            });
+
+        Ok(NetworkClient {
+            channels,
+            channels_v6,
+            timeout: Duration::from_millis(config.timeout_ms),
+            retries: config.retries,
+            backoff: Duration::from_millis(config.backoff_ms),
+            edns_udp_size: config.edns_udp_size,
+        })
+    }
+
+    fn build_query(&self, qname: &str, qtype: QueryType, recursive: bool, edns: bool) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+
+        // IDs come from a CSPRNG so they can't be predicted by an off-path attacker
+        packet.header.id = random::<u16>();
+        packet.header.questions = 1;
+        packet.header.recursion_desired = recursive;
+        packet
+            .questions
+            .push(DnsQuestion::new(String::from(qname), qtype));
+        // Always ask for signatures, so they're on hand for clients which want them
+        if edns {
+            packet
+                .resources
+                .push(DnsRecord::new_opt(self.edns_udp_size, true));
         }
+
+        packet
     }
 
     fn send_tcp_query(
@@ -25,34 +674,37 @@ impl NetworkClient {
         recursive: bool,
     ) -> Result<DnsPacket> {
         // Set up connection to downstream server
-        let mut stream = TcpStream::connect(&server)?;
+        let addr = resolve_server(server)?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
 
         // Prepare question packet to send downstream
-        let mut packet = DnsPacket::new();
-        packet.header.id = self.pid_seq.fetch_add(1, Ordering::SeqCst);
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
-        packet
-            .questions
-            .push(DnsQuestion::new(String::from(qname), qtype));
+        let mut packet = self.build_query(qname, qtype, recursive, true);
 
         // Write question into buffer and send request
         let mut req_buffer = BytePacketBuffer::new();
-        let data_len = packet.write(&mut req_buffer).unwrap();
-        let mut len_buffer = [0; 2];
-        len_buffer[0] = (data_len >> 8) as u8;
-        len_buffer[1] = (data_len & 0xFF) as u8;
-        stream.write(&len_buffer)?;
-        stream.write(&req_buffer.buf[0..req_buffer.head()])?;
+        let data_len = packet.write(&mut req_buffer)?;
+        write_frame(&mut stream, req_buffer.get_range(0, data_len)?)?;
 
         // Read the response
-        let mut len_buffer = [0; 2];
-        stream.read(&mut len_buffer)?;
-        let buf_len = ((len_buffer[0] as u16) << 8) | (len_buffer[1] as u16);
-        let mut res_buffer = VariableBuffer::new(buf_len as usize);
-        stream.read(&mut res_buffer.buf).unwrap();
+        let frame = FrameReader::new(&mut stream).read_frame()?.ok_or_else(|| {
+            DnsError::Io(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Connection to {0} closed before a response", addr),
+            ))
+        })?;
+        let mut res_buffer = VariableBuffer::from_bytes(frame);
+
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if !is_response_to(&packet, &response) {
+            return Err(DnsError::UnexpectedResponse(format!(
+                "Response from {0} does not match query",
+                addr
+            )));
+        }
 
-        DnsPacket::from_buffer(&mut res_buffer)
+        Ok(response)
     }
 
     fn send_udp_query(
@@ -61,25 +713,98 @@ impl NetworkClient {
         qtype: QueryType,
         server: (&str, u16),
         recursive: bool,
+        edns: bool,
+        retries: u32,
     ) -> Result<DnsPacket> {
-        let mut packet = DnsPacket::new();
+        let packet = self.build_query(qname, qtype, recursive, edns);
+        let addr = resolve_server(server)?;
+        let channels = if addr.is_ipv6() {
+            &self.channels_v6
+        } else {
+            &self.channels
+        };
+        if channels.is_empty() {
+            return Err(DnsError::Io(Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("No socket to query {0} from", addr),
+            )));
+        }
+        let channel = &channels[thread_rng().gen_range(0, channels.len())];
 
-        packet.header.id = self.pid_seq.fetch_add(1, Ordering::SeqCst);
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
-        packet
-            .questions
-            .push(DnsQuestion::new(String::from(qname), qtype));
+        // Register the query so the dispatcher can route its response back to us
+        let (sender, receiver) = mpsc::channel();
+        let (id, req_buffer) = register_query(&channel.pending, packet, addr, sender)?;
 
-        let mut req_buffer = BytePacketBuffer::new();
-        packet.write(&mut req_buffer).unwrap();
-        self.socket
-            .send_to(&req_buffer.buf[0..req_buffer.head()], server)?;
+        let result = self.exchange_udp(&channel.socket, &req_buffer, addr, &receiver, retries);
+        forget_query(&channel.pending, id);
+
+        result
+    }
+
+    fn exchange_udp(
+        &self,
+        socket: &UdpSocket,
+        req_buffer: &BytePacketBuffer,
+        addr: SocketAddr,
+        receiver: &mpsc::Receiver<DnsPacket>,
+        retries: u32,
+    ) -> Result<DnsPacket> {
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                let delay = self.backoff * 2u32.saturating_pow(attempt - 1);
+                println!("Retrying query to {0} in {1:?}", addr, delay);
+                thread::sleep(delay);
+            }
 
-        let mut res_buffer = BytePacketBuffer::new();
-        self.socket.recv_from(&mut res_buffer.buf).unwrap();
+            socket.send_to(&req_buffer.buf[0..req_buffer.head()], addr)?;
 
-        DnsPacket::from_buffer(&mut res_buffer)
+            match receiver.recv_timeout(self.timeout) {
+                Ok(response) => return Ok(response),
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(DnsError::Io(Error::new(
+                        ErrorKind::BrokenPipe,
+                        "Upstream response dispatcher stopped",
+                    )))
+                }
+            }
+        }
+
+        Err(DnsError::Timeout(format!(
+            "No response from {0} after {1} attempts",
+            addr,
+            retries + 1
+        )))
+    }
+
+    pub fn send_tls_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        upstream: &TlsUpstream,
+        recursive: bool,
+    ) -> Result<DnsPacket> {
+        let packet = self.build_query(qname, qtype, recursive, true);
+        upstream.exchange(packet, self.timeout)
+    }
+
+    pub fn send_https_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        upstream: &HttpsUpstream,
+        recursive: bool,
+    ) -> Result<DnsPacket> {
+        // Queries go out with an ID of 0, so identical ones can be cached by HTTP caches
+        // (RFC 8484 section 4.1); responses are matched up by HTTP/2 stream instead
+        let mut packet = self.build_query(qname, qtype, recursive, true);
+        packet.header.id = 0;
+        upstream.exchange(packet, self.timeout)
+    }
+
+    // How many times a timed out UDP query is resent
+    pub fn retries(&self) -> u32 {
+        self.retries
     }
 
     pub fn send_query(
@@ -89,7 +814,24 @@ impl NetworkClient {
         server: (&str, u16),
         recursive: bool,
     ) -> Result<DnsPacket> {
-        let packet = self.send_udp_query(qname, qtype, server, recursive)?;
+        self.send_query_with_retries(qname, qtype, server, recursive, self.retries)
+    }
+
+    // Send a query, resending it over UDP up to the given number of times if it times out
+    pub fn send_query_with_retries(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        recursive: bool,
+        retries: u32,
+    ) -> Result<DnsPacket> {
+        let mut packet = self.send_udp_query(qname, qtype, server, recursive, true, retries)?;
+
+        // Servers predating EDNS(0) may reject the OPT record; ask them again without it
+        if packet.header.rescode == ResponseCode::FORMERR && packet.get_edns().is_none() {
+            packet = self.send_udp_query(qname, qtype, server, recursive, false, retries)?;
+        }
 
         if !packet.header.truncated_message {
             return Ok(packet);
@@ -98,3 +840,216 @@ impl NetworkClient {
         self.send_tcp_query(qname, qtype, server, recursive)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::tls::CertificateStore;
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    // A response to the query answering with the given address
+    fn answer(query: &DnsPacket, addr: Ipv4Addr) -> DnsPacket {
+        let mut response = query.clone();
+        response.header.response = true;
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr,
+            ttl: 300,
+        });
+        response
+    }
+
+    fn send_packet(socket: &UdpSocket, mut packet: DnsPacket, addr: SocketAddr) {
+        let mut buffer = BytePacketBuffer::new();
+        let len = packet.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[0..len], addr).unwrap();
+    }
+
+    #[test]
+    fn spoofed_responses_are_counted_and_dropped() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mismatched = Arc::new(AtomicUsize::new(0));
+        let channel =
+            UdpChannel::new(UdpSocket::bind("127.0.0.1:0").unwrap(), mismatched.clone()).unwrap();
+        let client_addr = channel.socket.local_addr().unwrap();
+
+        let mut query = DnsPacket::new();
+        query.header.id = random::<u16>();
+        query.questions.push(DnsQuestion::new(
+            "www.example.com".to_string(),
+            QueryType::A,
+        ));
+        let (sender, receiver) = mpsc::channel();
+        let (id, req_buffer) = register_query(
+            &channel.pending,
+            query,
+            server.local_addr().unwrap(),
+            sender,
+        )
+        .unwrap();
+        channel
+            .socket
+            .send_to(
+                &req_buffer.buf[0..req_buffer.head()],
+                server.local_addr().unwrap(),
+            )
+            .unwrap();
+
+        let mut buffer = BytePacketBuffer::new();
+        server.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        let spoofed = Ipv4Addr::new(203, 0, 113, 1);
+
+        // The wrong ID, the wrong question, and the right answer from the wrong server
+        let mut wrong_id = answer(&query, spoofed);
+        wrong_id.header.id = id.wrapping_add(1);
+        send_packet(&server, wrong_id, client_addr);
+        let mut wrong_question = answer(&query, spoofed);
+        wrong_question.questions[0].name = "evil.example.com".to_string();
+        send_packet(&server, wrong_question, client_addr);
+        send_packet(&spoofer, answer(&query, spoofed), client_addr);
+
+        send_packet(
+            &server,
+            answer(&query, Ipv4Addr::new(192, 0, 2, 1)),
+            client_addr,
+        );
+
+        let response = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        match response.answers[..] {
+            [DnsRecord::A { addr, .. }] => assert_eq!(addr, Ipv4Addr::new(192, 0, 2, 1)),
+            _ => panic!("Unexpected answers: {:?}", response.answers),
+        }
+        assert_eq!(mismatched.load(Ordering::Relaxed), 3);
+        assert!(channel.pending.lock().unwrap().is_empty());
+    }
+
+    // A DNS over TLS server which reads queries in batches of the given size, answering each
+    // batch in reverse order, and hangs up after the first batch if asked to. Returns its
+    // address and a count of the connections made to it.
+    fn dot_server(batch: usize, hang_up: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let store =
+            CertificateStore::load(&fixtures.join("leaf.pem"), &fixtures.join("leaf.key")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let count = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                count.fetch_add(1, Ordering::SeqCst);
+                let session =
+                    rustls::ServerConnection::new(store.server_config(&[b"dot"])).unwrap();
+                let (reader, mut writer) = tls::split(session, stream.unwrap()).unwrap();
+                let mut frames = FrameReader::new(reader);
+
+                loop {
+                    let mut queries = Vec::new();
+                    while queries.len() < batch {
+                        match frames.read_frame() {
+                            Ok(Some(frame)) => {
+                                let mut buffer = VariableBuffer::from_bytes(frame);
+                                queries.push(DnsPacket::from_buffer(&mut buffer).unwrap());
+                            }
+                            _ => break,
+                        }
+                    }
+                    if queries.len() < batch {
+                        break;
+                    }
+
+                    for query in queries.iter().rev() {
+                        let mut response = answer(query, Ipv4Addr::new(192, 0, 2, 1));
+                        let mut buffer = BytePacketBuffer::new();
+                        let len = response.write(&mut buffer).unwrap();
+                        write_frame(&mut writer, &buffer.buf[0..len]).unwrap();
+                    }
+                    if hang_up {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (addr, connections)
+    }
+
+    fn tls_upstream(addr: SocketAddr, pins: &[String]) -> TlsUpstream {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls/root.pem");
+        let ca_file = if pins.is_empty() {
+            Some(root.as_path())
+        } else {
+            None
+        };
+        let config = tls::client_config(ca_file, pins, &[b"dot"]).unwrap();
+        TlsUpstream::new("127.0.0.1", addr.port(), "dot.test", config).unwrap()
+    }
+
+    fn network_client() -> NetworkClient {
+        NetworkClient::new(&ResolverConfig {
+            source_ports: 1,
+            ..ResolverConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn tls_upstream_pipelines_queries_on_one_connection() {
+        let (addr, connections) = dot_server(3, false);
+        let upstream = Arc::new(tls_upstream(addr, &[]));
+        let client = Arc::new(network_client());
+
+        // The server answers nothing until it has all three queries, so they have to be in
+        // flight together, and gets their answers back to front
+        let queries = (0..3)
+            .map(|i| {
+                let upstream = upstream.clone();
+                let client = client.clone();
+                thread::spawn(move || {
+                    let qname = format!("host{0}.example.com", i);
+                    let response = client
+                        .send_tls_query(&qname, QueryType::A, &upstream, true)
+                        .unwrap();
+                    assert_eq!(response.questions[0].name, qname);
+                })
+            })
+            .collect::<Vec<_>>();
+        for query in queries {
+            query.join().unwrap();
+        }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tls_upstream_reconnects_after_server_closes() {
+        let (addr, connections) = dot_server(1, true);
+        let upstream = tls_upstream(addr, &[]);
+        let client = network_client();
+
+        for _ in 0..3 {
+            let response = client
+                .send_tls_query("www.example.com", QueryType::A, &upstream, true)
+                .unwrap();
+            assert_eq!(response.answers.len(), 1);
+        }
+
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn tls_upstream_pin_mismatch_is_an_error() {
+        let (addr, _) = dot_server(1, false);
+        // A well-formed pin which matches no key
+        let upstream = tls_upstream(addr, &[base64::encode([0; 32])]);
+        let client = network_client();
+
+        match client.send_tls_query("www.example.com", QueryType::A, &upstream, true) {
+            Err(DnsError::Io(e)) => assert!(e.to_string().contains("pinned key"), "{0}", e),
+            _ => panic!("Expected the connection to fail"),
+        }
+    }
+}
@@ -1,53 +1,189 @@
+// Record and rcode names mirror their RFC mnemonics
+#![allow(clippy::upper_case_acronyms)]
+
 use super::buffer::*;
+use super::error::{DnsError, Result};
 use rand::random;
-use std::io::Result;
+use std::cmp;
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-// DNS response code
+// Longest character-string, such as one of the strings of a TXT record
+pub const MAX_CHARACTER_STRING: usize = 255;
+
+// The DNSSEC OK flag in an OPT record, asking for signatures to be included (RFC 3225)
+pub const EDNS_FLAG_DO: u16 = 0x8000;
+
+// DNS response code. Codes above 15 only fit in a message carrying an EDNS(0) OPT record,
+// which holds their upper eight bits (RFC 6891 section 6.1.3).
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResponseCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
+    UNKNOWN(u16),
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    DSOTYPENI,
+    BADVERS,
+    BADKEY,
+    BADTIME,
+    BADMODE,
+    BADNAME,
+    BADALG,
+    BADTRUNC,
+    BADCOOKIE,
 }
 
 impl ResponseCode {
-    pub fn from_num(num: u8) -> ResponseCode {
+    pub fn to_num(self) -> u16 {
+        match self {
+            ResponseCode::UNKNOWN(n) => n,
+            ResponseCode::NOERROR => 0,
+            ResponseCode::FORMERR => 1,
+            ResponseCode::SERVFAIL => 2,
+            ResponseCode::NXDOMAIN => 3,
+            ResponseCode::NOTIMP => 4,
+            ResponseCode::REFUSED => 5,
+            ResponseCode::YXDOMAIN => 6,
+            ResponseCode::YXRRSET => 7,
+            ResponseCode::NXRRSET => 8,
+            ResponseCode::NOTAUTH => 9,
+            ResponseCode::NOTZONE => 10,
+            ResponseCode::DSOTYPENI => 11,
+            ResponseCode::BADVERS => 16,
+            ResponseCode::BADKEY => 17,
+            ResponseCode::BADTIME => 18,
+            ResponseCode::BADMODE => 19,
+            ResponseCode::BADNAME => 20,
+            ResponseCode::BADALG => 21,
+            ResponseCode::BADTRUNC => 22,
+            ResponseCode::BADCOOKIE => 23,
+        }
+    }
+
+    pub fn from_num(num: u16) -> ResponseCode {
         match num {
+            0 => ResponseCode::NOERROR,
             1 => ResponseCode::FORMERR,
             2 => ResponseCode::SERVFAIL,
             3 => ResponseCode::NXDOMAIN,
             4 => ResponseCode::NOTIMP,
             5 => ResponseCode::REFUSED,
-            _ => ResponseCode::NOERROR,
+            6 => ResponseCode::YXDOMAIN,
+            7 => ResponseCode::YXRRSET,
+            8 => ResponseCode::NXRRSET,
+            9 => ResponseCode::NOTAUTH,
+            10 => ResponseCode::NOTZONE,
+            11 => ResponseCode::DSOTYPENI,
+            16 => ResponseCode::BADVERS,
+            17 => ResponseCode::BADKEY,
+            18 => ResponseCode::BADTIME,
+            19 => ResponseCode::BADMODE,
+            20 => ResponseCode::BADNAME,
+            21 => ResponseCode::BADALG,
+            22 => ResponseCode::BADTRUNC,
+            23 => ResponseCode::BADCOOKIE,
+            _ => ResponseCode::UNKNOWN(num),
+        }
+    }
+}
+
+// Kind of request a message makes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    UNKNOWN(u8),
+    QUERY,
+    IQUERY,
+    STATUS,
+    NOTIFY,
+    UPDATE,
+    DSO,
+}
+
+impl Opcode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            Opcode::UNKNOWN(n) => n,
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::DSO => 6,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            6 => Opcode::DSO,
+            _ => Opcode::UNKNOWN(num),
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
     AAAA,
     TXT,
+    SRV,
+    OPT,
+    DS,
+    SSHFP,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    NSEC3PARAM,
+    TLSA,
+    SVCB,
+    HTTPS,
+    CAA,
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(n) => n,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
             QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::SSHFP => 44,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::NSEC3PARAM => 51,
+            QueryType::TLSA => 52,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
+            QueryType::CAA => 257,
         }
     }
 
@@ -56,12 +192,70 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
             16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            44 => QueryType::SSHFP,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            51 => QueryType::NSEC3PARAM,
+            52 => QueryType::TLSA,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
+
+    // Map a presentation-format type mnemonic, such as `MX`, to its query type. The generic
+    // `TYPEnnn` form of RFC 3597 section 5 works for any type, known or not.
+    pub fn from_name(name: &str) -> Option<QueryType> {
+        let name = name.to_uppercase();
+        if let Some(num) = name.strip_prefix("TYPE") {
+            return num.parse::<u16>().ok().map(QueryType::from_num);
+        }
+
+        match name.as_str() {
+            "A" => Some(QueryType::A),
+            "NS" => Some(QueryType::NS),
+            "CNAME" => Some(QueryType::CNAME),
+            "SOA" => Some(QueryType::SOA),
+            "PTR" => Some(QueryType::PTR),
+            "MX" => Some(QueryType::MX),
+            "TXT" => Some(QueryType::TXT),
+            "AAAA" => Some(QueryType::AAAA),
+            "SRV" => Some(QueryType::SRV),
+            "DS" => Some(QueryType::DS),
+            "SSHFP" => Some(QueryType::SSHFP),
+            "RRSIG" => Some(QueryType::RRSIG),
+            "NSEC" => Some(QueryType::NSEC),
+            "DNSKEY" => Some(QueryType::DNSKEY),
+            "NSEC3" => Some(QueryType::NSEC3),
+            "NSEC3PARAM" => Some(QueryType::NSEC3PARAM),
+            "TLSA" => Some(QueryType::TLSA),
+            "SVCB" => Some(QueryType::SVCB),
+            "HTTPS" => Some(QueryType::HTTPS),
+            "CAA" => Some(QueryType::CAA),
+            _ => None,
+        }
+    }
+}
+
+// Type mnemonics, with the TYPEnnn form for types we don't know (RFC 3597 section 5)
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{0}", num),
+            qtype => write!(f, "{0:?}", qtype),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -71,7 +265,7 @@ pub struct DnsHeader {
     pub recursion_desired: bool,
     pub truncated_message: bool,
     pub authoritative_answer: bool,
-    pub opcode: u8,
+    pub opcode: Opcode,
     pub response: bool,
 
     pub rescode: ResponseCode,
@@ -94,7 +288,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode: 0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResponseCode::NOERROR,
@@ -120,7 +314,8 @@ impl DnsHeader {
         |QR|  Op|AA|TC|RD|RA|   z| Rcode|
         */
         let flags = buffer.read_u16()?;
-        self.rescode = ResponseCode::from_num((flags & 0xF) as u8);
+        // Only the lower four bits of the rcode; the rest come from any OPT record
+        self.rescode = ResponseCode::from_num(flags & 0xF);
         self.checking_disabled = (flags >> 4) & 1 > 0;
         self.authed_data = (flags >> 5) & 1 > 0;
         self.z = (flags >> 6) & 1 > 0;
@@ -128,7 +323,7 @@ impl DnsHeader {
         self.recursion_desired = (flags >> 8) & 1 > 0;
         self.truncated_message = (flags >> 9) & 1 > 0;
         self.authoritative_answer = (flags >> 10) & 1 > 0;
-        self.opcode = ((flags >> 11) & 0xF) as u8;
+        self.opcode = Opcode::from_num(((flags >> 11) & 0xF) as u8);
         self.response = (flags >> 15) & 1 > 0;
 
         // Read record count sections; each field is 16 bits
@@ -149,7 +344,7 @@ impl DnsHeader {
         // Write first byte's-worth of flags
         buffer.write(
             ((self.response as u8) << 7)
-                | (self.opcode << 6)
+                | ((self.opcode.to_num() & 0xF) << 3)
                 | ((self.authoritative_answer as u8) << 2)
                 | ((self.truncated_message as u8) << 1)
                 | (self.recursion_desired as u8),
@@ -161,7 +356,7 @@ impl DnsHeader {
                 | ((self.z as u8) << 6)
                 | ((self.authed_data as u8) << 5)
                 | ((self.checking_disabled as u8) << 4)
-                | (self.rescode as u8),
+                | ((self.rescode.to_num() & 0xF) as u8),
         )?;
 
         // Write record counts
@@ -196,10 +391,14 @@ impl DnsQuestion {
         Ok(())
     }
 
-    pub fn write<T: ByteBuffer>(&self, buffer: &mut T) -> Result<usize> {
+    pub fn write<T: ByteBuffer>(
+        &self,
+        buffer: &mut T,
+        names: &mut CompressionTable,
+    ) -> Result<usize> {
         let start_pos = buffer.head();
 
-        buffer.write_qname(&self.name)?;
+        buffer.write_compressed_qname(&self.name, names)?;
 
         let qtype = self.qtype.to_num();
         buffer.write_u16(qtype)?;
@@ -212,10 +411,12 @@ impl DnsQuestion {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub enum DnsRecord {
+    // A type we don't model, kept as opaque RDATA so it can be passed on unchanged (RFC 3597)
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        class: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
     A {
@@ -233,6 +434,22 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     },
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
     MX {
         domain: String,
         priority: u16,
@@ -241,7 +458,8 @@ pub enum DnsRecord {
     },
     TXT {
         domain: String,
-        txt_data: String,
+        // Each character-string, as raw bytes; they needn't be UTF-8
+        txt_data: Vec<Vec<u8>>,
         ttl: u32,
     },
     AAAA {
@@ -249,18 +467,557 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     },
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    },
+    SSHFP {
+        domain: String,
+        algorithm: u8,
+        fp_type: u8,
+        fingerprint: Vec<u8>,
+        ttl: u32,
+    },
+    TLSA {
+        domain: String,
+        cert_usage: u8,
+        selector: u8,
+        matching_type: u8,
+        cert_data: Vec<u8>,
+        ttl: u32,
+    },
+    // Service binding (RFC 9460); a priority of 0 makes it an alias for the target
+    SVCB {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    },
+    // SVCB for HTTPS origins, with the same layout
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    },
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    },
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        // Validity period, in seconds since the epoch
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    },
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    },
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        ttl: u32,
+    },
+    // EDNS(0) pseudo-record (RFC 6891); the class and TTL fields are repurposed
+    OPT {
+        udp_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        options: Vec<EdnsOption>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+// A service parameter of an SVCB or HTTPS record (RFC 9460 section 14.3.2)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SvcParam {
+    Mandatory(Vec<u16>),
+    Alpn(Vec<Vec<u8>>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown { key: u16, value: Vec<u8> },
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match *self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Unknown { key, .. } => key,
+        }
+    }
+
+    pub fn key_name(key: u16) -> String {
+        match key {
+            0 => "mandatory".to_string(),
+            1 => "alpn".to_string(),
+            2 => "no-default-alpn".to_string(),
+            3 => "port".to_string(),
+            4 => "ipv4hint".to_string(),
+            5 => "ech".to_string(),
+            6 => "ipv6hint".to_string(),
+            _ => format!("key{0}", key),
+        }
+    }
+
+    pub fn key_from_name(name: &str) -> Option<u16> {
+        match name {
+            "mandatory" => Some(0),
+            "alpn" => Some(1),
+            "no-default-alpn" => Some(2),
+            "port" => Some(3),
+            "ipv4hint" => Some(4),
+            "ech" => Some(5),
+            "ipv6hint" => Some(6),
+            _ => name.strip_prefix("key")?.parse::<u16>().ok(),
+        }
+    }
+
+    fn read<T: ByteBuffer>(buffer: &mut T) -> Result<SvcParam> {
+        let key = buffer.read_u16()?;
+        let len = buffer.read_u16()? as usize;
+        let end = buffer.head() + len;
+
+        let param = match key {
+            0 if len % 2 == 0 => {
+                let mut keys = Vec::with_capacity(len / 2);
+                while buffer.head() < end {
+                    keys.push(buffer.read_u16()?);
+                }
+                SvcParam::Mandatory(keys)
+            }
+            1 => {
+                let mut ids = Vec::new();
+                while buffer.head() < end {
+                    let id_len = buffer.read()? as usize;
+                    ids.push(read_bytes(buffer, id_len)?);
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 if len == 0 => SvcParam::NoDefaultAlpn,
+            3 if len == 2 => SvcParam::Port(buffer.read_u16()?),
+            4 if len % 4 == 0 => {
+                let mut addrs = Vec::with_capacity(len / 4);
+                while buffer.head() < end {
+                    addrs.push(Ipv4Addr::from(buffer.read_u32()?));
+                }
+                SvcParam::Ipv4Hint(addrs)
+            }
+            5 => SvcParam::Ech(read_bytes(buffer, len)?),
+            6 if len % 16 == 0 => {
+                let mut addrs = Vec::with_capacity(len / 16);
+                while buffer.head() < end {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&read_bytes(buffer, 16)?);
+                    addrs.push(Ipv6Addr::from(octets));
+                }
+                SvcParam::Ipv6Hint(addrs)
+            }
+            0 | 2 | 3 | 4 | 6 => {
+                return Err(DnsError::BadRecord(format!(
+                    "Invalid length {0} for SvcParam {1}",
+                    len,
+                    SvcParam::key_name(key)
+                )))
+            }
+            _ => SvcParam::Unknown {
+                key,
+                value: read_bytes(buffer, len)?,
+            },
+        };
+
+        if buffer.head() != end {
+            return Err(DnsError::BadRecord(format!(
+                "SvcParam {0} overruns its length of {1}",
+                SvcParam::key_name(key),
+                len
+            )));
+        }
+
+        Ok(param)
+    }
+
+    fn write<T: ByteBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u16(self.key())?;
+
+        // Preserve position to rewrite size of value later
+        let pos = buffer.head();
+        buffer.write_u16(0)?;
+
+        match *self {
+            SvcParam::Mandatory(ref keys) => {
+                for key in keys {
+                    buffer.write_u16(*key)?;
+                }
+            }
+            SvcParam::Alpn(ref ids) => {
+                for id in ids {
+                    if id.len() > MAX_CHARACTER_STRING {
+                        return Err(DnsError::BadRecord("ALPN ID is too long".to_string()));
+                    }
+                    buffer.write(id.len() as u8)?;
+                    write_bytes(buffer, id)?;
+                }
+            }
+            SvcParam::NoDefaultAlpn => {}
+            SvcParam::Port(port) => buffer.write_u16(port)?,
+            SvcParam::Ipv4Hint(ref addrs) => {
+                for addr in addrs {
+                    write_bytes(buffer, &addr.octets())?;
+                }
+            }
+            SvcParam::Ipv6Hint(ref addrs) => {
+                for addr in addrs {
+                    write_bytes(buffer, &addr.octets())?;
+                }
+            }
+            SvcParam::Ech(ref value) | SvcParam::Unknown { ref value, .. } => {
+                write_bytes(buffer, value)?
+            }
+        }
+
+        // Rewrite size of value
+        let size = buffer.head() - (pos + 2); // 2 bytes for value length
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(())
+    }
+}
+
+// Presentation format, as in `alpn="h2,h3"`
+impl fmt::Display for SvcParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn join<T: ToString>(items: &[T]) -> String {
+            items
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        }
+
+        let name = SvcParam::key_name(self.key());
+        match *self {
+            SvcParam::Mandatory(ref keys) => {
+                let names = keys
+                    .iter()
+                    .map(|key| SvcParam::key_name(*key))
+                    .collect::<Vec<String>>();
+                write!(f, "{0}={1}", name, names.join(","))
+            }
+            SvcParam::Alpn(ref ids) => {
+                let ids = ids.join(&b',');
+                write!(f, "{0}={1}", name, quote_string(&ids))
+            }
+            SvcParam::NoDefaultAlpn => write!(f, "{0}", name),
+            SvcParam::Port(port) => write!(f, "{0}={1}", name, port),
+            SvcParam::Ipv4Hint(ref addrs) => write!(f, "{0}={1}", name, join(addrs)),
+            SvcParam::Ech(ref value) => write!(f, "{0}={1}", name, base64::encode(value)),
+            SvcParam::Ipv6Hint(ref addrs) => write!(f, "{0}={1}", name, join(addrs)),
+            SvcParam::Unknown { ref value, .. } => write!(f, "{0}={1}", name, quote_string(value)),
+        }
+    }
+}
+
+fn read_bytes<T: ByteBuffer>(buffer: &mut T, len: usize) -> Result<Vec<u8>> {
+    let bytes = buffer.get_range(buffer.head(), len)?.to_vec();
+    buffer.step(len)?;
+
+    Ok(bytes)
+}
+
+// Everything left of a record's data, such as a TLSA record's certificate data
+fn read_rest<T: ByteBuffer>(buffer: &mut T, end: usize) -> Result<Vec<u8>> {
+    let len = end
+        .checked_sub(buffer.head())
+        .ok_or_else(|| DnsError::BadRecord("Record data overruns its length".to_string()))?;
+
+    read_bytes(buffer, len)
+}
+
+fn write_bytes<T: ByteBuffer>(buffer: &mut T, bytes: &[u8]) -> Result<()> {
+    for byte in bytes {
+        buffer.write(*byte)?;
+    }
+
+    Ok(())
+}
+
+// Read the bitmap of types ending an NSEC or NSEC3 record (RFC 4034 section 4.1.2)
+fn read_type_bitmap<T: ByteBuffer>(buffer: &mut T, end: usize) -> Result<Vec<QueryType>> {
+    let mut types = Vec::new();
+    while buffer.head() < end {
+        let window = buffer.read()? as u16;
+        let len = buffer.read()? as usize;
+        if len == 0 || len > 32 {
+            return Err(DnsError::BadRecord(format!(
+                "Invalid type bitmap length {0}",
+                len
+            )));
+        }
+
+        for (idx, byte) in read_bytes(buffer, len)?.into_iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let num = (window << 8) | (idx as u16 * 8 + bit);
+                    types.push(QueryType::from_num(num));
+                }
+            }
+        }
+    }
+
+    Ok(types)
+}
+
+// Write a type bitmap, with a block for each window of 256 types holding just enough bytes
+// for the highest type present in it
+fn write_type_bitmap<T: ByteBuffer>(buffer: &mut T, types: &[QueryType]) -> Result<()> {
+    let mut nums = types
+        .iter()
+        .map(|qtype| qtype.to_num())
+        .collect::<Vec<u16>>();
+    nums.sort_unstable();
+    nums.dedup();
+
+    let mut start = 0;
+    while start < nums.len() {
+        let window = nums[start] >> 8;
+        let end = nums[start..]
+            .iter()
+            .position(|num| num >> 8 != window)
+            .map_or(nums.len(), |idx| start + idx);
+
+        let mut bitmap = vec![0; (nums[end - 1] & 0xFF) as usize / 8 + 1];
+        for num in &nums[start..end] {
+            let low = (num & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+
+        buffer.write(window as u8)?;
+        buffer.write(bitmap.len() as u8)?;
+        write_bytes(buffer, &bitmap)?;
+        start = end;
+    }
+
+    Ok(())
 }
 
 impl DnsRecord {
+    // An OPT record advertising the UDP payload size we can receive, and whether we want
+    // DNSSEC records in return
+    pub fn new_opt(udp_size: u16, dnssec_ok: bool) -> DnsRecord {
+        DnsRecord::OPT {
+            udp_size,
+            extended_rcode: 0,
+            version: 0,
+            flags: if dnssec_ok { EDNS_FLAG_DO } else { 0 },
+            options: Vec::new(),
+        }
+    }
+
+    pub fn get_domain(&self) -> &str {
+        match *self {
+            DnsRecord::UNKNOWN { ref domain, .. }
+            | DnsRecord::A { ref domain, .. }
+            | DnsRecord::NS { ref domain, .. }
+            | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::SOA { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
+            | DnsRecord::MX { ref domain, .. }
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::SRV { ref domain, .. }
+            | DnsRecord::SSHFP { ref domain, .. }
+            | DnsRecord::TLSA { ref domain, .. }
+            | DnsRecord::SVCB { ref domain, .. }
+            | DnsRecord::HTTPS { ref domain, .. }
+            | DnsRecord::CAA { ref domain, .. }
+            | DnsRecord::DNSKEY { ref domain, .. }
+            | DnsRecord::DS { ref domain, .. }
+            | DnsRecord::RRSIG { ref domain, .. }
+            | DnsRecord::NSEC { ref domain, .. }
+            | DnsRecord::NSEC3 { ref domain, .. }
+            | DnsRecord::NSEC3PARAM { ref domain, .. } => domain,
+            // OPT is always owned by the root domain
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    pub fn get_querytype(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::SSHFP { .. } => QueryType::SSHFP,
+            DnsRecord::TLSA { .. } => QueryType::TLSA,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+        }
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::SSHFP { ttl, .. }
+            | DnsRecord::TLSA { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. } => ttl,
+            // OPT's TTL field carries flags rather than a lifetime; it is never cached
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::PTR { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::TXT { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. }
+            | DnsRecord::SRV { ref mut ttl, .. }
+            | DnsRecord::SSHFP { ref mut ttl, .. }
+            | DnsRecord::TLSA { ref mut ttl, .. }
+            | DnsRecord::SVCB { ref mut ttl, .. }
+            | DnsRecord::HTTPS { ref mut ttl, .. }
+            | DnsRecord::CAA { ref mut ttl, .. }
+            | DnsRecord::DNSKEY { ref mut ttl, .. }
+            | DnsRecord::DS { ref mut ttl, .. }
+            | DnsRecord::RRSIG { ref mut ttl, .. }
+            | DnsRecord::NSEC { ref mut ttl, .. }
+            | DnsRecord::NSEC3 { ref mut ttl, .. }
+            | DnsRecord::NSEC3PARAM { ref mut ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
     pub fn read<T: ByteBuffer>(buffer: &mut T) -> Result<DnsRecord> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype = buffer.read_u16()?;
-        let _ = buffer.read_u16()?; // ignore class
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
+        // The RDATA must be exactly as long as it claims to be
+        let data_start = buffer.head();
+        let record = DnsRecord::read_data(buffer, domain, qtype, class, ttl, data_len)?;
+        if buffer.head() != data_start + data_len as usize {
+            return Err(DnsError::BadRecord(format!(
+                "{0:?} data does not match its length of {1}",
+                record.get_querytype(),
+                data_len
+            )));
+        }
+
+        Ok(record)
+    }
+
+    fn read_data<T: ByteBuffer>(
+        buffer: &mut T,
+        domain: String,
+        qtype: u16,
+        class: u16,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord> {
         match QueryType::from_num(qtype) {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -269,7 +1026,7 @@ impl DnsRecord {
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
                 Ok(DnsRecord::A { domain, addr, ttl })
@@ -306,6 +1063,30 @@ impl DnsRecord {
                 buffer.read_qname(&mut host)?;
                 Ok(DnsRecord::NS { domain, host, ttl })
             }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::PTR { domain, host, ttl })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut host = String::new();
@@ -319,67 +1100,275 @@ impl DnsRecord {
                 })
             }
             QueryType::TXT => {
-                let mut txt_buf = Vec::with_capacity(data_len as usize);
-
-                for _ in 0..data_len {
-                    txt_buf.push(buffer.read()?);
+                // The data is a sequence of length-prefixed character-strings
+                let mut txt_data = Vec::new();
+                let end = buffer.head() + data_len as usize;
+                while buffer.head() < end {
+                    let len = buffer.read()? as usize;
+                    txt_data.push(buffer.get_range(buffer.head(), len)?.to_vec());
+                    buffer.step(len)?;
                 }
 
                 Ok(DnsRecord::TXT {
                     domain,
+                    txt_data,
                     ttl,
-                    txt_data: String::from_utf8_lossy(&txt_buf).to_string(),
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
 
-                Ok(DnsRecord::UNKNOWN {
+                Ok(DnsRecord::SRV {
                     domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
                     ttl,
-                    data_len,
-                    qtype,
                 })
             }
-        }
-    }
+            QueryType::SSHFP => {
+                let end = buffer.head() + data_len as usize;
 
-    pub fn write<T: ByteBuffer>(&self, buffer: &mut T) -> Result<usize> {
-        let start_pos = buffer.head();
+                Ok(DnsRecord::SSHFP {
+                    domain,
+                    algorithm: buffer.read()?,
+                    fp_type: buffer.read()?,
+                    fingerprint: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::TLSA => {
+                let end = buffer.head() + data_len as usize;
 
-        match *self {
-            DnsRecord::A {
-                ref domain,
-                addr,
-                ttl,
-            } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?; // class
-                buffer.write_u32(ttl)?;
-                buffer.write_u16(4)?; // A record has 4-byte IP address
+                Ok(DnsRecord::TLSA {
+                    domain,
+                    cert_usage: buffer.read()?,
+                    selector: buffer.read()?,
+                    matching_type: buffer.read()?,
+                    cert_data: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::SVCB | QueryType::HTTPS => {
+                let end = buffer.head() + data_len as usize;
+                let priority = buffer.read_u16()?;
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+                let mut params = Vec::new();
+                while buffer.head() < end {
+                    params.push(SvcParam::read(buffer)?);
+                }
 
-                // Write IP address
-                let octets = addr.octets();
-                for o in octets.iter() {
-                    buffer.write(*o)?;
+                if qtype == QueryType::SVCB.to_num() {
+                    Ok(DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
+                } else {
+                    Ok(DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
                 }
             }
-            DnsRecord::NS {
-                ref domain,
-                ref host,
-                ttl,
-            } => {
-                buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?; // class
-                buffer.write_u32(ttl)?;
+            QueryType::CAA => {
+                let end = buffer.head() + data_len as usize;
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let tag = String::from_utf8_lossy(&read_bytes(buffer, tag_len)?).to_string();
 
-                // Preserve position to rewrite size of data later
-                let pos = buffer.head();
-                buffer.write_u16(0)?;
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let end = buffer.head() + data_len as usize;
 
-                buffer.write_qname(host)?;
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags: buffer.read_u16()?,
+                    protocol: buffer.read()?,
+                    algorithm: buffer.read()?,
+                    public_key: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::DS => {
+                let end = buffer.head() + data_len as usize;
+
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag: buffer.read_u16()?,
+                    algorithm: buffer.read()?,
+                    digest_type: buffer.read()?,
+                    digest: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let end = buffer.head() + data_len as usize;
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer_name = String::new();
+                buffer.read_qname(&mut signer_name)?;
+
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature: read_rest(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let end = buffer.head() + data_len as usize;
+                let mut next_domain = String::new();
+                buffer.read_qname(&mut next_domain)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types: read_type_bitmap(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let end = buffer.head() + data_len as usize;
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+                let salt = read_bytes(buffer, salt_len)?;
+                let hash_len = buffer.read()? as usize;
+                let next_hashed_owner = read_bytes(buffer, hash_len)?;
+
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed_owner,
+                    types: read_type_bitmap(buffer, end)?,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+
+                Ok(DnsRecord::NSEC3PARAM {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt: read_bytes(buffer, salt_len)?,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let mut options = Vec::new();
+                let end = buffer.head() + data_len as usize;
+                while buffer.head() < end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()?;
+                    let data = buffer.get_range(buffer.head(), len as usize)?.to_vec();
+                    buffer.step(len as usize)?;
+
+                    options.push(EdnsOption { code, data });
+                }
+
+                Ok(DnsRecord::OPT {
+                    udp_size: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: ((ttl >> 16) & 0xFF) as u8,
+                    flags: (ttl & 0xFFFF) as u16,
+                    options,
+                })
+            }
+            QueryType::UNKNOWN(_) => {
+                let data = buffer.get_range(buffer.head(), data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
+
+                Ok(DnsRecord::UNKNOWN {
+                    domain,
+                    qtype,
+                    class,
+                    data,
+                    ttl,
+                })
+            }
+        }
+    }
+
+    pub fn write<T: ByteBuffer>(
+        &self,
+        buffer: &mut T,
+        names: &mut CompressionTable,
+    ) -> Result<usize> {
+        let start_pos = buffer.head();
+
+        match *self {
+            DnsRecord::A {
+                ref domain,
+                addr,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::A.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4)?; // A record has 4-byte IP address
+
+                // Write IP address
+                let octets = addr.octets();
+                for o in octets.iter() {
+                    buffer.write(*o)?;
+                }
+            }
+            DnsRecord::NS {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::NS.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_compressed_qname(host, names)?;
 
                 // Rewrite size of name server
                 let size = buffer.head() - (pos + 2); // 2 bytes for data length
@@ -390,7 +1379,7 @@ impl DnsRecord {
                 ref host,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_compressed_qname(domain, names)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
                 buffer.write_u16(1)?; // class
                 buffer.write_u32(ttl)?;
@@ -399,19 +1388,71 @@ impl DnsRecord {
                 let pos = buffer.head();
                 buffer.write_u16(0)?;
 
-                buffer.write_qname(host)?;
+                buffer.write_compressed_qname(host, names)?;
 
                 // Rewrite size of canonical name
                 let size = buffer.head() - (pos + 2); // 2 bytes for data length
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_compressed_qname(m_name, names)?;
+                buffer.write_compressed_qname(r_name, names)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                // Rewrite size of zone data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_compressed_qname(host, names)?;
+
+                // Rewrite size of pointer name
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
                 ref host,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_compressed_qname(domain, names)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
                 buffer.write_u16(1)?; // class
                 buffer.write_u32(ttl)?;
@@ -421,7 +1462,7 @@ impl DnsRecord {
                 buffer.write_u16(0)?;
 
                 buffer.write_u16(priority)?;
-                buffer.write_qname(host)?;
+                buffer.write_compressed_qname(host, names)?;
 
                 // Rewrite size of canonical name
                 let size = buffer.head() - (pos + 2); // 2 bytes for data length
@@ -432,23 +1473,39 @@ impl DnsRecord {
                 ref txt_data,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_compressed_qname(domain, names)?;
                 buffer.write_u16(QueryType::TXT.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(1)?; // class
                 buffer.write_u32(ttl)?;
-                buffer.write_u16(txt_data.len() as u16)?;
 
-                // Write txt data
-                for byte in txt_data.as_bytes() {
-                    buffer.write(*byte)?;
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                // Write each character-string with its length
+                for string in txt_data {
+                    if string.len() > MAX_CHARACTER_STRING {
+                        return Err(DnsError::BadRecord(format!(
+                            "TXT string exceeds maximum length: {0}",
+                            MAX_CHARACTER_STRING
+                        )));
+                    }
+                    buffer.write(string.len() as u8)?;
+                    for byte in string {
+                        buffer.write(*byte)?;
+                    }
                 }
+
+                // Rewrite size of text
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
             }
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
                 ttl,
             } => {
-                buffer.write_qname(domain)?;
+                buffer.write_compressed_qname(domain, names)?;
                 buffer.write_u16(QueryType::AAAA.to_num())?;
                 buffer.write_u16(1)?; // class
                 buffer.write_u32(ttl)?;
@@ -458,8 +1515,317 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Unknown record: {:?}", self);
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                // SRV targets must not be compressed (RFC 2782)
+                buffer.write_qname(host)?;
+
+                // Rewrite size of service data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SSHFP {
+                ref domain,
+                algorithm,
+                fp_type,
+                ref fingerprint,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::SSHFP.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(2 + fingerprint.len() as u16)?;
+
+                buffer.write(algorithm)?;
+                buffer.write(fp_type)?;
+                write_bytes(buffer, fingerprint)?;
+            }
+            DnsRecord::TLSA {
+                ref domain,
+                cert_usage,
+                selector,
+                matching_type,
+                ref cert_data,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::TLSA.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(3 + cert_data.len() as u16)?;
+
+                buffer.write(cert_usage)?;
+                buffer.write(selector)?;
+                buffer.write(matching_type)?;
+                write_bytes(buffer, cert_data)?;
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(self.get_querytype().to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                // Service binding targets must not be compressed (RFC 9460 section 2.2)
+                buffer.write_qname(target)?;
+                for param in params {
+                    param.write(buffer)?;
+                }
+
+                // Rewrite size of service data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(2 + (tag.len() + value.len()) as u16)?;
+
+                buffer.write(flags)?;
+                buffer.write(tag.len() as u8)?;
+                write_bytes(buffer, tag.as_bytes())?;
+                write_bytes(buffer, value)?;
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write(protocol)?;
+                buffer.write(algorithm)?;
+                write_bytes(buffer, public_key)?;
+            }
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write(algorithm)?;
+                buffer.write(digest_type)?;
+                write_bytes(buffer, digest)?;
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write(algorithm)?;
+                buffer.write(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                // Names in DNSSEC records must not be compressed (RFC 4034 section 3.1.7)
+                buffer.write_qname(signer_name)?;
+                write_bytes(buffer, signature)?;
+
+                // Rewrite size of signature data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next_domain,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                // Names in DNSSEC records must not be compressed (RFC 4034 section 4.1.1)
+                buffer.write_qname(next_domain)?;
+                write_type_bitmap(buffer, types)?;
+
+                // Rewrite size of denial data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed_owner,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                buffer.write(hash_algorithm)?;
+                buffer.write(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write(salt.len() as u8)?;
+                write_bytes(buffer, salt)?;
+                buffer.write(next_hashed_owner.len() as u8)?;
+                write_bytes(buffer, next_hashed_owner)?;
+                write_type_bitmap(buffer, types)?;
+
+                // Rewrite size of denial data
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC3PARAM {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(QueryType::NSEC3PARAM.to_num())?;
+                buffer.write_u16(1)?; // class
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(5 + salt.len() as u16)?;
+
+                buffer.write(hash_algorithm)?;
+                buffer.write(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write(salt.len() as u8)?;
+                write_bytes(buffer, salt)?;
+            }
+            DnsRecord::OPT {
+                udp_size,
+                extended_rcode,
+                version,
+                flags,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(udp_size)?; // class carries the payload size
+                buffer.write_u32(
+                    ((extended_rcode as u32) << 24) | ((version as u32) << 16) | flags as u32,
+                )?;
+
+                // Preserve position to rewrite size of data later
+                let pos = buffer.head();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    buffer.write_u16(option.code)?;
+                    buffer.write_u16(option.data.len() as u16)?;
+                    for byte in &option.data {
+                        buffer.write(*byte)?;
+                    }
+                }
+
+                // Rewrite size of options
+                let size = buffer.head() - (pos + 2); // 2 bytes for data length
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                class,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_compressed_qname(domain, names)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for byte in data {
+                    buffer.write(*byte)?;
+                }
             }
         }
 
@@ -467,6 +1833,349 @@ impl DnsRecord {
     }
 }
 
+// Names in presentation format are fully qualified, so end with a dot
+pub fn fqdn(name: &str) -> String {
+    format!("{0}.", name)
+}
+
+// Upper case hex, as dig prints fingerprints and certificate data
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{0:02X}", b)).collect()
+}
+
+// NSEC3 hashes are written in base32 with the extended hex alphabet, unpadded (RFC 5155 section 3.3)
+pub fn to_base32hex(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut bits = 0;
+    let mut value: u32 = 0;
+    for &byte in bytes {
+        value = (value << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(digit32((value >> bits) & 0x1F));
+        }
+        value &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(digit32(value << (5 - bits)));
+    }
+
+    encoded
+}
+
+fn digit32(value: u32) -> char {
+    std::char::from_digit(value, 32)
+        .map(|c| c.to_ascii_uppercase())
+        .unwrap_or('0')
+}
+
+// An empty NSEC3 salt is written as a dash
+fn format_salt(salt: &[u8]) -> String {
+    if salt.is_empty() {
+        "-".to_string()
+    } else {
+        to_hex(salt)
+    }
+}
+
+// The types listed in an NSEC or NSEC3 type bitmap, each preceded by a space
+fn format_types(types: &[QueryType]) -> String {
+    types.iter().map(|qtype| format!(" {0}", qtype)).collect()
+}
+
+// RRSIG validity times are shown as YYYYMMDDHHmmSS in UTC (RFC 4034 section 3.2)
+fn format_timestamp(timestamp: u32) -> String {
+    let secs = timestamp % 86400;
+
+    // Civil date from days since the epoch, per http://howardhinnant.github.io/date_algorithms.html
+    let days = (timestamp / 86400) as u64 + 719_468;
+    let era = days / 146_097;
+    let doe = days % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{0:04}{1:02}{2:02}{3:02}{4:02}{5:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Quote a character-string the way dig does, escaping anything unprintable as \DDD
+fn quote_string(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            0x20..=0x7E => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{0:03}", byte)),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+// Records in master file presentation format, as dig shows them
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // OPT isn't a real record, so dig shows it as a comment
+        if let DnsRecord::OPT {
+            udp_size,
+            version,
+            flags,
+            ..
+        } = *self
+        {
+            return write!(
+                f,
+                "; EDNS: version: {0}, flags: {1:#06x}; udp: {2}",
+                version, flags, udp_size
+            );
+        }
+
+        write!(f, "{0}\t{1}\t", fqdn(self.get_domain()), self.get_ttl())?;
+        match *self {
+            DnsRecord::UNKNOWN {
+                qtype,
+                class,
+                ref data,
+                ..
+            } => {
+                // RFC 3597 section 5 generic form
+                let hex = data
+                    .iter()
+                    .map(|b| format!("{0:02x}", b))
+                    .collect::<String>();
+                let class = match class {
+                    1 => "IN".to_string(),
+                    other => format!("CLASS{0}", other),
+                };
+                write!(
+                    f,
+                    "{0}\tTYPE{1}\t\\# {2} {3}",
+                    class,
+                    qtype,
+                    data.len(),
+                    hex
+                )
+            }
+            DnsRecord::A { addr, .. } => write!(f, "IN\tA\t{0}", addr),
+            DnsRecord::NS { ref host, .. } => write!(f, "IN\tNS\t{0}", fqdn(host)),
+            DnsRecord::CNAME { ref host, .. } => write!(f, "IN\tCNAME\t{0}", fqdn(host)),
+            DnsRecord::SOA {
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "IN\tSOA\t{0} {1} {2} {3} {4} {5} {6}",
+                fqdn(m_name),
+                fqdn(r_name),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::PTR { ref host, .. } => write!(f, "IN\tPTR\t{0}", fqdn(host)),
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => write!(f, "IN\tMX\t{0} {1}", priority, fqdn(host)),
+            DnsRecord::TXT { ref txt_data, .. } => {
+                let strings = txt_data
+                    .iter()
+                    .map(|string| quote_string(string))
+                    .collect::<Vec<String>>();
+                write!(f, "IN\tTXT\t{0}", strings.join(" "))
+            }
+            DnsRecord::AAAA { addr, .. } => write!(f, "IN\tAAAA\t{0}", addr),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                ref host,
+                ..
+            } => write!(
+                f,
+                "IN\tSRV\t{0} {1} {2} {3}",
+                priority,
+                weight,
+                port,
+                fqdn(host)
+            ),
+            DnsRecord::SSHFP {
+                algorithm,
+                fp_type,
+                ref fingerprint,
+                ..
+            } => write!(
+                f,
+                "IN\tSSHFP\t{0} {1} {2}",
+                algorithm,
+                fp_type,
+                to_hex(fingerprint)
+            ),
+            DnsRecord::TLSA {
+                cert_usage,
+                selector,
+                matching_type,
+                ref cert_data,
+                ..
+            } => write!(
+                f,
+                "IN\tTLSA\t{0} {1} {2} {3}",
+                cert_usage,
+                selector,
+                matching_type,
+                to_hex(cert_data)
+            ),
+            DnsRecord::SVCB {
+                priority,
+                ref target,
+                ref params,
+                ..
+            }
+            | DnsRecord::HTTPS {
+                priority,
+                ref target,
+                ref params,
+                ..
+            } => {
+                write!(
+                    f,
+                    "IN\t{0:?}\t{1} {2}",
+                    self.get_querytype(),
+                    priority,
+                    fqdn(target)
+                )?;
+                for param in params {
+                    write!(f, " {0}", param)?;
+                }
+                Ok(())
+            }
+            DnsRecord::CAA {
+                flags,
+                ref tag,
+                ref value,
+                ..
+            } => write!(f, "IN\tCAA\t{0} {1} {2}", flags, tag, quote_string(value)),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => write!(
+                f,
+                "IN\tDNSKEY\t{0} {1} {2} {3}",
+                flags,
+                protocol,
+                algorithm,
+                base64::encode(public_key)
+            ),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => write!(
+                f,
+                "IN\tDS\t{0} {1} {2} {3}",
+                key_tag,
+                algorithm,
+                digest_type,
+                to_hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => write!(
+                f,
+                "IN\tRRSIG\t{0} {1} {2} {3} {4} {5} {6} {7} {8}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                format_timestamp(expiration),
+                format_timestamp(inception),
+                key_tag,
+                fqdn(signer_name),
+                base64::encode(signature)
+            ),
+            DnsRecord::NSEC {
+                ref next_domain,
+                ref types,
+                ..
+            } => write!(
+                f,
+                "IN\tNSEC\t{0}{1}",
+                fqdn(next_domain),
+                format_types(types)
+            ),
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed_owner,
+                ref types,
+                ..
+            } => write!(
+                f,
+                "IN\tNSEC3\t{0} {1} {2} {3} {4}{5}",
+                hash_algorithm,
+                flags,
+                iterations,
+                format_salt(salt),
+                to_base32hex(next_hashed_owner),
+                format_types(types)
+            ),
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ..
+            } => write!(
+                f,
+                "IN\tNSEC3PARAM\t{0} {1} {2} {3}",
+                hash_algorithm,
+                flags,
+                iterations,
+                format_salt(salt)
+            ),
+            DnsRecord::OPT { .. } => Ok(()),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -518,44 +2227,86 @@ impl DnsPacket {
             result.resources.push(resource);
         }
 
+        // An OPT record carries the upper eight bits of a 12-bit rcode
+        if let Some(DnsRecord::OPT { extended_rcode, .. }) = result.get_edns() {
+            let rcode = ((*extended_rcode as u16) << 4) | result.header.rescode.to_num();
+            result.header.rescode = ResponseCode::from_num(rcode);
+        }
+
         Ok(result)
     }
 
     pub fn write<T: ByteBuffer>(&mut self, buffer: &mut T) -> Result<usize> {
         let start_pos = buffer.head();
-
-        // Setup temporary buffer in case this message gets truncated
-        let mut temp_buf = ExtendingBuffer::new();
         let max_size = buffer.max_size();
-        let mut size = 0;
 
-        // We should have enough space so far to write the header and questions
+        // Build the message in a temporary buffer in case it gets truncated. Compression
+        // pointers are offsets from the start of the message, which is offset 0 here.
+        let mut msg = ExtendingBuffer::new();
+        let mut names = CompressionTable::new();
+
+        // The upper bits of the rcode go in the OPT record, if there is one
+        let upper_rcode = (self.header.rescode.to_num() >> 4) as u8;
+        for rec in self.resources.iter_mut() {
+            if let DnsRecord::OPT {
+                ref mut extended_rcode,
+                ..
+            } = *rec
+            {
+                *extended_rcode = upper_rcode;
+            }
+        }
+
+        // Record counts are recalculated below, based on what actually fits
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = 0;
+        self.header.authoritative_entries = 0;
+        self.header.resource_entries = 0;
+
+        // The header is rewritten once the counts are known; it's written here as a placeholder
+        // so the names that follow are at their final offsets
+        let header_len = self.header.write(&mut msg)?;
 
-        size += self.header.write(&mut temp_buf)?;
+        // We should have enough space so far to write the header and questions
         for question in &self.questions {
-            size += question.write(&mut temp_buf)?;
+            question.write(&mut msg, &mut names)?;
+        }
+
+        // The OPT pseudo-record has to survive truncation, so set aside room for it first.
+        // It's owned by the root name, so never compresses.
+        let (opts, resources): (Vec<&DnsRecord>, Vec<&DnsRecord>) = self
+            .resources
+            .iter()
+            .partition(|rec| rec.get_querytype() == QueryType::OPT);
+        let mut opt_size = 0;
+        for opt in &opts {
+            opt_size += opt.write(&mut ExtendingBuffer::new(), &mut CompressionTable::new())?;
         }
 
         // This is where we may run out of space in the buffer... keep an eye out
 
-        let mut record_count = self.answers.len() + self.authorities.len() + self.resources.len();
         for (i, rec) in self
             .answers
             .iter()
             .chain(self.authorities.iter())
-            .chain(self.resources.iter())
+            .chain(resources.iter().cloned())
             .enumerate()
         {
-            size += rec.write(&mut temp_buf)?;
-            if size > max_size {
+            let rec_start = msg.head();
+            rec.write(&mut msg, &mut names)?;
+            if msg.head() + opt_size > max_size {
                 /* We ran out of space!
-                    - Set the record count for the packet to however far we got
-                    - Set the truncated bit in the header
+                    - Drop the record which didn't fit; nothing written after it can point into it
+                    - Set the truncated bit in the header, unless only additional records are
+                      missing, which the client can do without (RFC 2181 section 9)
                     - Stop trying to write to the packed buffer
                 */
-                println!("Truncating message for packet {0}", self.header.id);
-                record_count = i;
-                self.header.truncated_message = true;
+                msg.buf.truncate(rec_start);
+                msg.seek(rec_start)?;
+                if i < self.answers.len() + self.authorities.len() {
+                    println!("Truncating message for packet {0}", self.header.id);
+                    self.header.truncated_message = true;
+                }
                 break;
             } else if i < self.answers.len() {
                 self.header.answers += 1;
@@ -566,26 +2317,45 @@ impl DnsPacket {
             }
         }
 
-        // Now that we know we can write this packet to the buffer, do it for real
+        for opt in &opts {
+            opt.write(&mut msg, &mut names)?;
+        }
+        self.header.resource_entries += opts.len() as u16;
 
-        self.header.questions = self.questions.len() as u16;
+        // Now that we know we can write this packet to the buffer, do it for real
         self.header.write(buffer)?;
-
-        for question in &self.questions {
-            question.write(buffer)?;
+        for byte in &msg.buf[header_len..] {
+            buffer.write(*byte)?;
         }
 
-        for rec in self
-            .answers
+        Ok(buffer.head() - start_pos)
+    }
+
+    // The EDNS(0) OPT pseudo-record, if the sender included one
+    pub fn get_edns(&self) -> Option<&DnsRecord> {
+        self.resources
             .iter()
-            .chain(self.authorities.iter())
-            .chain(self.resources.iter())
-            .take(record_count)
-        {
-            rec.write(buffer)?;
+            .find(|rec| rec.get_querytype() == QueryType::OPT)
+    }
+
+    // Did the sender set the DO bit, asking for DNSSEC records?
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.get_edns(), Some(DnsRecord::OPT { flags, .. }) if flags & EDNS_FLAG_DO != 0)
+    }
+
+    // Largest UDP response the sender can receive; RFC 6891 treats anything below 512 as 512
+    pub fn max_udp_size(&self) -> usize {
+        match self.get_edns() {
+            Some(DnsRecord::OPT { udp_size, .. }) => cmp::max(*udp_size as usize, MAX_UDP_SIZE),
+            _ => MAX_UDP_SIZE,
         }
+    }
 
-        Ok(buffer.head() - start_pos)
+    // Find the SOA record a negative answer is authorized by
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities
+            .iter()
+            .find(|rec| matches!(rec, DnsRecord::SOA { .. }))
     }
 
     // Randomly choose A record from packet
@@ -674,3 +2444,202 @@ impl DnsPacket {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(name: &str) -> Vec<u8> {
+        let mut buffer = ExtendingBuffer::new();
+        buffer.write_qname(name).unwrap();
+        buffer.buf
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    // Write a record after one whose owner puts the name in the compression table, then check
+    // how the name came out and that the record reads back the same
+    fn write_after_owner(rec: &DnsRecord, name: &str) -> Vec<u8> {
+        let mut buffer = ExtendingBuffer::new();
+        let mut names = CompressionTable::new();
+        let owner = DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        };
+        owner.write(&mut buffer, &mut names).unwrap();
+        let start = buffer.head();
+        rec.write(&mut buffer, &mut names).unwrap();
+
+        let mut reader = VariableBuffer::from_bytes(buffer.buf.clone());
+        reader.seek(start).unwrap();
+        assert_eq!(&DnsRecord::read(&mut reader).unwrap(), rec);
+
+        buffer.buf[start..].to_vec()
+    }
+
+    #[test]
+    fn compresses_names_in_rdata_where_allowed() {
+        let rec = DnsRecord::CNAME {
+            domain: "alias.example.com".to_string(),
+            host: "target.example.com".to_string(),
+            ttl: 300,
+        };
+        let written = write_after_owner(&rec, "target.example.com");
+
+        assert!(!contains(&written, &wire("target.example.com")));
+        assert!(written.ends_with(&[0xC0, 0]));
+    }
+
+    #[test]
+    fn leaves_names_in_rdata_uncompressed_where_required() {
+        let target = "target.example.com";
+        let records = vec![
+            DnsRecord::SRV {
+                domain: "_sip._tcp.example.com".to_string(),
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                host: target.to_string(),
+                ttl: 300,
+            },
+            DnsRecord::SVCB {
+                domain: "_dns.example.com".to_string(),
+                priority: 1,
+                target: target.to_string(),
+                params: Vec::new(),
+                ttl: 300,
+            },
+            DnsRecord::HTTPS {
+                domain: "example.com".to_string(),
+                priority: 1,
+                target: target.to_string(),
+                params: Vec::new(),
+                ttl: 300,
+            },
+            DnsRecord::RRSIG {
+                domain: "www.example.com".to_string(),
+                type_covered: QueryType::A,
+                algorithm: 13,
+                labels: 3,
+                original_ttl: 300,
+                expiration: 1_700_003_600,
+                inception: 1_700_000_000,
+                key_tag: 12345,
+                signer_name: target.to_string(),
+                signature: vec![0xAB; 64],
+                ttl: 300,
+            },
+            DnsRecord::NSEC {
+                domain: "www.example.com".to_string(),
+                next_domain: target.to_string(),
+                types: vec![QueryType::A, QueryType::RRSIG, QueryType::NSEC],
+                ttl: 300,
+            },
+        ];
+
+        for rec in &records {
+            let written = write_after_owner(rec, target);
+            assert!(
+                contains(&written, &wire(target)),
+                "{:?} compressed its name",
+                rec.get_querytype()
+            );
+        }
+    }
+
+    #[test]
+    fn soa_ptr_and_srv_round_trip() {
+        let records = vec![
+            DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 2_024_010_101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1_209_600,
+                minimum: 300,
+                ttl: 3600,
+            },
+            DnsRecord::PTR {
+                domain: "1.2.0.192.in-addr.arpa".to_string(),
+                host: "www.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::SRV {
+                domain: "_ldap._tcp.example.com".to_string(),
+                priority: 0,
+                weight: 100,
+                port: 389,
+                host: "dc.example.com".to_string(),
+                ttl: 300,
+            },
+        ];
+
+        for rec in &records {
+            write_after_owner(rec, "example.com");
+        }
+    }
+
+    // A response with the given number of answers, and that many name server addresses in
+    // the additional section along with an OPT record
+    fn response(answers: usize, additional: usize) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet
+            .questions
+            .push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        for i in 0..answers {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, i as u8),
+                ttl: 300,
+            });
+        }
+        for i in 0..additional {
+            packet.resources.push(DnsRecord::AAAA {
+                domain: format!("ns{0}.example.net", i),
+                addr: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i as u16),
+                ttl: 300,
+            });
+        }
+        packet
+            .resources
+            .push(DnsRecord::new_opt(MAX_UDP_SIZE as u16, false));
+
+        packet
+    }
+
+    fn write_and_read(mut packet: DnsPacket) -> DnsPacket {
+        let mut buffer = BytePacketBuffer::new();
+        let len = packet.write(&mut buffer).unwrap();
+        assert!(len <= MAX_UDP_SIZE);
+
+        buffer.seek(0).unwrap();
+        DnsPacket::from_buffer(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn dropping_additional_records_does_not_set_tc() {
+        let packet = write_and_read(response(2, 40));
+
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.answers.len(), 2);
+        assert!(packet.resources.len() < 41);
+        assert!(packet.get_edns().is_some());
+    }
+
+    #[test]
+    fn dropping_answers_sets_tc() {
+        let packet = write_and_read(response(60, 0));
+
+        assert!(packet.header.truncated_message);
+        assert!(packet.answers.len() < 60);
+        assert!(packet.get_edns().is_some());
+    }
+}
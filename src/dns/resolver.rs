@@ -1,68 +1,109 @@
+use super::config::ResolverConfig;
 use super::context::ServerContext;
+use super::dnssec::Validator;
+use super::error::Result;
 use super::protocol::{DnsPacket, DnsRecord, QueryType, ResponseCode};
-use std::io::Result;
+use super::upstream::UpstreamPool;
+use std::io::{self, Error, ErrorKind};
 use std::sync::Arc;
 
 pub enum ResolverMode {
-    Forwarding { host: String, port: u16 },
+    Forwarding(Arc<UpstreamPool>),
     Recursive,
 }
 
 impl ResolverMode {
-    pub fn from_str(name: &str, server: Option<&str>) -> Option<ResolverMode> {
-        match name {
-            "recursive" => Some(ResolverMode::Recursive),
-            "forward" => Some(ResolverMode::Forwarding {
-                host: server.unwrap().to_string(),
-                port: 53,
-            }),
-            _ => None,
+    pub fn from_config(config: &ResolverConfig) -> io::Result<ResolverMode> {
+        match config.mode.as_str() {
+            "recursive" => Ok(ResolverMode::Recursive),
+            "forward" => Ok(ResolverMode::Forwarding(Arc::new(
+                UpstreamPool::from_config(config)?,
+            ))),
+            other => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown resolver mode: {0}", other),
+            )),
         }
     }
 }
 
 pub trait DnsResolver {
-    fn resolve(&self, qname: &str, qtype: QueryType, recursive: bool) -> Result<DnsPacket> {
-        // If query type is unknown, then we haven't implemented it yet
-        if let QueryType::UNKNOWN(_) = qtype {
+    fn resolve(&self, qname: &str, qtype: QueryType, _recursive: bool) -> Result<DnsPacket> {
+        // Zone transfers (IXFR and AXFR) aren't supported; any other type is resolved,
+        // whether or not we understand its records
+        if let QueryType::UNKNOWN(251) | QueryType::UNKNOWN(252) = qtype {
             let mut packet = DnsPacket::new();
             packet.header.rescode = ResponseCode::NOTIMP;
             return Ok(packet);
         }
 
-        // TODO: once implemented, check local authority for record
+        // Names in our local zones are answered authoritatively
+        let context = self.get_context();
+        if let Some(packet) = context.authority.query(qname, qtype) {
+            return Ok(packet);
+        }
 
-        // TODO: once implemented, check cache for record
+        // Answer from the cache if we have a live copy of the record, or know it doesn't exist
+        if let Some(packet) = context.cache.lookup(qname, qtype) {
+            return Ok(packet);
+        }
 
         // Finally, execute resolution using a name server or downstream server
-        self.execute(qname, qtype)
+        let packet = self.execute(qname, qtype)?;
+        context.cache.store(qname, qtype, &packet);
+
+        Ok(packet)
+    }
+
+    // Resolve a client's question. Answers from outside our own zones are checked with DNSSEC,
+    // unless the client has disabled checking (RFC 4035 section 3.2.2), and marked with the AD
+    // bit if they turn out to be secure.
+    fn resolve_checked(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let mut packet = self.resolve(qname, qtype, true)?;
+
+        let context = self.get_context();
+        if !checking_disabled && context.authority.query(qname, qtype).is_none() {
+            packet.header.authed_data = self.validate(qname, qtype, &packet)?;
+        }
+
+        Ok(packet)
+    }
+
+    // Whether an answer is secure. Resolvers which don't validate themselves pass on what the
+    // server they asked said.
+    fn validate(&self, _qname: &str, _qtype: QueryType, packet: &DnsPacket) -> Result<bool> {
+        Ok(packet.header.authed_data)
     }
 
+    fn get_context(&self) -> Arc<ServerContext>;
+
     fn execute(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
 pub struct ForwardResolver {
-    server: (String, u16),
+    upstreams: Arc<UpstreamPool>,
     context: Arc<ServerContext>,
 }
 
 impl ForwardResolver {
-    pub fn new(server: (String, u16), context: Arc<ServerContext>) -> ForwardResolver {
-        ForwardResolver { server, context }
+    pub fn new(upstreams: Arc<UpstreamPool>, context: Arc<ServerContext>) -> ForwardResolver {
+        ForwardResolver { upstreams, context }
     }
 }
 
 impl DnsResolver for ForwardResolver {
-    fn execute(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-        let (ref host, port) = &self.server;
-        let result = self
-            .context
-            .client
-            .send_query(qname, qtype, (host, *port), true);
-
-        // TODO: store the result in the DNS record cache
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
 
-        result
+    fn execute(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.upstreams
+            .send_query(&self.context.client, qname, qtype)
     }
 }
 
@@ -77,6 +118,26 @@ impl RecursiveResolver {
 }
 
 impl DnsResolver for RecursiveResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
+
+    // Build the chain of trust from our trust anchors, fetching the DS and DNSKEY records
+    // along the way just like any other lookup
+    fn validate(&self, qname: &str, qtype: QueryType, packet: &DnsPacket) -> Result<bool> {
+        if !self.context.dnssec_validation {
+            return Ok(false);
+        }
+
+        let fetch = |name: &str, qtype: QueryType| self.resolve(name, qtype, true);
+        Validator::new(
+            &self.context.trust_anchors,
+            &self.context.dnssec_keys,
+            fetch,
+        )
+        .validate(qname, qtype, packet)
+    }
+
     fn execute(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
         // For now we're always starting with *a.root-servers.net*.
         let mut ns = "198.41.0.4".to_string();
@@ -89,33 +150,27 @@ impl DnsResolver for RecursiveResolver {
             );
             let ns_copy = ns.clone();
             let server = (ns_copy.as_str(), 53);
-            let mut response =
-                self.context
-                    .client
-                    .send_query(qname, qtype.clone(), server, true)?;
+            let mut response = self.context.client.send_query(qname, qtype, server, true)?;
 
             // If we have answers and no errors or the name server tells us no, done
             if (!response.answers.is_empty() && response.header.rescode == ResponseCode::NOERROR)
                 || response.header.rescode == ResponseCode::NXDOMAIN
             {
-                match qtype {
-                    QueryType::A => {
-                        let mut cname_responses: Vec<DnsRecord> = Vec::new();
-                        for rec in &response.answers {
-                            if let DnsRecord::CNAME { ref host, .. } = *rec {
-                                let cname_resp = self.resolve(&host, QueryType::A, true)?;
-                                println!("Resolved CNAME: {:?}", &host);
-                                response.header.rescode = cname_resp.header.rescode;
-
-                                for a_rec in cname_resp.answers {
-                                    cname_responses.push(a_rec);
-                                    response.header.answers += 1;
-                                }
-                            };
-                        }
-                        response.answers.extend(cname_responses);
+                if qtype == QueryType::A {
+                    let mut cname_responses: Vec<DnsRecord> = Vec::new();
+                    for rec in &response.answers {
+                        if let DnsRecord::CNAME { ref host, .. } = *rec {
+                            let cname_resp = self.resolve(host, QueryType::A, true)?;
+                            println!("Resolved CNAME: {:?}", &host);
+                            response.header.rescode = cname_resp.header.rescode;
+
+                            for a_rec in cname_resp.answers {
+                                cname_responses.push(a_rec);
+                                response.header.answers += 1;
+                            }
+                        };
                     }
-                    _ => {}
+                    response.answers.extend(cname_responses);
                 }
 
                 return Ok(response);
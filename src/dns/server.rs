@@ -1,11 +1,23 @@
 use super::buffer::*;
 use super::context::ServerContext;
+use super::error::{self, DnsError};
+use super::framing::{is_timeout, write_frame, FrameReader};
+use super::http::{self, HttpRequest};
 use super::protocol::*;
+use super::tls;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use rustls::{ServerConfig, ServerConnection};
 use std::boxed::Box;
-use std::io::{Read, Result, Write};
-use std::net::{TcpListener, UdpSocket};
+use std::cmp;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
 
 type Task = Box<dyn FnOnce() + Send + 'static>;
 
@@ -15,7 +27,6 @@ enum Message {
 }
 
 struct Worker {
-    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
@@ -24,9 +35,9 @@ impl Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver
                 .lock()
-                .expect(format!("Worker {0} failed to acquire lock", &id).as_str())
+                .unwrap_or_else(|_| panic!("Worker {0} failed to acquire lock", &id))
                 .recv()
-                .expect(format!("Worker {0} failed to receive task from channel", &id).as_str());
+                .unwrap_or_else(|_| panic!("Worker {0} failed to receive task from channel", &id));
             match message {
                 Message::NewTask(task) => task(),
                 Message::Terminate => break,
@@ -34,7 +45,6 @@ impl Worker {
         });
 
         Worker {
-            id,
             thread: Some(thread),
         }
     }
@@ -89,47 +99,123 @@ impl Drop for Threadpool {
     }
 }
 
+// Signatures and proofs of non-existence are left out of responses to clients which didn't set
+// the DO bit, unless they asked for that type explicitly (RFC 4035 section 3.2.1)
+fn is_dnssec_only(rec: &DnsRecord, qtype: QueryType) -> bool {
+    let rtype = rec.get_querytype();
+    rtype != qtype && matches!(rtype, QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3)
+}
+
 fn execute_query(request: DnsPacket, context: Arc<ServerContext>) -> DnsPacket {
     let context_ptr = context.clone();
     let resolver = context.get_resolver(context_ptr);
     // Prepare response packet
     let mut response = DnsPacket::new();
     response.header.id = request.header.id; // question and answer must have same id
+    response.header.opcode = request.header.opcode;
     response.header.recursion_desired = request.header.recursion_desired;
     response.header.recursion_available = context.allow_recursion;
+    response.header.checking_disabled = request.header.checking_disabled;
     response.header.response = true;
 
-    // If the request has no questions, return a FORMERR
-    if request.questions.is_empty() {
+    // Clients using EDNS(0) get told the payload size we're willing to send in return,
+    // and have their DO bit echoed back (RFC 3225 section 3)
+    let dnssec_ok = request.dnssec_ok();
+    if request.get_edns().is_some() {
+        response
+            .resources
+            .push(DnsRecord::new_opt(context.edns_udp_size, dnssec_ok));
+    }
+
+    // EDNS(0) is the only version there is (RFC 6891 section 6.1.3)
+    let unsupported_edns = matches!(
+        request.get_edns(),
+        Some(DnsRecord::OPT { version, .. }) if *version > 0
+    );
+
+    if request.header.opcode != Opcode::QUERY {
+        // We only answer standard queries, not notifies, updates and the like
+        println!("Unsupported opcode: {:?}", request.header.opcode);
+        response.header.rescode = ResponseCode::NOTIMP;
+    } else if unsupported_edns {
+        response.header.rescode = ResponseCode::BADVERS;
+    } else if request.questions.is_empty() {
+        // If the request has no questions, return a FORMERR
         response.header.rescode = ResponseCode::FORMERR;
     } else {
         let question = &request.questions[0];
         println!("Received query: {:?}", question);
 
         // Now, forward the request to the downstream server
-        if let Ok(result) = resolver.resolve(&question.name, question.qtype, true) {
-            response.questions.push(question.clone());
-            response.header.rescode = result.header.rescode;
-            for rec in result.answers {
-                println!("Answers: {:?}", rec);
-                response.answers.push(rec);
-            }
-            for rec in result.authorities {
-                println!("Authority: {:?}", rec);
-                response.authorities.push(rec);
+        response.questions.push(question.clone());
+        match resolver.resolve_checked(
+            &question.name,
+            question.qtype,
+            request.header.checking_disabled,
+        ) {
+            Ok(result) => {
+                response.header.rescode = result.header.rescode;
+                response.header.authoritative_answer = result.header.authoritative_answer;
+                // Only clients which show they understand DNSSEC are told an answer was
+                // validated (RFC 6840 section 5.7)
+                response.header.authed_data =
+                    result.header.authed_data && (dnssec_ok || request.header.authed_data);
+                let visible = |rec: &DnsRecord| dnssec_ok || !is_dnssec_only(rec, question.qtype);
+                for rec in result.answers.into_iter().filter(visible) {
+                    println!("Answers: {}", rec);
+                    response.answers.push(rec);
+                }
+                for rec in result.authorities.into_iter().filter(visible) {
+                    println!("Authority: {}", rec);
+                    response.authorities.push(rec);
+                }
+                // OPT records are hop-by-hop, so never pass an upstream's on to the client
+                for rec in result.resources.into_iter().filter(visible) {
+                    if rec.get_querytype() == QueryType::OPT {
+                        continue;
+                    }
+                    println!("Resource: {}", rec);
+                    response.resources.push(rec);
+                }
             }
-            for rec in result.resources {
-                println!("Resource: {:?}", rec);
-                response.resources.push(rec);
+            // Pass on a refusal from upstream; anything else, bogus DNSSEC included, means we failed
+            Err(e) => {
+                println!("Failed to resolve {:?}: {}", question, e);
+                response.header.rescode = match e {
+                    DnsError::UpstreamRefused(_) => ResponseCode::REFUSED,
+                    _ => ResponseCode::SERVFAIL,
+                };
             }
-        } else {
-            response.header.rescode = ResponseCode::SERVFAIL;
         }
     }
 
     response
 }
 
+// Answer a request we couldn't parse with FORMERR, as long as its header can be read and
+// it isn't itself a response. Anything else is dropped.
+fn malformed_response<T: ByteBuffer>(req_buffer: &mut T, e: &DnsError) -> Option<DnsPacket> {
+    if !e.is_malformed() {
+        return None;
+    }
+
+    let mut header = DnsHeader::new();
+    req_buffer.seek(0).ok()?;
+    header.read(req_buffer).ok()?;
+    if header.response {
+        return None;
+    }
+
+    let mut response = DnsPacket::new();
+    response.header.id = header.id;
+    response.header.opcode = header.opcode;
+    response.header.recursion_desired = header.recursion_desired;
+    response.header.response = true;
+    response.header.rescode = ResponseCode::FORMERR;
+
+    Some(response)
+}
+
 pub trait DnsServer {
     fn run(&self, thread_count: usize) -> Result<thread::JoinHandle<()>>;
 }
@@ -149,8 +235,8 @@ impl UdpServer {
 impl DnsServer for UdpServer {
     fn run(&self, thread_count: usize) -> Result<thread::JoinHandle<()>> {
         let thread_pool = Threadpool::new(thread_count);
-        let socket = UdpSocket::bind(("0.0.0.0", self.context.dns_port)).unwrap();
-        let socket_ptr = Arc::new(Mutex::new(socket.try_clone().unwrap()));
+        let socket = UdpSocket::bind((self.context.listen_address, self.context.dns_port))?;
+        let socket_ptr = Arc::new(Mutex::new(socket.try_clone()?));
         let context_ptr = self.context.clone();
 
         let udp_thread = thread::Builder::new()
@@ -158,24 +244,36 @@ impl DnsServer for UdpServer {
             .spawn(move || {
                 loop {
                     // Receive a request into a buffer
-                    let mut req_buffer = BytePacketBuffer::new();
+                    let mut req_buffer = BytePacketBuffer::with_size(MAX_EDNS_SIZE);
                     match socket.recv_from(&mut req_buffer.buf) {
-                        Ok((_, raddr)) => {
+                        Ok((len, raddr)) => {
+                            // Anything past the datagram isn't part of the request
+                            req_buffer.buf.truncate(len);
                             let socket_clone = socket_ptr.clone();
                             let context_ptr_clone = context_ptr.clone();
                             thread_pool.execute(move || {
-                                // Read DNS packet from buffer
-                                let request = match DnsPacket::from_buffer(&mut req_buffer) {
-                                    Ok(packet) => packet,
-                                    Err(e) => {
-                                        println!("Failed to parse DNS packet: {:?}", e);
-                                        return;
-                                    }
-                                };
-                                let mut response = execute_query(request, context_ptr_clone);
+                                // Read DNS packet from buffer and execute the query in it
+                                let (mut response, max_size) =
+                                    match DnsPacket::from_buffer(&mut req_buffer) {
+                                        Ok(request) => {
+                                            // Responses must fit the payload size both sides can handle
+                                            let max_size = cmp::min(
+                                                request.max_udp_size(),
+                                                context_ptr_clone.edns_udp_size as usize,
+                                            );
+                                            (execute_query(request, context_ptr_clone), max_size)
+                                        }
+                                        Err(e) => {
+                                            println!("Failed to parse DNS packet: {}", e);
+                                            match malformed_response(&mut req_buffer, &e) {
+                                                Some(response) => (response, MAX_UDP_SIZE),
+                                                None => return,
+                                            }
+                                        }
+                                    };
 
                                 // Finally, write the response to a buffer and return to client
-                                let mut res_buffer = BytePacketBuffer::new();
+                                let mut res_buffer = BytePacketBuffer::with_size(max_size);
                                 match response.write(&mut res_buffer) {
                                     Ok(_) => {}
                                     Err(e) => {
@@ -196,12 +294,10 @@ impl DnsServer for UdpServer {
                                     }
                                 };
 
-                                match socket_clone.lock().unwrap().send_to(res_data, raddr) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!("Failed to send response buffer: {:?}", e);
-                                        return;
-                                    }
+                                if let Err(e) =
+                                    socket_clone.lock().unwrap().send_to(res_data, raddr)
+                                {
+                                    println!("Failed to send response buffer: {:?}", e);
                                 }
                             });
                         }
@@ -219,6 +315,191 @@ impl DnsServer for UdpServer {
 
 // TCP server
 
+// A client connection's place under the server's connection limit, given up when dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A client's TCP connection, shared between its reader and the workers answering its queries.
+// It counts towards the server's connection limit until the last of them lets go of it.
+struct TcpConnection<W: Write> {
+    writer: Mutex<W>,
+    _slot: ConnectionSlot,
+}
+
+impl<W: Write> TcpConnection<W> {
+    fn write_response(&self, response: &mut DnsPacket) -> error::Result<()> {
+        let mut res_buffer = ExtendingBuffer::new();
+        response.write(&mut res_buffer)?;
+
+        let res_len = res_buffer.head();
+        let res_data = res_buffer.get_range(0, res_len)?;
+
+        // Hold the lock for the whole frame so pipelined responses don't interleave
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Failed to acquire TCP connection lock");
+        write_frame(&mut *writer, res_data)?;
+
+        Ok(())
+    }
+
+    fn write_http(&self, status: u16, headers: &[(&str, String)], body: &[u8]) -> Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Failed to acquire TCP connection lock");
+        http::write_response(&mut *writer, status, headers, body)
+    }
+}
+
+// Read pipelined queries off a connection until the client closes it or it goes idle,
+// handing each to the thread pool so responses are sent as soon as they're ready
+fn serve_tcp_connection<R, W>(
+    (reader, writer): (R, W),
+    slot: ConnectionSlot,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+) where
+    R: Read,
+    W: Write + Send + 'static,
+{
+    let connection = Arc::new(TcpConnection {
+        writer: Mutex::new(writer),
+        _slot: slot,
+    });
+    let outstanding = Arc::new(AtomicUsize::new(0));
+
+    // A query has as long to arrive as an idle connection is kept open
+    let mut frames = FrameReader::new(reader);
+    frames.set_message_timeout(context.tcp_timeout);
+
+    loop {
+        let frame = match frames.read_frame() {
+            Ok(Some(frame)) => frame,
+            // The client closed the connection between messages
+            Ok(None) => break,
+            // A client which stalls part way through a query is cut off, however many answers
+            // it's waiting on; otherwise only an idle connection is closed
+            Err(ref e) if is_timeout(e) => {
+                if frames.has_partial_frame() {
+                    println!("Closing TCP connection: query not completed in time");
+                    break;
+                }
+                if outstanding.load(Ordering::SeqCst) > 0 {
+                    continue;
+                }
+                println!("Closing idle TCP connection");
+                break;
+            }
+            Err(e) => {
+                println!("Failed to read DNS message from stream: {:?}", e);
+                break;
+            }
+        };
+
+        if frame.is_empty() {
+            println!("Ignoring zero-length DNS message");
+            continue;
+        }
+        let mut req_buffer = VariableBuffer::from_bytes(frame);
+
+        // Parse request buffer into packet
+        let request = match DnsPacket::from_buffer(&mut req_buffer) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("Failed to parse DNS packet: {}", e);
+                if let Some(mut response) = malformed_response(&mut req_buffer, &e) {
+                    if let Err(e) = connection.write_response(&mut response) {
+                        println!("Failed to send response buffer: {}", e);
+                    }
+                }
+                continue;
+            }
+        };
+
+        // Execute the query in the request and write the response back to the client
+        let connection = connection.clone();
+        let context = context.clone();
+        let outstanding = outstanding.clone();
+        outstanding.fetch_add(1, Ordering::SeqCst);
+        thread_pool.execute(move || {
+            let mut response = execute_query(request, context);
+            if let Err(e) = connection.write_response(&mut response) {
+                println!("Failed to send response buffer: {:?}", e);
+            }
+            outstanding.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+// Accept connections until the listener fails, reading each one's queries on its own thread.
+// `setup` turns a new stream into whatever `serve` reads the queries from and writes the
+// responses to.
+fn accept_connections<T, F, S>(
+    listener: TcpListener,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+    setup: F,
+    serve: S,
+) where
+    T: Send + 'static,
+    F: Fn(TcpStream) -> Result<T>,
+    S: Fn(T, ConnectionSlot, Arc<Threadpool>, Arc<ServerContext>) + Send + Copy + 'static,
+{
+    let active_connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to read TCP stream: {:?}", e);
+                continue;
+            }
+        };
+
+        // Turn clients away once we're at the limit, rather than queueing them
+        if active_connections.load(Ordering::SeqCst) >= context.max_tcp_connections {
+            println!(
+                "Refusing TCP connection: limit of {0} reached",
+                context.max_tcp_connections
+            );
+            continue;
+        }
+
+        // Idle connections time out on read; stalled clients time out on write
+        let timeout = Some(context.tcp_timeout);
+        let client = match stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .and_then(|_| setup(stream))
+        {
+            Ok(client) => client,
+            Err(e) => {
+                println!("Failed to set up TCP stream: {:?}", e);
+                continue;
+            }
+        };
+
+        active_connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(active_connections.clone());
+
+        let thread_pool = thread_pool.clone();
+        let context = context.clone();
+        let spawned = thread::Builder::new()
+            .name("DNS - TCP connection reader".to_string())
+            .spawn(move || serve(client, slot, thread_pool, context));
+        if let Err(e) = spawned {
+            println!("Failed to spawn TCP connection reader: {:?}", e);
+        }
+    }
+}
+
 pub struct TcpServer {
     context: Arc<ServerContext>,
 }
@@ -232,95 +513,623 @@ impl TcpServer {
 impl DnsServer for TcpServer {
     fn run(&self, thread_count: usize) -> Result<thread::JoinHandle<()>> {
         // Setup thread pool
-        let thread_pool = Threadpool::new(thread_count);
-        let listener = TcpListener::bind(("0.0.0.0", self.context.dns_port)).unwrap();
+        let thread_pool = Arc::new(Threadpool::new(thread_count));
+        let listener = TcpListener::bind((self.context.listen_address, self.context.dns_port))?;
         let context_ptr = self.context.clone();
 
         let tcp_thread = thread::Builder::new()
             .name("DNS - TCP server worker".to_string())
             .spawn(move || {
-                for stream in listener.incoming() {
-                    let thread_context = context_ptr.clone();
-                    match stream {
-                        Ok(mut stream) => {
-                            thread_pool.execute(move || {
-                                let mut len_buf = [0; 2];
-                                stream.read(&mut len_buf).unwrap();
-                                // Read request from stream into buffer
-                                // FIXME: use buffer with no size limit and capacity of length read from stream
-                                let buf_len = ((len_buf[0] as u16) << 8) | (len_buf[1] as u16);
-                                let mut req_buffer = VariableBuffer::new(buf_len as usize);
-                                match stream.read(&mut req_buffer.buf) {
-                                    Ok(bytes_read) => {
-                                        println!("Read {} bytes from stream", bytes_read);
-                                    }
-                                    Err(e) => {
-                                        println!("Failed to read bytes from stream: {:?}", e);
-                                        return;
-                                    }
-                                }
-                                // Parse request buffer into packet
-                                let request = match DnsPacket::from_buffer(&mut req_buffer) {
-                                    Ok(packet) => packet,
-                                    Err(e) => {
-                                        println!("Failed to parse DNS packet: {:?}", e);
-                                        return;
-                                    }
-                                };
-                                // Execute the query in the request and write the response into a buffer
-                                let mut response = execute_query(request, thread_context);
-                                let mut res_buffer = ExtendingBuffer::new();
-                                match response.write(&mut res_buffer) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!(
-                                            "Failed to write response packet to buffer: {:?}",
-                                            e
-                                        );
-                                        return;
-                                    }
-                                }
+                accept_connections(
+                    listener,
+                    thread_pool,
+                    context_ptr,
+                    |stream| Ok((stream.try_clone()?, stream)),
+                    serve_tcp_connection,
+                )
+            })?;
 
-                                let res_len = res_buffer.head();
-                                let res_data = match res_buffer.get_range(0, res_len) {
-                                    Ok(result) => result,
-                                    Err(e) => {
-                                        println!("Failed to read response buffer: {:?}", e);
-                                        return;
-                                    }
-                                };
+        Ok(tcp_thread)
+    }
+}
 
-                                // Write packet length first
-                                let mut len_buf = [0; 2];
-                                len_buf[0] = (res_len >> 8) as u8;
-                                len_buf[1] = (res_len & 0xFF) as u8;
-                                match stream.write(&len_buf) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!(
-                                            "Failed to write packet length to buffer: {:?}",
-                                            e
-                                        );
-                                        return;
-                                    }
-                                }
-                                // Now, write the data
-                                match stream.write(res_data) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        println!("Failed to send response buffer: {:?}", e);
-                                        return;
-                                    }
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            println!("Failed to read TCP stream: {:?}", e);
-                        }
-                    }
+// The TLS configuration for a new connection. Each one takes up whichever certificate is
+// current when it arrives.
+fn tls_config(context: &ServerContext, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let certificates = context
+        .certificates
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "No TLS certificate loaded"))?;
+
+    Ok(certificates.server_config(alpn))
+}
+
+// Start a TLS session on a new connection
+fn accept_tls(
+    context: &ServerContext,
+    alpn: &[u8],
+    stream: TcpStream,
+) -> Result<(tls::TlsReader, tls::TlsWriter)> {
+    let session = ServerConnection::new(tls_config(context, &[alpn])?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    tls::split(session, stream)
+}
+
+// DNS over TLS server (RFC 7858). Queries are framed just as they are over TCP.
+
+// ALPN protocol identifying DNS over TLS (RFC 8310 section 8.1)
+const DOT_ALPN: &[u8] = b"dot";
+
+pub struct TlsServer {
+    context: Arc<ServerContext>,
+}
+
+impl TlsServer {
+    pub fn new(context: Arc<ServerContext>) -> TlsServer {
+        TlsServer { context }
+    }
+}
+
+impl DnsServer for TlsServer {
+    fn run(&self, thread_count: usize) -> Result<thread::JoinHandle<()>> {
+        if self.context.certificates.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "DNS over TLS needs a certificate and private key",
+            ));
+        }
+
+        let thread_pool = Arc::new(Threadpool::new(thread_count));
+        let listener = TcpListener::bind((self.context.listen_address, self.context.tls_port))?;
+        let context_ptr = self.context.clone();
+
+        let tls_thread = thread::Builder::new()
+            .name("DNS - TLS server worker".to_string())
+            .spawn(move || {
+                let context = context_ptr.clone();
+                accept_connections(
+                    listener,
+                    thread_pool,
+                    context_ptr,
+                    move |stream| accept_tls(&context, DOT_ALPN, stream),
+                    serve_tcp_connection,
+                )
+            })?;
+
+        Ok(tls_thread)
+    }
+}
+
+// DNS over HTTPS server (RFC 8484). Requests over HTTP/2 are answered as soon as they're ready,
+// like pipelined TCP queries; those over HTTP/1.1 one at a time, in order.
+
+// HTTP/2 is the minimum RFC 8484 section 5.2 recommends, so it's offered first, with HTTP/1.1
+// for clients which don't speak it
+const H2_ALPN: &[u8] = b"h2";
+const HTTP_ALPN: &[&[u8]] = &[H2_ALPN, b"http/1.1"];
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// HTTP caches may keep an answer as long as its shortest lived record (RFC 8484 section 5.1),
+// and negative answers as long as their SOA allows (RFC 2308 section 5)
+fn cache_control(response: &DnsPacket) -> String {
+    let ttls = response
+        .answers
+        .iter()
+        .chain(&response.authorities)
+        .chain(&response.resources)
+        .filter_map(|rec| match *rec {
+            DnsRecord::OPT { .. } => None,
+            DnsRecord::SOA { ttl, minimum, .. } => Some(cmp::min(ttl, minimum)),
+            _ => Some(rec.get_ttl()),
+        });
+
+    match (response.header.rescode, ttls.min()) {
+        (ResponseCode::NOERROR, Some(ttl)) | (ResponseCode::NXDOMAIN, Some(ttl)) => {
+            format!("max-age={0}", ttl)
+        }
+        _ => "no-store".to_string(),
+    }
+}
+
+// The DNS message in a request: base64url in the dns parameter of a GET, or the body of a POST
+fn doh_message(request: &HttpRequest) -> std::result::Result<Vec<u8>, u16> {
+    match request.method.as_str() {
+        "GET" => request
+            .query_param("dns")
+            .and_then(|dns| {
+                base64::decode_config(dns.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+            })
+            .ok_or(400),
+        "POST" => {
+            let content_type = request.header("Content-Type").unwrap_or("");
+            let media_type = content_type.split(';').next().unwrap_or("").trim();
+            if !media_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                return Err(415);
+            }
+            Ok(request.body.clone())
+        }
+        _ => Err(405),
+    }
+}
+
+// Answer a DNS over HTTPS request, running its query on the thread pool like any other
+fn answer_doh_request(
+    request: &HttpRequest,
+    thread_pool: &Threadpool,
+    context: &Arc<ServerContext>,
+) -> (u16, Vec<(&'static str, String)>, Vec<u8>) {
+    if request.path() != context.doh_path {
+        return (404, Vec::new(), Vec::new());
+    }
+    let message = match doh_message(request) {
+        Ok(message) => message,
+        Err(405) => return (405, vec![("Allow", "GET, POST".to_string())], Vec::new()),
+        Err(status) => return (status, Vec::new(), Vec::new()),
+    };
+
+    let mut req_buffer = VariableBuffer::from_bytes(message);
+    let query = match DnsPacket::from_buffer(&mut req_buffer) {
+        Ok(packet) => packet,
+        Err(e) => {
+            println!("Failed to parse DNS packet: {}", e);
+            return (400, Vec::new(), Vec::new());
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let context = context.clone();
+    thread_pool.execute(move || {
+        // The connection may have gone by the time we're done
+        let _ = sender.send(execute_query(query, context));
+    });
+    let mut response = match receiver.recv() {
+        Ok(response) => response,
+        Err(_) => return (500, Vec::new(), Vec::new()),
+    };
+
+    let mut res_buffer = ExtendingBuffer::new();
+    if let Err(e) = response.write(&mut res_buffer) {
+        println!("Failed to write response packet to buffer: {:?}", e);
+        return (500, Vec::new(), Vec::new());
+    }
+    let headers = vec![
+        ("Content-Type", DNS_MESSAGE.to_string()),
+        ("Cache-Control", cache_control(&response)),
+    ];
+    let res_len = res_buffer.head();
+    res_buffer.buf.truncate(res_len);
+
+    (200, headers, res_buffer.buf)
+}
+
+fn serve_http1_connection<R, W>(
+    reader: R,
+    connection: Arc<TcpConnection<W>>,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+) where
+    R: Read,
+    W: Write + Send + 'static,
+{
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let request = match http::read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            // The client closed the connection between requests
+            Ok(None) => break,
+            Err(ref e) if is_timeout(e) => {
+                println!("Closing idle HTTPS connection");
+                break;
+            }
+            Err(e) => {
+                println!("Failed to read HTTP request: {}", e);
+                if e.kind() == ErrorKind::InvalidData {
+                    let _ = connection.write_http(400, &[], &[]);
+                }
+                break;
+            }
+        };
+
+        let (status, headers, body) = answer_doh_request(&request, &thread_pool, &context);
+        if let Err(e) = connection.write_http(status, &headers, &body) {
+            println!("Failed to send HTTP response: {:?}", e);
+            break;
+        }
+        if !request.keep_alive() {
+            break;
+        }
+    }
+}
+
+// Answer a request on an HTTP/2 stream
+async fn answer_h2_request(
+    request: ::http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+) -> std::result::Result<(), h2::Error> {
+    let (status, headers, body) = match http::read_h2_request(request).await {
+        Ok(request) => {
+            // The query runs on the thread pool; waiting on it mustn't hold up other streams
+            tokio::task::spawn_blocking(move || {
+                answer_doh_request(&request, &thread_pool, &context)
+            })
+            .await
+            .unwrap_or_else(|_| (500, Vec::new(), Vec::new()))
+        }
+        Err(ref e) if e.kind() == ErrorKind::InvalidData => (400, Vec::new(), Vec::new()),
+        Err(e) => {
+            println!("Failed to read HTTP/2 request: {}", e);
+            return Ok(());
+        }
+    };
+
+    let mut response = ::http::Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    let response = response
+        .header("Content-Length", body.len())
+        .body(())
+        .expect("Failed to build HTTP response");
+
+    let mut stream = respond.send_response(response, body.is_empty())?;
+    if !body.is_empty() {
+        stream.send_data(Bytes::from(body), true)?;
+    }
+
+    Ok(())
+}
+
+// Answer the requests on an HTTP/2 connection (RFC 9113) until the client closes it or it goes
+// idle. Each is answered on its own task, so slow queries don't hold up the rest.
+async fn serve_h2_connection<T>(
+    stream: T,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+) -> std::result::Result<(), h2::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection =
+        match tokio::time::timeout(context.tcp_timeout, h2::server::handshake(stream)).await {
+            Ok(connection) => connection?,
+            Err(_) => return Ok(()),
+        };
+    let outstanding = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        // Responses only go out while the connection is polled, so it's kept open for as long
+        // as any are owed
+        let (request, respond) =
+            match tokio::time::timeout(context.tcp_timeout, connection.accept()).await {
+                Ok(Some(request)) => request?,
+                // The client closed the connection
+                Ok(None) => return Ok(()),
+                Err(_) if outstanding.load(Ordering::SeqCst) > 0 => continue,
+                Err(_) => {
+                    println!("Closing idle HTTPS connection");
+                    return Ok(());
                 }
+            };
+
+        let thread_pool = thread_pool.clone();
+        let context = context.clone();
+        let outstanding = outstanding.clone();
+        outstanding.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            if let Err(e) = answer_h2_request(request, respond, thread_pool, context).await {
+                println!("Failed to send HTTP/2 response: {}", e);
+            }
+            outstanding.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+// Serve a DNS over HTTPS connection with whichever HTTP version the client picked during the
+// TLS handshake. Each connection has a runtime of its own to drive HTTP/2 with.
+fn serve_https_connection(
+    stream: TcpStream,
+    slot: ConnectionSlot,
+    thread_pool: Arc<Threadpool>,
+    context: Arc<ServerContext>,
+) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            println!("Failed to start HTTPS connection runtime: {:?}", e);
+            return;
+        }
+    };
+
+    let handshake = async {
+        let acceptor = TlsAcceptor::from(tls_config(&context, HTTP_ALPN)?);
+        stream.set_nonblocking(true)?;
+        let stream = tokio::net::TcpStream::from_std(stream)?;
+        tokio::time::timeout(context.tcp_timeout, acceptor.accept(stream))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake timed out"))?
+    };
+    let stream = match runtime.block_on(handshake) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Failed to accept HTTPS connection: {:?}", e);
+            return;
+        }
+    };
+
+    if stream.get_ref().1.alpn_protocol() == Some(H2_ALPN) {
+        if let Err(e) = runtime.block_on(serve_h2_connection(stream, thread_pool, context)) {
+            println!("HTTP/2 connection failed: {}", e);
+        }
+        return;
+    }
+
+    // HTTP/1.1 is read a request at a time, so the session goes back onto a blocking socket
+    let (stream, session) = stream.into_inner();
+    let halves = stream.into_std().and_then(|stream| {
+        stream.set_nonblocking(false)?;
+        tls::split(session, stream)
+    });
+    let (reader, writer) = match halves {
+        Ok(halves) => halves,
+        Err(e) => {
+            println!("Failed to set up HTTPS connection: {:?}", e);
+            return;
+        }
+    };
+    let connection = Arc::new(TcpConnection {
+        writer: Mutex::new(writer),
+        _slot: slot,
+    });
+
+    serve_http1_connection(reader, connection, thread_pool, context);
+}
+
+pub struct HttpsServer {
+    context: Arc<ServerContext>,
+}
+
+impl HttpsServer {
+    pub fn new(context: Arc<ServerContext>) -> HttpsServer {
+        HttpsServer { context }
+    }
+}
+
+impl DnsServer for HttpsServer {
+    fn run(&self, thread_count: usize) -> Result<thread::JoinHandle<()>> {
+        if self.context.certificates.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "DNS over HTTPS needs a certificate and private key",
+            ));
+        }
+
+        let thread_pool = Arc::new(Threadpool::new(thread_count));
+        let listener = TcpListener::bind((self.context.listen_address, self.context.https_port))?;
+        let context_ptr = self.context.clone();
+
+        let https_thread = thread::Builder::new()
+            .name("DNS - HTTPS server worker".to_string())
+            .spawn(move || {
+                accept_connections(
+                    listener,
+                    thread_pool,
+                    context_ptr,
+                    Ok,
+                    serve_https_connection,
+                )
             })?;
 
-        Ok(tcp_thread)
+        Ok(https_thread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::authority::Zone;
+    use crate::dns::config::Config;
+    use crate::dns::network::HttpsUpstream;
+    use crate::dns::tls::CertificateStore;
+    use crate::dns::zonefile;
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConnection;
+    use std::convert::TryFrom;
+    use std::io::BufRead;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    const ZONE: &str = "$ORIGIN example.test.
+$TTL 300
+@ IN SOA ns hostmaster 1 3600 600 86400 60
+www IN A 192.0.2.1
+";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    // A server context answering for example.test, with the test certificate loaded
+    fn test_context(timeout: Duration) -> ServerContext {
+        let mut context = ServerContext::new(&Config::default()).unwrap();
+        context.tcp_timeout = timeout;
+        context
+            .authority
+            .add_zone(Zone::new(zonefile::parse(ZONE).unwrap()).unwrap())
+            .unwrap();
+        context.certificates =
+            Some(CertificateStore::load(&fixture("leaf.pem"), &fixture("leaf.key")).unwrap());
+        context
+    }
+
+    // Accept connections on a port of our own, set up and served as given
+    fn listen<T, F, S>(context: ServerContext, setup: F, serve: S) -> SocketAddr
+    where
+        T: Send + 'static,
+        F: Fn(TcpStream) -> Result<T> + Send + 'static,
+        S: Fn(T, ConnectionSlot, Arc<Threadpool>, Arc<ServerContext>) + Send + Copy + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            accept_connections(
+                listener,
+                Arc::new(Threadpool::new(2)),
+                Arc::new(context),
+                setup,
+                serve,
+            )
+        });
+
+        addr
+    }
+
+    // A TCP server with a short timeout
+    fn tcp_server(timeout: Duration) -> SocketAddr {
+        listen(
+            test_context(timeout),
+            |stream| Ok((stream.try_clone()?, stream)),
+            serve_tcp_connection,
+        )
+    }
+
+    fn https_server() -> SocketAddr {
+        listen(
+            test_context(Duration::from_secs(5)),
+            Ok,
+            serve_https_connection,
+        )
+    }
+
+    fn assert_answer(packet: &DnsPacket) {
+        assert_eq!(packet.header.rescode, ResponseCode::NOERROR);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.get_random_a(), Some("192.0.2.1".to_string()));
+    }
+
+    // Wait for the server to hang up, failing if it's still there after a few seconds
+    fn assert_closed(client: &mut TcpStream, started: Instant) {
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 1];
+        match client.read(&mut buf) {
+            Ok(0) => {}
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {}
+            other => panic!("Connection still open: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn closes_connection_stalled_mid_query() {
+        let addr = tcp_server(Duration::from_millis(200));
+        let mut client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+
+        // Half of a length prefix, then nothing
+        client.write_all(&[0]).unwrap();
+        assert_closed(&mut client, started);
+    }
+
+    #[test]
+    fn closes_connection_trickling_a_query() {
+        let addr = tcp_server(Duration::from_millis(300));
+        let mut client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+
+        // Each byte arrives well within the read timeout, but the query never completes
+        client.write_all(&[0x01, 0x00]).unwrap();
+        let mut writer = client.try_clone().unwrap();
+        thread::spawn(move || {
+            for _ in 0..100 {
+                if writer.write_all(&[0]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        assert_closed(&mut client, started);
+    }
+
+    #[test]
+    fn serves_doh_over_http2() {
+        let addr = https_server();
+        let config = tls::client_config(Some(&fixture("root.pem")), &[], &[b"h2"]).unwrap();
+        let url = format!("https://127.0.0.1:{0}/dns-query", addr.port());
+        let upstream =
+            HttpsUpstream::new(&url, "127.0.0.1", addr.port(), "dot.test", config).unwrap();
+        let client = ServerContext::new(&Config::default()).unwrap().client;
+
+        // Several queries share the one connection
+        for _ in 0..3 {
+            let packet = client
+                .send_https_query("www.example.test", QueryType::A, &upstream, true)
+                .unwrap();
+            assert_answer(&packet);
+        }
+    }
+
+    #[test]
+    fn serves_doh_over_http1() {
+        let addr = https_server();
+        let config = tls::client_config(Some(&fixture("root.pem")), &[], &[b"http/1.1"]).unwrap();
+        let session =
+            ClientConnection::new(config, ServerName::try_from("dot.test").unwrap()).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (reader, mut writer) = tls::split(session, stream).unwrap();
+
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new(
+            "www.example.test".to_string(),
+            QueryType::A,
+        ));
+        let mut req_buffer = ExtendingBuffer::new();
+        query.write(&mut req_buffer).unwrap();
+        let dns = base64::encode_config(
+            &req_buffer.buf[..req_buffer.head()],
+            base64::URL_SAFE_NO_PAD,
+        );
+        write!(
+            writer,
+            "GET /dns-query?dns={0} HTTP/1.1\r\nHost: dot.test\r\n\r\n",
+            dns
+        )
+        .unwrap();
+
+        let mut reader = BufReader::new(reader);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let packet = DnsPacket::from_buffer(&mut VariableBuffer::from_bytes(body)).unwrap();
+        assert_answer(&packet);
     }
 }
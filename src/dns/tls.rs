@@ -0,0 +1,524 @@
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, Connection, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+fn invalid<E: std::fmt::Display>(path: &Path, e: E) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Failed to load {0}: {1}", path.display(), e),
+    )
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+// Latest modification time of the files, or None if any can't be read
+fn modified(paths: &[&Path]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect::<Option<Vec<SystemTime>>>()?
+        .into_iter()
+        .max()
+}
+
+fn build_config(certificate: &Path, private_key: &Path) -> Result<ServerConfig> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| invalid(certificate, e))?;
+    if chain.is_empty() {
+        return Err(invalid(certificate, "no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(private_key).map_err(|e| invalid(private_key, e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(tls_error)
+}
+
+// The server's certificate chain and private key, loaded from PEM files. The files are checked
+// for changes as connections arrive, so a renewed certificate is picked up without a restart.
+pub struct CertificateStore {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    // The configuration built from the files, and when they were modified at the time
+    current: RwLock<(Option<SystemTime>, Arc<ServerConfig>)>,
+}
+
+impl CertificateStore {
+    pub fn load(certificate: &Path, private_key: &Path) -> Result<CertificateStore> {
+        let loaded = modified(&[certificate, private_key]);
+        let config = build_config(certificate, private_key)?;
+
+        Ok(CertificateStore {
+            certificate: certificate.to_path_buf(),
+            private_key: private_key.to_path_buf(),
+            current: RwLock::new((loaded, Arc::new(config))),
+        })
+    }
+
+    // TLS configuration for a new connection, offering the given ALPN protocols
+    pub fn server_config(&self, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+        self.reload_if_changed();
+
+        let current = self
+            .current
+            .read()
+            .expect("Failed to acquire certificate lock");
+        let mut config = (*current.1).clone();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+        Arc::new(config)
+    }
+
+    // A half-written or broken replacement is logged and the old certificate kept, so a renewal
+    // gone wrong doesn't take the server down
+    fn reload_if_changed(&self) {
+        let modified = modified(&[&self.certificate, &self.private_key]);
+        let loaded = self
+            .current
+            .read()
+            .expect("Failed to acquire certificate lock")
+            .0;
+        if modified.is_none() || modified == loaded {
+            return;
+        }
+
+        let mut current = self
+            .current
+            .write()
+            .expect("Failed to acquire certificate lock");
+        if modified == current.0 {
+            return;
+        }
+        match build_config(&self.certificate, &self.private_key) {
+            Ok(config) => {
+                println!(
+                    "Reloaded TLS certificate from {0}",
+                    self.certificate.display()
+                );
+                *current = (modified, Arc::new(config));
+            }
+            Err(e) => {
+                println!("Failed to reload TLS certificate: {}", e);
+                current.0 = modified;
+            }
+        }
+    }
+}
+
+// Checks an upstream server's certificate against pinned SHA-256 hashes of a SubjectPublicKeyInfo
+// (RFC 7858 section 4.2). A pin on the server's own key needs nothing more, as the handshake is
+// checked against that key. A pin on an intermediate CA only counts once the server's
+// certificate is shown to chain to it and to be valid for the name, since anyone can send a
+// copy of the intermediate along with a certificate of their own.
+#[derive(Debug)]
+struct SpkiPinVerifier {
+    pins: Vec<Vec<u8>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl SpkiPinVerifier {
+    fn is_pinned(&self, certificate: &CertificateDer<'_>) -> bool {
+        match webpki::EndEntityCert::try_from(certificate) {
+            Ok(cert) => {
+                let hash = digest(&SHA256, &cert.subject_public_key_info());
+                self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref())
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Whether the certificate is issued for the name through the pinned intermediate
+    fn chains_to(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        pinned: &CertificateDer<'_>,
+        server_name: &ServerName<'_>,
+        now: UnixTime,
+    ) -> bool {
+        let cert = match webpki::EndEntityCert::try_from(end_entity) {
+            Ok(cert) => cert,
+            Err(_) => return false,
+        };
+        let anchor = match webpki::anchor_from_trusted_cert(pinned) {
+            Ok(anchor) => anchor,
+            Err(_) => return false,
+        };
+
+        cert.verify_for_usage(
+            self.algorithms.all,
+            &[anchor],
+            intermediates,
+            now,
+            webpki::KeyUsage::server_auth(),
+            None,
+            None,
+        )
+        .is_ok()
+            && cert.verify_is_valid_for_subject_name(server_name).is_ok()
+    }
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if self.is_pinned(end_entity)
+            || intermediates.iter().any(|intermediate| {
+                self.is_pinned(intermediate)
+                    && self.chains_to(end_entity, intermediates, intermediate, server_name, now)
+            })
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "Certificate does not match any pinned key".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// Pins are given as the base64 SHA-256 hash of the key, as in RFC 7469
+fn decode_pin(pin: &str) -> Result<Vec<u8>> {
+    match base64::decode(pin) {
+        Ok(hash) if hash.len() == SHA256.output_len() => Ok(hash),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Invalid SPKI pin {0:?}: expected a base64 SHA-256 hash",
+                pin
+            ),
+        )),
+    }
+}
+
+fn load_roots(ca_file: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    let ca_file = match ca_file {
+        Some(ca_file) => ca_file,
+        None => {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            return Ok(roots);
+        }
+    };
+
+    for certificate in CertificateDer::pem_file_iter(ca_file).map_err(|e| invalid(ca_file, e))? {
+        let certificate = certificate.map_err(|e| invalid(ca_file, e))?;
+        roots.add(certificate).map_err(|e| invalid(ca_file, e))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid(ca_file, "no certificates found"));
+    }
+
+    Ok(roots)
+}
+
+// TLS configuration for connecting to an upstream server, offering the given ALPN protocols.
+// The server's certificate must match one of the pinned keys if any are given, and otherwise
+// chain to a CA in the PEM file, or to one of the Mozilla root CAs if there isn't one.
+pub fn client_config(
+    ca_file: Option<&Path>,
+    pins: &[String],
+    alpn: &[&[u8]],
+) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+
+    let mut config = if pins.is_empty() {
+        builder
+            .with_root_certificates(load_roots(ca_file)?)
+            .with_no_client_auth()
+    } else {
+        let verifier = SpkiPinVerifier {
+            pins: pins
+                .iter()
+                .map(|pin| decode_pin(pin))
+                .collect::<Result<Vec<Vec<u8>>>>()?,
+            algorithms: provider.signature_verification_algorithms,
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth()
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+// Split a TLS session over a TCP stream into a half that reads decrypted messages and a half
+// that writes them, so one thread can wait on messages while others send theirs. The session
+// is only locked to encrypt and decrypt, never while waiting on the network.
+pub fn split<C: Into<Connection>>(session: C, stream: TcpStream) -> Result<(TlsReader, TlsWriter)> {
+    let session = Arc::new(Mutex::new(session.into()));
+    let reader = TlsReader {
+        stream: stream.try_clone()?,
+        session: session.clone(),
+    };
+
+    Ok((reader, TlsWriter { stream, session }))
+}
+
+// Send whatever the session has ready: handshake messages, alerts and encrypted data
+fn flush_session(session: &mut Connection, mut stream: &TcpStream) -> Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut stream)?;
+    }
+
+    Ok(())
+}
+
+pub struct TlsReader {
+    stream: TcpStream,
+    session: Arc<Mutex<Connection>>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            {
+                let mut session = self
+                    .session
+                    .lock()
+                    .expect("Failed to acquire TLS session lock");
+                match session.reader().read(buf) {
+                    // Data ready, or the peer closed the session cleanly
+                    Ok(bytes_read) => return Ok(bytes_read),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                flush_session(&mut session, &self.stream)?;
+            }
+
+            // Timeouts on the stream come back as errors here, just as they do for plain TCP
+            let mut chunk = [0; 4096];
+            let bytes_read = match (&self.stream).read(&mut chunk)? {
+                0 => return Ok(0),
+                bytes_read => bytes_read,
+            };
+
+            let mut session = self
+                .session
+                .lock()
+                .expect("Failed to acquire TLS session lock");
+            let mut received = &chunk[..bytes_read];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                if let Err(e) = session.process_new_packets() {
+                    // Let the peer know why before giving up on it
+                    let _ = flush_session(&mut session, &self.stream);
+                    return Err(tls_error(e));
+                }
+            }
+        }
+    }
+}
+
+pub struct TlsWriter {
+    stream: TcpStream,
+    session: Arc<Mutex<Connection>>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut session = self
+            .session
+            .lock()
+            .expect("Failed to acquire TLS session lock");
+        session.writer().write_all(buf)?;
+        flush_session(&mut session, &self.stream)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut session = self
+            .session
+            .lock()
+            .expect("Failed to acquire TLS session lock");
+        flush_session(&mut session, &self.stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::framing::{write_frame, FrameReader};
+    use rustls::{ClientConnection, ServerConnection};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    // The pin for the first certificate in a PEM file
+    fn pin(name: &str) -> String {
+        let certificate = CertificateDer::from_pem_file(fixture(name)).unwrap();
+        let cert = webpki::EndEntityCert::try_from(&certificate).unwrap();
+        base64::encode(digest(&SHA256, &cert.subject_public_key_info()))
+    }
+
+    // A server that presents the certificate chain and echoes back one message
+    fn serve(certificate: &str, private_key: &str) -> SocketAddr {
+        let store = CertificateStore::load(&fixture(certificate), &fixture(private_key)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let session = ServerConnection::new(store.server_config(&[])).unwrap();
+            let (reader, mut writer) = split(session, stream).unwrap();
+            if let Ok(Some(message)) = FrameReader::new(reader).read_frame() {
+                let _ = write_frame(&mut writer, &message);
+            }
+        });
+
+        addr
+    }
+
+    fn exchange(addr: SocketAddr, name: &str, ca_file: Option<&str>, pins: &[String]) -> bool {
+        let ca_file = ca_file.map(fixture);
+        let config = client_config(ca_file.as_deref(), pins, &[]).unwrap();
+        let session =
+            ClientConnection::new(config, ServerName::try_from(name.to_string()).unwrap()).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (reader, mut writer) = split(session, stream).unwrap();
+
+        write_frame(&mut writer, b"ping").unwrap();
+        match FrameReader::new(reader).read_frame() {
+            Ok(Some(message)) => message == b"ping",
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn ca_file_accepts_chain_to_ca() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(exchange(addr, "dot.test", Some("root.pem"), &[]));
+    }
+
+    #[test]
+    fn ca_file_accepts_ip_address_name() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(exchange(addr, "127.0.0.1", Some("root.pem"), &[]));
+    }
+
+    #[test]
+    fn ca_file_rejects_chain_to_other_ca() {
+        let addr = serve("attacker.pem", "attacker.key");
+        assert!(!exchange(addr, "dot.test", Some("root.pem"), &[]));
+    }
+
+    #[test]
+    fn default_roots_reject_private_ca() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(!exchange(addr, "dot.test", None, &[]));
+    }
+
+    #[test]
+    fn ca_file_rejects_wrong_name() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(!exchange(addr, "other.test", Some("root.pem"), &[]));
+    }
+
+    #[test]
+    fn pin_accepts_matching_key() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(exchange(addr, "dot.test", None, &[pin("leaf.pem")]));
+    }
+
+    #[test]
+    fn pin_rejects_other_key() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(!exchange(addr, "dot.test", None, &[pin("attacker.pem")]));
+    }
+
+    #[test]
+    fn intermediate_pin_accepts_chain() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(exchange(addr, "dot.test", None, &[pin("intermediate.pem")]));
+    }
+
+    #[test]
+    fn intermediate_pin_rejects_wrong_name() {
+        let addr = serve("leaf.pem", "leaf.key");
+        assert!(!exchange(
+            addr,
+            "other.test",
+            None,
+            &[pin("intermediate.pem")]
+        ));
+    }
+
+    // Sending a copy of the pinned intermediate with a certificate it didn't issue proves nothing
+    #[test]
+    fn intermediate_pin_rejects_unrelated_leaf() {
+        let addr = serve("attacker.pem", "attacker.key");
+        assert!(!exchange(
+            addr,
+            "dot.test",
+            None,
+            &[pin("intermediate.pem")]
+        ));
+    }
+
+    #[test]
+    fn malformed_pin_is_rejected() {
+        assert!(client_config(None, &["nope".to_string()], &[]).is_err());
+    }
+}
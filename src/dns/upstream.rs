@@ -0,0 +1,495 @@
+use super::config::ResolverConfig;
+use super::error::{DnsError, Result};
+use super::network::{HttpsUpstream, NetworkClient, TlsUpstream};
+use super::protocol::{DnsPacket, QueryType, ResponseCode};
+use super::tls;
+use rand::{thread_rng, Rng};
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DNS_PORT: u16 = 53;
+// DNS over TLS port (RFC 7858 section 3.1)
+const DOT_PORT: u16 = 853;
+const HTTPS_PORT: u16 = 443;
+
+// Split a host from an optional port. IPv6 addresses need brackets to be given a port.
+fn split_host_port(address: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid upstream address: {0}", address),
+        )
+    };
+
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(invalid)?;
+        match &rest[end + 1..] {
+            "" => (&rest[..end], None),
+            port => (
+                &rest[..end],
+                Some(port.strip_prefix(':').ok_or_else(invalid)?),
+            ),
+        }
+    } else {
+        match address.rfind(':') {
+            Some(colon) if address.matches(':').count() == 1 => {
+                (&address[..colon], Some(&address[colon + 1..]))
+            }
+            _ => (address, None),
+        }
+    };
+
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok((host.to_string(), port))
+}
+
+// A server queries are forwarded to, and how we talk to it
+pub enum Upstream {
+    // Plain DNS over UDP, falling back to TCP for answers too large for a datagram
+    Plain { host: String, port: u16 },
+    // DNS over TLS, on a connection kept open between queries
+    Tls(Arc<TlsUpstream>),
+    // DNS over HTTPS, on an HTTP/2 connection kept open between queries
+    Https(Arc<HttpsUpstream>),
+}
+
+impl Upstream {
+    // Upstreams are host[:port] to send plain DNS to, tls://host[:port] for DNS over TLS, or an
+    // https:// URL for DNS over HTTPS. Both of the latter are authenticated as the resolver
+    // config says.
+    pub fn from_config(upstream: &str, config: &ResolverConfig) -> io::Result<Upstream> {
+        if let Some(address) = upstream.strip_prefix("tls://") {
+            let (host, port) = split_host_port(address, DOT_PORT)?;
+            let name = config.tls_name.as_deref().unwrap_or(&host);
+            let client_config = tls::client_config(
+                config.tls_ca_file.as_deref(),
+                &config.tls_spki_pins,
+                &[b"dot"],
+            )?;
+
+            return Ok(Upstream::Tls(Arc::new(TlsUpstream::new(
+                &host,
+                port,
+                name,
+                client_config,
+            )?)));
+        }
+
+        if let Some(rest) = upstream.strip_prefix("https://") {
+            // The path defaults to the one RFC 8484 uses in its examples
+            let (address, url) = match rest.find('/') {
+                Some(slash) => (&rest[..slash], upstream.to_string()),
+                None => (rest, format!("{0}/dns-query", upstream)),
+            };
+            let (host, port) = split_host_port(address, HTTPS_PORT)?;
+            let name = config.tls_name.as_deref().unwrap_or(&host);
+            let client_config = tls::client_config(
+                config.tls_ca_file.as_deref(),
+                &config.tls_spki_pins,
+                &[b"h2"],
+            )?;
+
+            return Ok(Upstream::Https(Arc::new(HttpsUpstream::new(
+                &url,
+                &host,
+                port,
+                name,
+                client_config,
+            )?)));
+        }
+
+        let (host, port) = split_host_port(upstream, DNS_PORT)?;
+        Ok(Upstream::Plain { host, port })
+    }
+
+    // Send a query however the upstream is reached. Plain DNS queries are resent over UDP up
+    // to the given number of times; the others get one attempt on their connection.
+    fn send_query(
+        &self,
+        client: &NetworkClient,
+        qname: &str,
+        qtype: QueryType,
+        retries: u32,
+    ) -> Result<DnsPacket> {
+        let packet = match *self {
+            Upstream::Plain { ref host, port } => {
+                client.send_query_with_retries(qname, qtype, (host, port), true, retries)?
+            }
+            Upstream::Tls(ref upstream) => client.send_tls_query(qname, qtype, upstream, true)?,
+            Upstream::Https(ref upstream) => {
+                client.send_https_query(qname, qtype, upstream, true)?
+            }
+        };
+
+        // Our upstream won't serve us, so neither can we
+        if packet.header.rescode == ResponseCode::REFUSED {
+            return Err(DnsError::UpstreamRefused(format!(
+                "{0} refused to answer",
+                self
+            )));
+        }
+
+        Ok(packet)
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Upstream::Plain { ref host, port } => write!(f, "{0}:{1}", host, port),
+            Upstream::Tls(ref upstream) => write!(f, "{0}", upstream),
+            Upstream::Https(ref upstream) => write!(f, "{0}", upstream),
+        }
+    }
+}
+
+// How the upstream a query is sent to is chosen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    // The first one which is up, in the order they're configured
+    Strict,
+    // Each in turn, as often as their weights say
+    RoundRobin,
+    // Any, with odds in proportion to their weights
+    Random,
+    // Whichever has been answering fastest
+    LowestLatency,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "strict" => Some(Strategy::Strict),
+            "round-robin" => Some(Strategy::RoundRobin),
+            "random" => Some(Strategy::Random),
+            "lowest-latency" => Some(Strategy::LowestLatency),
+            _ => None,
+        }
+    }
+}
+
+// Whether an error means the upstream isn't answering at all, rather than answering badly
+fn is_unreachable(e: &DnsError) -> bool {
+    matches!(e, DnsError::Timeout(_) | DnsError::Io(_))
+}
+
+// An upstream in a pool, and how it's been doing
+struct Member {
+    upstream: Upstream,
+    weight: usize,
+    down: AtomicBool,
+    // Moving average of its response times in microseconds, or 0 until one's been measured
+    latency: AtomicU64,
+}
+
+impl Member {
+    fn is_down(&self) -> bool {
+        self.down.load(Ordering::SeqCst)
+    }
+
+    fn mark_down(&self, e: &DnsError) {
+        if !self.down.swap(true, Ordering::SeqCst) {
+            println!("Marking upstream {0} down: {1}", self.upstream, e);
+        }
+    }
+
+    fn mark_up(&self) {
+        if self.down.swap(false, Ordering::SeqCst) {
+            println!("Upstream {0} is back up", self.upstream);
+        }
+    }
+
+    fn record_latency(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let average = match self.latency.load(Ordering::Relaxed) {
+            0 => sample,
+            average => (average * 7 + sample) / 8,
+        };
+        self.latency.store(average.max(1), Ordering::Relaxed);
+    }
+
+    // Send a query to this upstream, keeping track of whether it's answering
+    fn send_query(
+        &self,
+        client: &NetworkClient,
+        qname: &str,
+        qtype: QueryType,
+        retries: u32,
+    ) -> Result<DnsPacket> {
+        let started = Instant::now();
+        match self.upstream.send_query(client, qname, qtype, retries) {
+            Ok(packet) => {
+                self.record_latency(started.elapsed());
+                self.mark_up();
+                Ok(packet)
+            }
+            Err(e) => {
+                if is_unreachable(&e) {
+                    self.mark_down(&e);
+                }
+                println!("Upstream {0} failed to answer: {1}", self.upstream, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+// The servers queries are forwarded to. Each query goes to the one the strategy picks, and on
+// to the others in turn if it can't be answered there. Upstreams which stop answering are
+// marked down, so they're only tried once the rest have failed, until a health check finds
+// them answering again.
+pub struct UpstreamPool {
+    members: Vec<Member>,
+    strategy: Strategy,
+    // Position in the weighted rotation, for round-robin
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn from_config(config: &ResolverConfig) -> io::Result<UpstreamPool> {
+        let strategy = Strategy::from_name(&config.upstream_strategy).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown upstream strategy: {0}", config.upstream_strategy),
+            )
+        })?;
+
+        let members = config
+            .upstream_list()
+            .into_iter()
+            .map(|(address, weight)| {
+                Ok(Member {
+                    upstream: Upstream::from_config(address, config)?,
+                    weight,
+                    down: AtomicBool::new(false),
+                    latency: AtomicU64::new(0),
+                })
+            })
+            .collect::<io::Result<Vec<Member>>>()?;
+        if members.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Forward mode requires an upstream",
+            ));
+        }
+
+        Ok(UpstreamPool {
+            members,
+            strategy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // The member a position in the weighted rotation falls on
+    fn weighted(&self, mut position: usize) -> usize {
+        for (index, member) in self.members.iter().enumerate() {
+            if position < member.weight {
+                return index;
+            }
+            position -= member.weight;
+        }
+
+        0
+    }
+
+    // Upstreams in the order they should be tried: those which are up as the strategy would
+    // have it, then those which are down as a last resort
+    fn candidates(&self) -> Vec<&Member> {
+        let total_weight = self
+            .members
+            .iter()
+            .map(|member| member.weight)
+            .sum::<usize>();
+        let first = match self.strategy {
+            Strategy::Strict | Strategy::LowestLatency => 0,
+            Strategy::RoundRobin => {
+                self.weighted(self.next.fetch_add(1, Ordering::Relaxed) % total_weight)
+            }
+            Strategy::Random => self.weighted(thread_rng().gen_range(0, total_weight)),
+        };
+
+        let mut candidates = self.members[first..]
+            .iter()
+            .chain(self.members[..first].iter())
+            .collect::<Vec<&Member>>();
+        if self.strategy == Strategy::LowestLatency {
+            candidates.sort_by_key(|member| member.latency.load(Ordering::Relaxed));
+        }
+        candidates.sort_by_key(|member| member.is_down());
+
+        candidates
+    }
+
+    // Send a query upstream, failing over from one upstream to the next until one answers.
+    // With a single upstream, timed out queries are resent to it as the client is configured
+    // to. With several, each gets one attempt per round, and those which timed out are tried
+    // again in the next round, so a silent upstream doesn't hold the query up for all its
+    // retries before the others are asked.
+    pub fn send_query(
+        &self,
+        client: &NetworkClient,
+        qname: &str,
+        qtype: QueryType,
+    ) -> Result<DnsPacket> {
+        if self.members.len() == 1 {
+            return self.members[0].send_query(client, qname, qtype, client.retries());
+        }
+
+        let mut candidates = self.candidates();
+        let mut last_error = None;
+        for _ in 0..=client.retries() {
+            let mut timed_out = Vec::new();
+            for member in candidates {
+                match member.send_query(client, qname, qtype, 0) {
+                    Ok(packet) => return Ok(packet),
+                    Err(e) => {
+                        if let DnsError::Timeout(_) = e {
+                            timed_out.push(member);
+                        }
+                        last_error = Some(e);
+                    }
+                }
+            }
+            if timed_out.is_empty() {
+                break;
+            }
+            candidates = timed_out;
+        }
+
+        Err(last_error.expect("Upstream pool is empty"))
+    }
+
+    // Probe upstreams which are down every interval, and put them back in use once they answer
+    pub fn start_health_checks(
+        pool: Arc<UpstreamPool>,
+        client: Arc<NetworkClient>,
+        interval: Duration,
+    ) -> io::Result<()> {
+        thread::Builder::new()
+            .name("DNS - upstream health checks".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                for member in pool.members.iter().filter(|member| member.is_down()) {
+                    // Any answer will do, even a refusal, so ask for the root's name servers
+                    match member
+                        .upstream
+                        .send_query(&client, "", QueryType::NS, client.retries())
+                    {
+                        Err(ref e) if is_unreachable(e) => {}
+                        _ => member.mark_up(),
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::buffer::BytePacketBuffer;
+    use crate::dns::config::UpstreamConfig;
+    use crate::dns::protocol::DnsRecord;
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    // A plain DNS server answering A queries with 192.0.2.1, if it answers at all
+    fn stub_server(answer: bool) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            if !answer {
+                continue;
+            }
+
+            let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+            packet.header.response = true;
+            packet.answers.push(DnsRecord::A {
+                domain: packet.questions[0].name.clone(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 300,
+            });
+            let mut response = BytePacketBuffer::new();
+            let len = packet.write(&mut response).unwrap();
+            socket.send_to(&response.buf[0..len], src).unwrap();
+        });
+
+        addr
+    }
+
+    fn resolver_config(upstreams: &[String]) -> ResolverConfig {
+        ResolverConfig {
+            mode: "forward".to_string(),
+            upstreams: upstreams
+                .iter()
+                .map(|address| UpstreamConfig::Address(address.clone()))
+                .collect(),
+            source_ports: 1,
+            timeout_ms: 200,
+            retries: 2,
+            backoff_ms: 100,
+            ..ResolverConfig::default()
+        }
+    }
+
+    #[test]
+    fn silent_upstream_fails_over_after_one_attempt() {
+        let config = resolver_config(&[stub_server(false), stub_server(true)]);
+        let pool = UpstreamPool::from_config(&config).unwrap();
+        let client = NetworkClient::new(&config).unwrap();
+
+        // Waiting out all the silent upstream's retries would take 900ms
+        let started = Instant::now();
+        let packet = pool
+            .send_query(&client, "www.example.test", QueryType::A)
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert_eq!(packet.answers.len(), 1);
+
+        // It's now down, so the next query goes straight to the one which answers
+        let started = Instant::now();
+        pool.send_query(&client, "www.example.test", QueryType::A)
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn silent_pool_is_retried_in_rounds() {
+        let config = resolver_config(&[stub_server(false), stub_server(false)]);
+        let pool = UpstreamPool::from_config(&config).unwrap();
+        let client = NetworkClient::new(&config).unwrap();
+
+        let started = Instant::now();
+        match pool.send_query(&client, "www.example.test", QueryType::A) {
+            Err(DnsError::Timeout(_)) => {}
+            _ => panic!("Expected the query to time out"),
+        }
+        // Three rounds of one attempt at each
+        assert!(started.elapsed() >= Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn lone_upstream_gets_every_retry() {
+        let config = resolver_config(&[stub_server(false)]);
+        let pool = UpstreamPool::from_config(&config).unwrap();
+        let client = NetworkClient::new(&config).unwrap();
+
+        match pool.send_query(&client, "www.example.test", QueryType::A) {
+            Err(DnsError::Timeout(message)) => assert!(message.ends_with("after 3 attempts")),
+            _ => panic!("Expected the query to time out"),
+        }
+    }
+}
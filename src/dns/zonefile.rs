@@ -0,0 +1,957 @@
+use super::buffer::{ByteBuffer, ExtendingBuffer};
+use super::protocol::{DnsRecord, QueryType, SvcParam, MAX_CHARACTER_STRING};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::iter::Peekable;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::{Chars, FromStr};
+
+// One entry of the master file, after joining parenthesized continuations
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    // Entries starting with whitespace inherit the owner of the previous record
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+// A token with its escapes decoded. Character-strings take the bytes as they are, which needn't
+// be UTF-8, while every other field reads the token as text.
+#[derive(Debug, Clone)]
+struct Token {
+    bytes: Vec<u8>,
+    text: String,
+    // Whether this is the unquoted `\#` introducing generic RDATA (RFC 3597 section 5)
+    generic: bool,
+}
+
+impl Token {
+    fn new(bytes: Vec<u8>, generic: bool) -> Token {
+        Token {
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+            bytes,
+            generic,
+        }
+    }
+}
+
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    let mut utf8 = [0; 4];
+    bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+}
+
+// Decode the escape following a backslash: `\DDD` is the byte with that decimal value, and `\X`
+// is X itself, even where X would otherwise be special (RFC 1035 section 5.1). Returns X for
+// the latter.
+fn unescape(chars: &mut Peekable<Chars>, line: usize, bytes: &mut Vec<u8>) -> Result<Option<char>> {
+    let digits = chars.clone().take(3).collect::<String>();
+    if digits.len() == 3 && digits.chars().all(|c| c.is_ascii_digit()) {
+        let value = digits
+            .parse::<u8>()
+            .map_err(|_| parse_error(line, &format!("Invalid escape \\{0}", digits)))?;
+        chars.nth(2);
+        bytes.push(value);
+        return Ok(None);
+    }
+
+    let escaped = chars
+        .next()
+        .ok_or_else(|| parse_error(line, "Escape at end of input"))?;
+    push_char(bytes, escaped);
+
+    Ok(Some(escaped))
+}
+
+fn parse_error(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {0}: {1}", line, msg))
+}
+
+// Split a master file into entries per RFC 1035 section 5.1
+fn tokenize(input: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut tokens = Vec::new();
+    let mut current = Vec::new();
+    // Whether the token so far is just `\#`
+    let mut generic = false;
+    let mut line = 1;
+    let mut entry_line = 1;
+    let mut blank_owner = false;
+    let mut at_line_start = true;
+    let mut depth = 0;
+
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 && tokens.is_empty() {
+            entry_line = line;
+            blank_owner = c == ' ' || c == '\t';
+        }
+        at_line_start = false;
+
+        match c {
+            '"' => {
+                let mut text = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.peek().is_some() => {
+                            unescape(&mut chars, line, &mut text)?;
+                        }
+                        Some('\n') | Some('\\') | None => {
+                            return Err(parse_error(line, "Unterminated string"))
+                        }
+                        Some(other) => push_char(&mut text, other),
+                    }
+                }
+                // A quoted value may follow a key within a token, as in `alpn="h2,h3"`
+                if current.is_empty() {
+                    tokens.push(Token::new(text, false));
+                } else {
+                    current.extend_from_slice(&text);
+                    generic = false;
+                }
+            }
+            ';' => {
+                // Comments run to the end of the line
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' | ')' | ' ' | '\t' | '\r' | '\n' => {
+                if !current.is_empty() {
+                    tokens.push(Token::new(current.split_off(0), generic));
+                    generic = false;
+                }
+
+                match c {
+                    '(' => depth += 1,
+                    ')' => {
+                        if depth == 0 {
+                            return Err(parse_error(line, "Unbalanced parentheses"));
+                        }
+                        depth -= 1;
+                    }
+                    '\n' => {
+                        line += 1;
+                        at_line_start = true;
+                        if depth == 0 && !tokens.is_empty() {
+                            entries.push(Entry {
+                                line: entry_line,
+                                blank_owner,
+                                tokens: tokens.clone(),
+                            });
+                            tokens.clear();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            '\\' => {
+                let starts_token = current.is_empty();
+                let escaped = unescape(&mut chars, line, &mut current)?;
+                generic = starts_token && escaped == Some('#');
+            }
+            _ => {
+                push_char(&mut current, c);
+                generic = false;
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(parse_error(line, "Unbalanced parentheses"));
+    }
+    if !current.is_empty() {
+        tokens.push(Token::new(current, generic));
+    }
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            line: entry_line,
+            blank_owner,
+            tokens,
+        });
+    }
+
+    Ok(entries)
+}
+
+// Parse a TTL, allowing BIND-style unit suffixes such as `1h30m`
+fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(ttl) = text.parse::<u32>() {
+        return Some(ttl);
+    }
+
+    let mut total: u32 = 0;
+    let mut value = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            value.push(c);
+            continue;
+        }
+
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604_800,
+            _ => return None,
+        };
+        let amount = value.parse::<u32>().ok()?;
+        total = total.checked_add(amount.checked_mul(multiplier)?)?;
+        value.clear();
+    }
+
+    if value.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+// Parse an NSEC3 hash, in unpadded base32 with the extended hex alphabet (RFC 5155 section 3.3)
+fn parse_base32hex(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0;
+    let mut value: u32 = 0;
+    for c in text.chars() {
+        value = (value << 5) | c.to_digit(32)?;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((value >> bits) as u8);
+            value &= (1 << bits) - 1;
+        }
+    }
+
+    // Bits left over only pad out the last character
+    if value != 0 {
+        return None;
+    }
+
+    Some(bytes)
+}
+
+// Parse an RRSIG validity time, given as YYYYMMDDHHmmSS in UTC or as seconds since the epoch
+// (RFC 4034 section 3.2)
+fn parse_timestamp(text: &str) -> Option<u32> {
+    if text.len() != 14 {
+        return text.parse::<u32>().ok();
+    }
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |start: usize, len: usize| text[start..start + len].parse::<i64>().ok();
+    let (year, month, day) = (field(0, 4)?, field(4, 2)?, field(6, 2)?);
+    let (hour, minute, second) = (field(8, 2)?, field(10, 2)?, field(12, 2)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    // Days since the epoch, per http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let timestamp = days * 86400 + hour * 3600 + minute * 60 + second;
+    if (0..=u32::MAX as i64).contains(&timestamp) {
+        Some(timestamp as u32)
+    } else {
+        None
+    }
+}
+
+// The text of tokens which together make up one field
+fn concat(tokens: &[Token]) -> String {
+    tokens.iter().map(|t| t.text.as_str()).collect()
+}
+
+// Parse RDATA in the generic `\# <length> <hex>` form of RFC 3597 section 5
+fn parse_generic_rdata(line: usize, rdata: &[Token]) -> Result<Vec<u8>> {
+    let invalid = || parse_error(line, "Invalid generic RDATA");
+
+    let len = rdata
+        .get(1)
+        .and_then(|t| t.text.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    let data = parse_hex(&concat(&rdata[2..])).ok_or_else(invalid)?;
+    if data.len() != len as usize {
+        return Err(invalid());
+    }
+
+    Ok(data)
+}
+
+// Parse the `key=value` SvcParams of an SVCB or HTTPS record (RFC 9460 section 2.1)
+fn parse_svc_params(line: usize, tokens: &[Token]) -> Result<Vec<SvcParam>> {
+    let invalid = |token: &Token| parse_error(line, &format!("Invalid SvcParam {0}", token.text));
+    let list = |value: &str| -> Vec<String> { value.split(',').map(|s| s.to_string()).collect() };
+
+    let mut params = Vec::new();
+    for token in tokens {
+        // Values are kept as bytes for the keys whose values are opaque
+        let (name, value, value_bytes) = match token.text.find('=') {
+            Some(idx) => (
+                &token.text[..idx],
+                &token.text[idx + 1..],
+                &token.bytes[idx + 1..],
+            ),
+            None => (token.text.as_str(), "", &token.bytes[..0]),
+        };
+        let key = SvcParam::key_from_name(&name.to_lowercase()).ok_or_else(|| invalid(token))?;
+
+        let param = match key {
+            0 => SvcParam::Mandatory(
+                list(value)
+                    .iter()
+                    .map(|name| SvcParam::key_from_name(name))
+                    .collect::<Option<Vec<u16>>>()
+                    .ok_or_else(|| invalid(token))?,
+            ),
+            1 if !value.is_empty() => SvcParam::Alpn(
+                value_bytes
+                    .split(|&byte| byte == b',')
+                    .map(|id| id.to_vec())
+                    .collect(),
+            ),
+            2 if value.is_empty() => SvcParam::NoDefaultAlpn,
+            3 => SvcParam::Port(value.parse::<u16>().map_err(|_| invalid(token))?),
+            4 => SvcParam::Ipv4Hint(
+                list(value)
+                    .iter()
+                    .map(|addr| Ipv4Addr::from_str(addr))
+                    .collect::<std::result::Result<Vec<Ipv4Addr>, _>>()
+                    .map_err(|_| invalid(token))?,
+            ),
+            5 => SvcParam::Ech(base64::decode(value).map_err(|_| invalid(token))?),
+            6 => SvcParam::Ipv6Hint(
+                list(value)
+                    .iter()
+                    .map(|addr| Ipv6Addr::from_str(addr))
+                    .collect::<std::result::Result<Vec<Ipv6Addr>, _>>()
+                    .map_err(|_| invalid(token))?,
+            ),
+            1 | 2 => return Err(invalid(token)),
+            _ => SvcParam::Unknown {
+                key,
+                value: value_bytes.to_vec(),
+            },
+        };
+        params.push(param);
+    }
+
+    // Keys must appear in increasing order on the wire, and only once
+    params.sort_by_key(|param| param.key());
+    if params.windows(2).any(|pair| pair[0].key() == pair[1].key()) {
+        return Err(parse_error(line, "Duplicate SvcParam"));
+    }
+
+    Ok(params)
+}
+
+// Decode generic RDATA for a type we do understand, by reading it back as a wire-format record
+fn decode_rdata(
+    line: usize,
+    domain: &str,
+    qtype: QueryType,
+    ttl: u32,
+    data: &[u8],
+) -> Result<DnsRecord> {
+    let mut buffer = ExtendingBuffer::new();
+    buffer.write_qname(domain)?;
+    buffer.write_u16(qtype.to_num())?;
+    buffer.write_u16(1)?; // class
+    buffer.write_u32(ttl)?;
+    buffer.write_u16(data.len() as u16)?;
+    for byte in data {
+        buffer.write(*byte)?;
+    }
+
+    buffer.seek(0)?;
+    DnsRecord::read(&mut buffer).map_err(|e| {
+        parse_error(
+            line,
+            &format!("Generic RDATA is not a valid {0:?} record: {1}", qtype, e),
+        )
+    })
+}
+
+struct Parser {
+    origin: Option<String>,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    last_ttl: Option<u32>,
+}
+
+impl Parser {
+    fn absolute_name(&self, line: usize, name: &str) -> Result<String> {
+        let name = name.to_lowercase();
+        if name.ends_with('.') {
+            return Ok(name.trim_end_matches('.').to_string());
+        }
+
+        let origin = match self.origin {
+            Some(ref origin) => origin,
+            None => return Err(parse_error(line, "Relative name used before $ORIGIN")),
+        };
+
+        if name == "@" {
+            Ok(origin.clone())
+        } else if origin.is_empty() {
+            Ok(name)
+        } else {
+            Ok(format!("{0}.{1}", name, origin))
+        }
+    }
+
+    fn parse_entry(&mut self, entry: &Entry) -> Result<Option<DnsRecord>> {
+        let line = entry.line;
+        let tokens = &entry.tokens;
+
+        // Directives
+        match tokens[0].text.to_uppercase().as_str() {
+            "$ORIGIN" => {
+                let origin = tokens
+                    .get(1)
+                    .map(|t| t.text.as_str())
+                    .ok_or_else(|| parse_error(line, "$ORIGIN requires a name"))?;
+                self.origin = Some(self.absolute_name(line, origin)?);
+                return Ok(None);
+            }
+            "$TTL" => {
+                let ttl = tokens
+                    .get(1)
+                    .and_then(|t| parse_ttl(&t.text))
+                    .ok_or_else(|| parse_error(line, "$TTL requires a TTL"))?;
+                self.default_ttl = Some(ttl);
+                return Ok(None);
+            }
+            directive if directive.starts_with('$') => {
+                return Err(parse_error(
+                    line,
+                    &format!("Unsupported directive {0}", directive),
+                ));
+            }
+            _ => {}
+        }
+
+        let mut pos = 0;
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or_else(|| parse_error(line, "No previous owner to inherit"))?
+        } else {
+            pos += 1;
+            self.absolute_name(line, &tokens[0].text)?
+        };
+
+        // TTL and class may appear in either order ahead of the type
+        let mut ttl = None;
+        let mut rtype = None;
+        while let Some(token) = tokens.get(pos).map(|t| &t.text) {
+            pos += 1;
+            if token.eq_ignore_ascii_case("IN") {
+                continue;
+            }
+            if let Some(value) = parse_ttl(token) {
+                ttl = Some(value);
+                continue;
+            }
+            rtype = Some(token.to_uppercase());
+            break;
+        }
+
+        let rtype = rtype.ok_or_else(|| parse_error(line, "Missing record type"))?;
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or_else(|| parse_error(line, "No TTL given and no $TTL in effect"))?;
+
+        let record = self.parse_rdata(line, owner.clone(), ttl, &rtype, &tokens[pos..])?;
+
+        self.last_owner = Some(owner);
+        self.last_ttl = Some(ttl);
+
+        Ok(Some(record))
+    }
+
+    fn parse_rdata(
+        &self,
+        line: usize,
+        domain: String,
+        ttl: u32,
+        rtype: &str,
+        rdata: &[Token],
+    ) -> Result<DnsRecord> {
+        let token = |idx: usize| -> Result<&Token> {
+            rdata
+                .get(idx)
+                .ok_or_else(|| parse_error(line, &format!("Incomplete {0} record", rtype)))
+        };
+        let field = |idx: usize| -> Result<&str> { token(idx).map(|t| t.text.as_str()) };
+        let number = |idx: usize| -> Result<u32> {
+            let text = field(idx)?;
+            text.parse::<u32>()
+                .ok()
+                .or_else(|| parse_ttl(text))
+                .ok_or_else(|| parse_error(line, &format!("Invalid number {0}", text)))
+        };
+        let invalid = |what: &str| parse_error(line, &format!("Invalid {0}", what));
+        let short = |idx: usize, what: &str| -> Result<u16> {
+            let value = number(idx)?;
+            if value > u16::MAX as u32 {
+                return Err(invalid(what));
+            }
+            Ok(value as u16)
+        };
+        let byte = |idx: usize, what: &str| -> Result<u8> {
+            let value = number(idx)?;
+            if value > u8::MAX as u32 {
+                return Err(invalid(what));
+            }
+            Ok(value as u8)
+        };
+        let hex = |from: usize, what: &str| -> Result<Vec<u8>> {
+            if rdata.len() <= from {
+                return Err(invalid(what));
+            }
+            parse_hex(&concat(&rdata[from..])).ok_or_else(|| invalid(what))
+        };
+        // Keys and signatures may be split over several tokens
+        let base64 = |from: usize, what: &str| -> Result<Vec<u8>> {
+            if rdata.len() <= from {
+                return Err(invalid(what));
+            }
+            base64::decode(concat(&rdata[from..])).map_err(|_| invalid(what))
+        };
+        let salt = |idx: usize| -> Result<Vec<u8>> {
+            match field(idx)? {
+                "-" => Ok(Vec::new()),
+                text => parse_hex(text).ok_or_else(|| invalid("NSEC3 salt")),
+            }
+        };
+        let types = |from: usize| -> Result<Vec<QueryType>> {
+            rdata
+                .iter()
+                .skip(from)
+                .map(|t| QueryType::from_name(&t.text).ok_or_else(|| invalid("type in bitmap")))
+                .collect()
+        };
+
+        if rdata.first().is_some_and(|t| t.generic) {
+            let data = parse_generic_rdata(line, rdata)?;
+            return match QueryType::from_name(rtype) {
+                Some(QueryType::UNKNOWN(qtype)) => Ok(DnsRecord::UNKNOWN {
+                    domain,
+                    qtype,
+                    class: 1,
+                    data,
+                    ttl,
+                }),
+                Some(QueryType::OPT) | None => Err(parse_error(
+                    line,
+                    &format!("Unsupported record type {0}", rtype),
+                )),
+                Some(qtype) => decode_rdata(line, &domain, qtype, ttl, &data),
+            };
+        }
+
+        let record = match QueryType::from_name(rtype) {
+            Some(QueryType::A) => DnsRecord::A {
+                domain,
+                addr: Ipv4Addr::from_str(field(0)?).map_err(|_| invalid("IPv4 address"))?,
+                ttl,
+            },
+            Some(QueryType::AAAA) => DnsRecord::AAAA {
+                domain,
+                addr: Ipv6Addr::from_str(field(0)?).map_err(|_| invalid("IPv6 address"))?,
+                ttl,
+            },
+            Some(QueryType::NS) => DnsRecord::NS {
+                domain,
+                host: self.absolute_name(line, field(0)?)?,
+                ttl,
+            },
+            Some(QueryType::CNAME) => DnsRecord::CNAME {
+                domain,
+                host: self.absolute_name(line, field(0)?)?,
+                ttl,
+            },
+            Some(QueryType::PTR) => DnsRecord::PTR {
+                domain,
+                host: self.absolute_name(line, field(0)?)?,
+                ttl,
+            },
+            Some(QueryType::MX) => DnsRecord::MX {
+                domain,
+                priority: short(0, "MX priority")?,
+                host: self.absolute_name(line, field(1)?)?,
+                ttl,
+            },
+            Some(QueryType::TXT) => {
+                if rdata.is_empty() {
+                    return Err(invalid("TXT record"));
+                }
+
+                // Each token is one character-string
+                if rdata.iter().any(|t| t.bytes.len() > MAX_CHARACTER_STRING) {
+                    return Err(invalid("TXT string, which is longer than 255 bytes"));
+                }
+
+                DnsRecord::TXT {
+                    domain,
+                    txt_data: rdata.iter().map(|t| t.bytes.clone()).collect(),
+                    ttl,
+                }
+            }
+            Some(QueryType::SOA) => DnsRecord::SOA {
+                domain,
+                m_name: self.absolute_name(line, field(0)?)?,
+                r_name: self.absolute_name(line, field(1)?)?,
+                serial: number(2)?,
+                refresh: number(3)?,
+                retry: number(4)?,
+                expire: number(5)?,
+                minimum: number(6)?,
+                ttl,
+            },
+            Some(QueryType::SRV) => DnsRecord::SRV {
+                domain,
+                priority: short(0, "SRV priority")?,
+                weight: short(1, "SRV weight")?,
+                port: short(2, "SRV port")?,
+                host: self.absolute_name(line, field(3)?)?,
+                ttl,
+            },
+            Some(QueryType::SSHFP) => DnsRecord::SSHFP {
+                domain,
+                algorithm: byte(0, "SSHFP algorithm")?,
+                fp_type: byte(1, "SSHFP fingerprint type")?,
+                fingerprint: hex(2, "SSHFP fingerprint")?,
+                ttl,
+            },
+            Some(QueryType::TLSA) => DnsRecord::TLSA {
+                domain,
+                cert_usage: byte(0, "TLSA certificate usage")?,
+                selector: byte(1, "TLSA selector")?,
+                matching_type: byte(2, "TLSA matching type")?,
+                cert_data: hex(3, "TLSA certificate data")?,
+                ttl,
+            },
+            Some(qtype @ QueryType::SVCB) | Some(qtype @ QueryType::HTTPS) => {
+                let priority = short(0, "service priority")?;
+                let target = self.absolute_name(line, field(1)?)?;
+                let params = parse_svc_params(line, &rdata[2..])?;
+
+                if qtype == QueryType::SVCB {
+                    DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    }
+                } else {
+                    DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    }
+                }
+            }
+            Some(QueryType::DNSKEY) => DnsRecord::DNSKEY {
+                domain,
+                flags: short(0, "DNSKEY flags")?,
+                protocol: byte(1, "DNSKEY protocol")?,
+                algorithm: byte(2, "DNSKEY algorithm")?,
+                public_key: base64(3, "DNSKEY public key")?,
+                ttl,
+            },
+            Some(QueryType::DS) => DnsRecord::DS {
+                domain,
+                key_tag: short(0, "DS key tag")?,
+                algorithm: byte(1, "DS algorithm")?,
+                digest_type: byte(2, "DS digest type")?,
+                digest: hex(3, "DS digest")?,
+                ttl,
+            },
+            Some(QueryType::RRSIG) => DnsRecord::RRSIG {
+                domain,
+                type_covered: QueryType::from_name(field(0)?)
+                    .ok_or_else(|| invalid("RRSIG type covered"))?,
+                algorithm: byte(1, "RRSIG algorithm")?,
+                labels: byte(2, "RRSIG labels")?,
+                original_ttl: number(3)?,
+                expiration: parse_timestamp(field(4)?)
+                    .ok_or_else(|| invalid("RRSIG expiration"))?,
+                inception: parse_timestamp(field(5)?).ok_or_else(|| invalid("RRSIG inception"))?,
+                key_tag: short(6, "RRSIG key tag")?,
+                signer_name: self.absolute_name(line, field(7)?)?,
+                signature: base64(8, "RRSIG signature")?,
+                ttl,
+            },
+            Some(QueryType::NSEC) => DnsRecord::NSEC {
+                domain,
+                next_domain: self.absolute_name(line, field(0)?)?,
+                types: types(1)?,
+                ttl,
+            },
+            Some(QueryType::NSEC3) => DnsRecord::NSEC3 {
+                domain,
+                hash_algorithm: byte(0, "NSEC3 hash algorithm")?,
+                flags: byte(1, "NSEC3 flags")?,
+                iterations: short(2, "NSEC3 iterations")?,
+                salt: salt(3)?,
+                next_hashed_owner: parse_base32hex(field(4)?)
+                    .ok_or_else(|| invalid("NSEC3 next hashed owner"))?,
+                types: types(5)?,
+                ttl,
+            },
+            Some(QueryType::NSEC3PARAM) => DnsRecord::NSEC3PARAM {
+                domain,
+                hash_algorithm: byte(0, "NSEC3PARAM hash algorithm")?,
+                flags: byte(1, "NSEC3PARAM flags")?,
+                iterations: short(2, "NSEC3PARAM iterations")?,
+                salt: salt(3)?,
+                ttl,
+            },
+            Some(QueryType::CAA) => {
+                let tag = field(1)?;
+                if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(invalid("CAA tag"));
+                }
+
+                DnsRecord::CAA {
+                    domain,
+                    flags: byte(0, "CAA flags")?,
+                    tag: tag.to_lowercase(),
+                    value: token(2)?.bytes.clone(),
+                    ttl,
+                }
+            }
+            _ => {
+                return Err(parse_error(
+                    line,
+                    &format!("Unsupported record type {0}", rtype),
+                ))
+            }
+        };
+
+        Ok(record)
+    }
+}
+
+// Parse the records of an RFC 1035 master file
+pub fn parse(input: &str) -> Result<Vec<DnsRecord>> {
+    let mut parser = Parser {
+        origin: None,
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+    };
+
+    let mut records = Vec::new();
+    for entry in tokenize(input)? {
+        if let Some(record) = parser.parse_entry(&entry)? {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<DnsRecord>> {
+    let path = path.as_ref();
+    let input = fs::read_to_string(path)?;
+
+    parse(&input).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{0}: {1}", path.display(), e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(rdata: &str) -> Result<DnsRecord> {
+        let input = format!("$ORIGIN example.com.\n$TTL 300\nwww {0}\n", rdata);
+        Ok(parse(&input)?.remove(0))
+    }
+
+    fn txt_data(rec: &DnsRecord) -> Vec<Vec<u8>> {
+        match *rec {
+            DnsRecord::TXT { ref txt_data, .. } => txt_data.clone(),
+            _ => panic!("Not a TXT record: {:?}", rec),
+        }
+    }
+
+    #[test]
+    fn resolves_directives_and_relative_names() {
+        let input = "$ORIGIN example.com.
+$TTL 1h
+@ IN SOA ns1 hostmaster.example.net. (
+        2024010101 ; serial
+        7200       ; refresh
+        900        ; retry
+        1209600    ; expire
+        300 )      ; minimum
+        NS ns1
+ns1 600 A 192.0.2.53
+www.example.com. IN A 192.0.2.1
+    MX 10 mail
+$ORIGIN sub.example.com.
+@ CNAME www.example.com.
+";
+        let records = parse(input).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::SOA {
+                    domain: "example.com".to_string(),
+                    m_name: "ns1.example.com".to_string(),
+                    r_name: "hostmaster.example.net".to_string(),
+                    serial: 2024010101,
+                    refresh: 7200,
+                    retry: 900,
+                    expire: 1209600,
+                    minimum: 300,
+                    ttl: 3600,
+                },
+                DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.com".to_string(),
+                    ttl: 3600,
+                },
+                DnsRecord::A {
+                    domain: "ns1.example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 53),
+                    ttl: 600,
+                },
+                DnsRecord::A {
+                    domain: "www.example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: 3600,
+                },
+                DnsRecord::MX {
+                    domain: "www.example.com".to_string(),
+                    priority: 10,
+                    host: "mail.example.com".to_string(),
+                    ttl: 3600,
+                },
+                DnsRecord::CNAME {
+                    domain: "sub.example.com".to_string(),
+                    host: "www.example.com".to_string(),
+                    ttl: 3600,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_incomplete_zones() {
+        let error = |input: &str| parse(input).unwrap_err().to_string();
+
+        assert_eq!(
+            error("$TTL 300\nwww A 192.0.2.1\n"),
+            "line 2: Relative name used before $ORIGIN"
+        );
+        assert_eq!(
+            error("$ORIGIN example.com.\nwww A 192.0.2.1\n"),
+            "line 2: No TTL given and no $TTL in effect"
+        );
+        assert!(
+            error("$TTL 300\n@ SOA ns1 hostmaster ( 1 2 3 4\n").ends_with("Unbalanced parentheses")
+        );
+    }
+
+    #[test]
+    fn decodes_escapes_in_quoted_strings() {
+        let rec = parse_one(r#"TXT "\065\066C" "say \"hi\"" "back\\slash" "\255\000""#).unwrap();
+
+        assert_eq!(
+            txt_data(&rec),
+            vec![
+                b"ABC".to_vec(),
+                b"say \"hi\"".to_vec(),
+                b"back\\slash".to_vec(),
+                vec![255, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_escapes_in_unquoted_tokens() {
+        let rec = parse_one(r"TXT \065BC two\ words semi\;colon \(paren\)").unwrap();
+
+        assert_eq!(
+            txt_data(&rec),
+            vec![
+                b"ABC".to_vec(),
+                b"two words".to_vec(),
+                b"semi;colon".to_vec(),
+                b"(paren)".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_escapes_out_of_range() {
+        assert!(parse_one(r#"TXT "\256""#).is_err());
+        assert!(parse_one(r"TXT \999").is_err());
+    }
+
+    #[test]
+    fn generic_rdata_needs_unquoted_marker() {
+        let rec = parse_one(r"TYPE1 \# 4 c0000201").unwrap();
+        assert!(matches!(rec, DnsRecord::A { addr, .. } if addr == Ipv4Addr::new(192, 0, 2, 1)));
+
+        let rec = parse_one(r#"TXT "\#" 4"#).unwrap();
+        assert_eq!(txt_data(&rec), vec![b"#".to_vec(), b"4".to_vec()]);
+    }
+
+    #[test]
+    fn txt_with_binary_round_trips() {
+        let rec = DnsRecord::TXT {
+            domain: "www.example.com".to_string(),
+            txt_data: vec![
+                (0..=127).collect(),
+                (128..=255).collect(),
+                b"quote \" backslash \\ semicolon ; paren ( )".to_vec(),
+                Vec::new(),
+            ],
+            ttl: 300,
+        };
+
+        let parsed = parse(&format!("{0}\n", rec)).unwrap();
+        assert_eq!(parsed, vec![rec]);
+    }
+}
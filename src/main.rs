@@ -1,8 +1,11 @@
 extern crate clap;
 use clap::{App, Arg};
 mod dns;
+use dns::config::{Config, UpstreamConfig};
 use dns::server::DnsServer;
-use dns::{context::ServerContext, resolver::ResolverMode, server::{UdpServer, TcpServer}};
+use dns::{context::ServerContext, server::{UdpServer, TcpServer, TlsServer, HttpsServer}};
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 
 fn main() {
@@ -10,57 +13,120 @@ fn main() {
     let matches = App::new("rDNS")
         .author("MAKLs")
         .about("Recursive DNS resolver")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("CONFIG FILE")
+                .help("TOML configuration file; command line flags override its values"),
+        )
         .arg(
             Arg::with_name("mode")
                 .short("m")
                 .long("mode")
                 .value_name("RESOLVER MODE")
-                .possible_values(&["recursive", "forward"])
-                .default_value("recursive"),
+                .possible_values(&["recursive", "forward"]),
         )
         .arg(
             Arg::with_name("downstream_server")
                 .short("s")
                 .long("server")
                 .value_name("DOWNSTREAM DNS SERVER")
-                .required_if("mode", "forward"),
+                .multiple(true)
+                .use_delimiter(true)
+                .help("host[:port], tls://host[:port] or https:// URL to forward queries to; \
+                       separate several with commas"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT"),
         )
         .arg(
             Arg::with_name("thread-count")
                 .short("c")
                 .long("thread-count")
-                .default_value("5")
                 .value_name("THREAD COUNT"),
         )
+        .arg(
+            Arg::with_name("zone")
+                .short("z")
+                .long("zone")
+                .value_name("ZONE FILE")
+                .help("Serve a zone from an RFC 1035 master file; may be repeated")
+                .multiple(true)
+                .number_of_values(1),
+        )
         .get_matches();
     println!("Starting rDNS\n");
 
-    // Prepare server context
-    let mut context = ServerContext::new();
-    let resolver_mode = ResolverMode::from_str(
-        matches.value_of("mode").unwrap(),
-        matches.value_of("downstream_server"),
-    );
-    if let Some(mode) = resolver_mode {
-        context.set_resolver_mode(mode);
-    } else {
-        context.set_resolver_mode(ResolverMode::Recursive);
+    // Load configuration, then apply command line overrides
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::from_file(path).unwrap_or_else(|e| {
+            println!("Failed to load configuration: {}", e);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    if let Some(mode) = matches.value_of("mode") {
+        config.resolver.mode = mode.to_string();
+    }
+    if let Some(servers) = matches.values_of("downstream_server") {
+        config.resolver.upstream = None;
+        config.resolver.upstreams = servers
+            .map(|server| UpstreamConfig::Address(server.to_string()))
+            .collect();
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.server.port = port.parse::<u16>().unwrap_or_else(|_| {
+            println!("Invalid port: {}", port);
+            process::exit(1);
+        });
+    }
+    if let Some(count) = matches.value_of("thread-count") {
+        let thread_count = count.parse::<usize>().unwrap_or_else(|_| {
+            println!("Invalid thread count: {}", count);
+            process::exit(1);
+        });
+        config.server.udp_threads = thread_count;
+        config.server.tcp_threads = thread_count;
+    }
+    if let Some(zones) = matches.values_of("zone") {
+        config.zones = zones.map(PathBuf::from).collect();
+    }
+    if let Err(e) = config.validate() {
+        println!("Invalid configuration: {}", e);
+        process::exit(1);
     }
 
+    // Prepare server context
+    let context = ServerContext::new(&config).unwrap_or_else(|e| {
+        println!("Failed to initialize server: {}", e);
+        process::exit(1);
+    });
     let context_ptr = Arc::new(context);
 
     // Run servers
-    let thread_count = matches
-        .value_of("thread-count")
-        .unwrap()
-        .parse::<usize>()
-        .expect("Failed to parse thread count");
     let udp_server = UdpServer::new(context_ptr.clone());
     let tcp_server = TcpServer::new(context_ptr.clone());
 
     // FIXME: need better way to collect server threads and join on them
-    let _ = tcp_server.run(thread_count);
-    match udp_server.run(thread_count) {
+    if let Err(e) = tcp_server.run(config.server.tcp_threads) {
+        println!("Failed to run TCP server: {:?}", e);
+    }
+    if config.tls.enabled() {
+        let tls_server = TlsServer::new(context_ptr.clone());
+        if let Err(e) = tls_server.run(config.tls.threads) {
+            println!("Failed to run TLS server: {:?}", e);
+        }
+    }
+    if config.https.enabled {
+        let https_server = HttpsServer::new(context_ptr.clone());
+        if let Err(e) = https_server.run(config.https.threads) {
+            println!("Failed to run HTTPS server: {:?}", e);
+        }
+    }
+    match udp_server.run(config.server.udp_threads) {
         Ok(handle) => handle.join().unwrap(),
         Err(e) => println!("Failed to run UDP server: {:?}", e),
     }